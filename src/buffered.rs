@@ -0,0 +1,299 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Write-back buffering of file handlers, for backends with a high cost per call (e.g. network
+//! storage), which the kernel would otherwise write to in chunks of up to 128KiB.
+
+use std::cmp::{max, min};
+use std::sync::Mutex;
+use time::Timespec;
+
+use super::filelike::{write_full_at, AttrFileLike, ReadFileLike, SetLenFileLike, WriteFileLike};
+use fuse_mt::FileAttr;
+use super::{Errno, Result};
+
+#[derive(Debug)]
+struct Buffer {
+    offset: u64,
+    data: Vec<u8>,
+    // The first error of pushing the buffer other than by `flush`, to be returned by the next one.
+    error: Option<Errno>,
+}
+
+fn push<W>(inner: &W, buffer: &mut Buffer) -> Result<()> where W: WriteFileLike {
+    if buffer.data.is_empty() {
+        return Ok(());
+    }
+    let res = write_full_at(inner, &buffer.data, buffer.offset);
+    // The data is dropped even on error, as there's no telling what was written.
+    buffer.data.clear();
+    res.map(|_| ())
+}
+
+fn push_deferred<W>(inner: &W, buffer: &mut Buffer) where W: WriteFileLike {
+    if let Err(e) = push(inner, buffer) {
+        buffer.error.get_or_insert(e);
+    }
+}
+
+/// Adaptor buffering writes of a handler: contiguous (or overlapping) writes are coalesced in
+/// memory, up to the given capacity, and only written to the handler when the buffer fills up,
+/// when a write elsewhere in the file comes in, on `write_buffered` (i.e. `close`, with
+/// `FilesystemFLOpen`) and `flush` (i.e. `fsync`), on `set_len` and `getattr`, and as a last
+/// resort when the adaptor is dropped. Writes larger than the capacity go directly to the handler.
+/// Errors of writing the buffer to the handler other than in `write_buffered` or `flush` (and
+/// `set_len`) are returned by the next one of these; errors on drop are lost.
+/// If the handler supports reading, so does the adaptor, with the buffered data read over that
+/// of the handler.
+#[derive(Debug)]
+pub struct BufferedWriter<W> where W: WriteFileLike {
+    inner: W,
+    capacity: usize,
+    buffer: Mutex<Buffer>,
+}
+
+impl<W> BufferedWriter<W> where W: WriteFileLike {
+    /// Wrap a handler, buffering up to `capacity` bytes.
+    pub fn new(inner: W, capacity: usize) -> BufferedWriter<W> {
+        BufferedWriter {
+            inner,
+            capacity,
+            buffer: Mutex::new(Buffer { offset: 0, data: vec![], error: None }),
+        }
+    }
+
+    /// The wrapped handler. Note that it doesn't reflect the buffered writes.
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    /// The number of bytes buffered.
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().data.len()
+    }
+}
+
+impl<W> WriteFileLike for BufferedWriter<W> where W: WriteFileLike {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let end = buffer.offset + buffer.data.len() as u64;
+        if buffer.data.is_empty() || offset < buffer.offset || offset > end {
+            push_deferred(&self.inner, &mut buffer);
+            if buf.len() >= self.capacity {
+                return write_full_at(&self.inner, buf, offset);
+            }
+            buffer.offset = offset;
+        }
+        let start = (offset - buffer.offset) as usize;
+        let overlap = min(buf.len(), buffer.data.len() - start);
+        buffer.data[start..start + overlap].copy_from_slice(&buf[..overlap]);
+        buffer.data.extend_from_slice(&buf[overlap..]);
+        if buffer.data.len() >= self.capacity {
+            push_deferred(&self.inner, &mut buffer);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.write_buffered()?;
+        self.inner.flush()
+    }
+
+    fn write_buffered(&self) -> Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let res = push(&self.inner, &mut buffer);
+        if let Some(e) = buffer.error.take() {
+            return Err(e);
+        }
+        res?;
+        self.inner.write_buffered()
+    }
+}
+
+impl<W> ReadFileLike for BufferedWriter<W> where W: WriteFileLike + ReadFileLike {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        // Keep the buffer from being written while reading.
+        let buffer = self.buffer.lock().unwrap();
+        let mut len = self.inner.read_at(buf, offset)?;
+        let start = max(offset, buffer.offset);
+        let end = min(offset + buf.len() as u64, buffer.offset + buffer.data.len() as u64);
+        if start < end {
+            let to = (start - offset) as usize;
+            let from = (start - buffer.offset) as usize;
+            let count = (end - start) as usize;
+            // The buffer may extend the file, leaving a hole after its current end.
+            if to > len {
+                buf[len..to].iter_mut().for_each(|b| *b = 0);
+            }
+            buf[to..to + count].copy_from_slice(&buffer.data[from..from + count]);
+            len = max(len, to + count);
+        }
+        Ok(len)
+    }
+}
+
+impl<W> SetLenFileLike for BufferedWriter<W> where W: WriteFileLike + SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        push(&self.inner, &mut buffer)?;
+        self.inner.set_len(size)
+    }
+}
+
+impl<W> AttrFileLike for BufferedWriter<W> where W: WriteFileLike + AttrFileLike {
+    /// The attributes of the file, after writing the buffer, so that they reflect it.
+    fn getattr(&self) -> Result<FileAttr> {
+        let mut buffer = self.buffer.lock().unwrap();
+        push_deferred(&self.inner, &mut buffer);
+        self.inner.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.inner.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.inner.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.inner.utimens(atime, mtime)
+    }
+}
+
+impl<W> Drop for BufferedWriter<W> where W: WriteFileLike {
+    fn drop(&mut self) {
+        if let Ok(buffer) = self.buffer.get_mut() {
+            let _ = push(&self.inner, buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::super::filelike::{FilesystemFLOpen, MemFile};
+    use super::super::flags::AccessMode;
+    use fuse_mt::RequestInfo;
+
+    // A handler recording the writes reaching it, failing them while `fail` is set.
+    #[derive(Default)]
+    struct Recording {
+        file: MemFile,
+        writes: Mutex<Vec<(u64, usize)>>,
+        fail: AtomicBool,
+    }
+
+    impl WriteFileLike for Recording {
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(Errno::EIO);
+            }
+            self.writes.lock().unwrap().push((offset, buf.len()));
+            self.file.write_at(buf, offset)
+        }
+    }
+
+    impl ReadFileLike for Recording {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+    }
+
+    impl SetLenFileLike for Recording {
+        fn set_len(&self, size: u64) -> Result<()> {
+            self.file.set_len(size)
+        }
+    }
+
+    impl AttrFileLike for Recording {}
+
+    fn writes(writer: &BufferedWriter<Recording>) -> Vec<(u64, usize)> {
+        writer.inner().writes.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn coalesces_writes() {
+        let writer = BufferedWriter::new(Recording::default(), 8);
+        writer.write_at(b"ab", 0).unwrap();
+        writer.write_at(b"cd", 2).unwrap();
+        writer.write_at(b"X", 1).unwrap();
+        assert_eq!(writer.buffered(), 4);
+        assert!(writes(&writer).is_empty());
+        // Writing elsewhere pushes the buffer.
+        writer.write_at(b"ef", 10).unwrap();
+        assert_eq!(writes(&writer), [(0, 4)]);
+        // Filling the buffer pushes it.
+        writer.write_at(b"ghijkl", 12).unwrap();
+        assert_eq!(writes(&writer), [(0, 4), (10, 8)]);
+        // Large writes go directly.
+        writer.write_at(&[b'z'; 8], 20).unwrap();
+        assert_eq!(writes(&writer), [(0, 4), (10, 8), (20, 8)]);
+        writer.write_at(b"m", 30).unwrap();
+        writer.write_buffered().unwrap();
+        assert_eq!(writer.buffered(), 0);
+        assert_eq!(&writer.inner().file.to_vec()[..4], b"aXcd");
+    }
+
+    #[test]
+    fn reads_buffered_data() {
+        let writer = BufferedWriter::new(Recording::default(), 16);
+        writer.inner().file.write_at(b"hello", 0).unwrap();
+        writer.write_at(b"LL", 2).unwrap();
+        let mut buf = [0xff; 16];
+        assert_eq!(writer.read_at(&mut buf, 0), Ok(5));
+        assert_eq!(&buf[..5], b"heLLo");
+        writer.write_buffered().unwrap();
+        // Past the end of file, leaving a hole.
+        writer.write_at(b"xy", 8).unwrap();
+        let mut buf = [0xff; 16];
+        assert_eq!(writer.read_at(&mut buf, 0), Ok(10));
+        assert_eq!(&buf[..10], b"heLLo\0\0\0xy");
+        let mut buf = [0xff; 4];
+        assert_eq!(writer.read_at(&mut buf, 6), Ok(4));
+        assert_eq!(&buf, b"\0\0xy");
+        assert_eq!(writer.inner().file.len(), 5);
+    }
+
+    #[test]
+    fn defers_errors() {
+        let writer = BufferedWriter::new(Recording::default(), 16);
+        writer.write_at(b"ab", 0).unwrap();
+        writer.inner().fail.store(true, Ordering::SeqCst);
+        // The buffer fails to be written on a write elsewhere, which succeeds itself.
+        assert_eq!(writer.write_at(b"cd", 8), Ok(2));
+        writer.inner().fail.store(false, Ordering::SeqCst);
+        assert_eq!(writer.flush(), Err(Errno::EIO));
+        assert_eq!(writes(&writer), [(8, 2)]);
+        writer.flush().unwrap();
+        writer.write_at(b"ef", 0).unwrap();
+        writer.inner().fail.store(true, Ordering::SeqCst);
+        assert_eq!(writer.write_buffered(), Err(Errno::EIO));
+        assert_eq!(writer.write_buffered(), Ok(()));
+    }
+
+    #[test]
+    fn writes_on_close() {
+        struct Fs;
+        impl FilesystemFLOpen for Fs {
+            type FileLike = BufferedWriter<Recording>;
+        }
+        let req = || RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+        let path = Path::new("/f");
+        let writer = BufferedWriter::new(Recording::default(), 16);
+        writer.write_at(b"ab", 0).unwrap();
+        Fs.flush(req(), path, &writer, 0).unwrap();
+        assert_eq!(writes(&writer), [(0, 2)]);
+        writer.write_at(b"cd", 2).unwrap();
+        writer.inner().fail.store(true, Ordering::SeqCst);
+        let flags = AccessMode::WriteOnly.into();
+        assert_eq!(Fs.release(req(), path, writer, flags, 0, false), Err(Errno::EIO));
+    }
+}
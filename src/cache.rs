@@ -0,0 +1,453 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Caching of file contents in memory, for file handlers over slow backends (e.g. network
+//! storage).
+//! A `BlockCache` holds fixed-size, aligned blocks of files, evicting the least recently used
+//! ones beyond its capacity. It is shared by `CachedReader`-s, which serve reads from it, reading
+//! the missing blocks from the wrapped handler. Blocks are kept by a key identifying the file
+//! (e.g. its inode number), so all handlers of the same file, given the same key, share them.
+//! Writes (and truncates) through a `CachedReader` invalidate the blocks they touch; changes made
+//! otherwise should be followed by `BlockCache::invalidate`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use time::Timespec;
+
+use super::filelike::{read_full_at, AttrFileLike, ReadFileLike, SetLenFileLike, WriteFileLike};
+use fuse_mt::FileAttr;
+use super::Result;
+
+// The key of a file, and the index of a block in it.
+type BlockId = (u64, u64);
+
+// The number of times the whole cache was cleared, and the number of times a file was invalidated
+// since, for telling whether it was invalidated while reading it.
+type Generation = (u64, u64);
+
+#[derive(Debug)]
+struct Block {
+    data: Arc<Vec<u8>>,
+    last_use: u64,
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    blocks: HashMap<BlockId, Block>,
+    // The blocks by their last use, and the blocks of each file.
+    lru: BTreeMap<u64, BlockId>,
+    files: HashMap<u64, BTreeSet<u64>>,
+    tick: u64,
+    // Bumped by every invalidation of a file (or of all of them, for `clears`), so that blocks
+    // read from the handler before it are dropped rather than cached.
+    clears: u64,
+    generations: HashMap<u64, u64>,
+}
+
+impl CacheInner {
+    fn touch(&mut self, key: u64, index: u64) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let block = self.blocks.get_mut(&(key, index))?;
+        self.lru.remove(&block.last_use);
+        block.last_use = tick;
+        self.lru.insert(tick, (key, index));
+        Some(block.data.clone())
+    }
+
+    fn generation(&self, key: u64) -> Generation {
+        (self.clears, self.generations.get(&key).cloned().unwrap_or(0))
+    }
+
+    fn bump(&mut self, key: u64) {
+        *self.generations.entry(key).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, key: u64, index: u64) {
+        if let Some(block) = self.blocks.remove(&(key, index)) {
+            self.lru.remove(&block.last_use);
+        }
+        let now_empty = match self.files.get_mut(&key) {
+            Some(indices) => {
+                indices.remove(&index);
+                indices.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            self.files.remove(&key);
+        }
+    }
+}
+
+/// A cache of blocks of files, see the module docs. It is a handle, so clones of it share the same
+/// cache.
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    block_size: usize,
+    max_blocks: usize,
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+impl BlockCache {
+    /// A new, empty cache, of blocks of `block_size` bytes, keeping up to `capacity` bytes (but at
+    /// least one block).
+    pub fn new(block_size: usize, capacity: usize) -> BlockCache {
+        assert!(block_size > 0, "block size must be positive");
+        BlockCache {
+            block_size,
+            max_blocks: (capacity / block_size).max(1),
+            inner: Arc::new(Mutex::new(CacheInner {
+                blocks: HashMap::new(),
+                lru: BTreeMap::new(),
+                files: HashMap::new(),
+                tick: 0,
+                clears: 0,
+                generations: HashMap::new(),
+            })),
+        }
+    }
+
+    /// The size of the blocks.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The number of bytes cached.
+    pub fn cached_bytes(&self) -> usize {
+        self.inner.lock().unwrap().blocks.values().map(|block| block.data.len()).sum()
+    }
+
+    /// Drop the blocks of the given file, e.g. after it was changed other than through a
+    /// `CachedReader`.
+    pub fn invalidate(&self, key: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bump(key);
+        for index in inner.files.remove(&key).unwrap_or_default() {
+            inner.remove(key, index);
+        }
+    }
+
+    /// Drop all the blocks.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clears += 1;
+        inner.generations.clear();
+        inner.blocks.clear();
+        inner.lru.clear();
+        inner.files.clear();
+    }
+
+    // Drop the blocks of the given file from `first` to `last`, along with its last cached block,
+    // which may be a short one at the end of file, no longer so if the file grows.
+    fn invalidate_range(&self, key: u64, first: u64, last: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bump(key);
+        let indices: Vec<u64> = match inner.files.get(&key) {
+            Some(indices) => {
+                indices.range(first..last.saturating_add(1))
+                    .chain(indices.iter().next_back())
+                    .cloned()
+                    .collect()
+            }
+            None => return,
+        };
+        for index in indices {
+            inner.remove(key, index);
+        }
+    }
+
+    fn get(&self, key: u64, index: u64) -> Option<Arc<Vec<u8>>> {
+        self.inner.lock().unwrap().touch(key, index)
+    }
+
+    fn contains(&self, key: u64, index: u64) -> bool {
+        self.inner.lock().unwrap().blocks.contains_key(&(key, index))
+    }
+
+    fn generation(&self, key: u64) -> Generation {
+        self.inner.lock().unwrap().generation(key)
+    }
+
+    // Cache a block read from the handler, unless the file was invalidated since (at the given
+    // generation).
+    fn insert(&self, key: u64, index: u64, data: Arc<Vec<u8>>, generation: Generation) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation(key) != generation {
+            return;
+        }
+        inner.remove(key, index);
+        while inner.blocks.len() >= self.max_blocks {
+            let (&oldest, &(old_key, old_index)) = match inner.lru.iter().next() {
+                Some(entry) => entry,
+                None => break,
+            };
+            inner.lru.remove(&oldest);
+            inner.remove(old_key, old_index);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.blocks.insert((key, index), Block { data, last_use: tick });
+        inner.lru.insert(tick, (key, index));
+        inner.files.entry(key).or_default().insert(index);
+    }
+}
+
+/// Adaptor serving reads of a handler from a `BlockCache`, see the module docs. Reading a range
+/// with missing blocks reads them all from the handler at once, so the handler should read
+/// fully (see `FillingAdaptor`).
+/// Writes and truncates are passed on to the handler, invalidating the blocks they touch.
+#[derive(Debug)]
+pub struct CachedReader<R> {
+    inner: R,
+    cache: BlockCache,
+    key: u64,
+}
+
+impl<R> CachedReader<R> {
+    /// Wrap a handler of the file identified by `key` (e.g. its inode number), caching its blocks
+    /// in `cache`.
+    pub fn new(inner: R, cache: BlockCache, key: u64) -> CachedReader<R> {
+        CachedReader { inner, cache, key }
+    }
+
+    /// The wrapped handler.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwrap the wrapped handler.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Drop the cached blocks touched by writing `len` bytes at `offset`.
+    fn invalidate(&self, offset: u64, len: usize) {
+        let block_size = self.cache.block_size as u64;
+        let end = offset.saturating_add(len.max(1) as u64 - 1);
+        self.cache.invalidate_range(self.key, offset / block_size, end / block_size);
+    }
+}
+
+impl<R> CachedReader<R> where R: ReadFileLike {
+    // The blocks from `first` to `last`, or up to the end of file if it is before `last` (in
+    // which case the last block returned is short).
+    fn blocks(&self, first: u64, last: u64) -> Result<Vec<Arc<Vec<u8>>>> {
+        let block_size = self.cache.block_size;
+        let mut blocks = vec![];
+        let mut index = first;
+        while index <= last {
+            if let Some(data) = self.cache.get(self.key, index) {
+                let eof = data.len() < block_size;
+                blocks.push(data);
+                if eof {
+                    break;
+                }
+                index += 1;
+                continue;
+            }
+            // Read this block along with the missing ones following it.
+            let mut end = index + 1;
+            while end <= last && !self.cache.contains(self.key, end) {
+                end += 1;
+            }
+            let generation = self.cache.generation(self.key);
+            let mut raw = vec![0; (end - index) as usize * block_size];
+            let len = read_full_at(&self.inner, &mut raw, index * block_size as u64)?;
+            for (i, start) in (index..end).zip((0..).step_by(block_size)) {
+                let data = Arc::new(raw[start.min(len)..(start + block_size).min(len)].to_vec());
+                let eof = data.len() < block_size;
+                self.cache.insert(self.key, i, data.clone(), generation);
+                blocks.push(data);
+                if eof {
+                    return Ok(blocks);
+                }
+            }
+            index = end;
+        }
+        Ok(blocks)
+    }
+}
+
+impl<R> ReadFileLike for CachedReader<R> where R: ReadFileLike {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.cache.block_size as u64;
+        let first = offset / block_size;
+        let last = offset.saturating_add(buf.len() as u64 - 1) / block_size;
+        let mut skip = (offset % block_size) as usize;
+        let mut done = 0;
+        for data in self.blocks(first, last)? {
+            if skip >= data.len() {
+                break;
+            }
+            let len = (data.len() - skip).min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&data[skip..skip + len]);
+            done += len;
+            skip = 0;
+        }
+        Ok(done)
+    }
+}
+
+impl<R> WriteFileLike for CachedReader<R> where R: WriteFileLike {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let res = self.inner.write_at(buf, offset);
+        // Even a failed write may have written some of it.
+        self.invalidate(offset, buf.len());
+        res
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_buffered(&self) -> Result<()> {
+        self.inner.write_buffered()
+    }
+}
+
+impl<R> SetLenFileLike for CachedReader<R> where R: SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        let res = self.inner.set_len(size);
+        self.cache.invalidate(self.key);
+        res
+    }
+}
+
+impl<R> AttrFileLike for CachedReader<R> where R: AttrFileLike {
+    fn getattr(&self) -> Result<FileAttr> {
+        self.inner.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.inner.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.inner.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.inner.utimens(atime, mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::super::filelike::MemFile;
+
+    // A handler counting the reads reaching it (other than those at the end of file).
+    struct Counting(Arc<MemFile>, Arc<AtomicUsize>);
+
+    impl ReadFileLike for Counting {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            let len = self.0.read_at(buf, offset)?;
+            if len > 0 {
+                self.1.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(len)
+        }
+    }
+
+    impl WriteFileLike for Counting {
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            self.0.write_at(buf, offset)
+        }
+    }
+
+    fn reader(file: &Arc<MemFile>, cache: &BlockCache, key: u64)
+              -> (CachedReader<Counting>, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        (CachedReader::new(Counting(file.clone(), reads.clone()), cache.clone(), key), reads)
+    }
+
+    fn read(reader: &CachedReader<Counting>, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let len = reader.read_at(&mut buf, offset).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let file = Arc::new(MemFile::from_vec((0..40).collect()));
+        let cache = BlockCache::new(10, 30);
+        let (reader, reads) = reader(&file, &cache, 1);
+        assert_eq!(read(&reader, 0, 30), (0..30).collect::<Vec<u8>>());
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.cached_bytes(), 30);
+        // Use the first block again, so that the second one is the oldest.
+        read(&reader, 0, 10);
+        read(&reader, 30, 10);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.cached_bytes(), 30);
+        read(&reader, 0, 10);
+        read(&reader, 20, 10);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        read(&reader, 10, 10);
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn shares_blocks_across_handlers() {
+        let file = Arc::new(MemFile::from_vec((0..25).collect()));
+        let cache = BlockCache::new(10, 100);
+        let (first, first_reads) = reader(&file, &cache, 1);
+        let (second, second_reads) = reader(&file, &cache, 1);
+        let (other, other_reads) = reader(&file, &cache, 2);
+        assert_eq!(read(&first, 5, 100), (5..25).collect::<Vec<u8>>());
+        assert_eq!(read(&second, 0, 100), (0..25).collect::<Vec<u8>>());
+        assert_eq!(first_reads.load(Ordering::SeqCst), 1);
+        assert_eq!(second_reads.load(Ordering::SeqCst), 0);
+        read(&other, 0, 10);
+        assert_eq!(other_reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn writes_invalidate() {
+        let file = Arc::new(MemFile::from_vec(vec![1; 25]));
+        let cache = BlockCache::new(10, 100);
+        let (first, reads) = reader(&file, &cache, 1);
+        let (second, _) = reader(&file, &cache, 1);
+        read(&first, 0, 100);
+        second.write_at(&[2; 3], 12).unwrap();
+        assert_eq!(read(&first, 10, 10), [1, 1, 2, 2, 2, 1, 1, 1, 1, 1]);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        // Growing the file drops the short block at its end.
+        second.write_at(&[3; 10], 25).unwrap();
+        assert_eq!(read(&first, 20, 100), [1, 1, 1, 1, 1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3]);
+        read(&first, 0, 10);
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+        file.write_at(&[4], 0).unwrap();
+        cache.invalidate(1);
+        assert_eq!(read(&first, 0, 2), [4, 1]);
+    }
+
+    #[test]
+    fn generation_per_file() {
+        let cache = BlockCache::new(10, 100);
+        let data = Arc::new(vec![0; 10]);
+        let generation = cache.generation(1);
+        cache.invalidate(2);
+        cache.insert(1, 0, data.clone(), generation);
+        assert!(cache.contains(1, 0));
+        let generation = cache.generation(1);
+        cache.invalidate(1);
+        cache.insert(1, 0, data.clone(), generation);
+        assert!(!cache.contains(1, 0));
+        let generation = cache.generation(1);
+        cache.clear();
+        cache.insert(1, 0, data, generation);
+        assert!(!cache.contains(1, 0));
+    }
+}
@@ -0,0 +1,454 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Helpers for common pattern of `FilesystemFL::DirLike`: delegating listing the entries etc to
+//! the objects, the same way `filelike` does for files.
+//! Handler objects implement `ReadDirLike` (or `PagedReadDirLike`, for listing page by page), and
+//! are used with `FilesystemFLOpenDir`, which together with `FilesystemFLOpen` and
+//! `FilesystemFLMeta` can be wrapped in `filelike::OpenFs`.
+
+use std::fs::{self, ReadDir};
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::filelike::kind_from_file_type;
+use super::flags::OpenFlags;
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultReaddir};
+
+/// Trait to be implemented for providing the "lister" functionality, to be used with
+/// FilesystemFLOpenDir.
+pub trait ReadDirLike {
+    /// Get the entries of the directory, including "." and "..".
+    /// fuse-mt calls this once per handler, and caches the result for the following calls.
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>>;
+
+    /// Synchronize directory contents.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn copy_entry(entry: &DirectoryEntry) -> DirectoryEntry {
+    DirectoryEntry {
+        name: entry.name.clone(),
+        kind: entry.kind,
+    }
+}
+
+/// A snapshot of the entries, e.g. taken on `opendir`.
+impl ReadDirLike for Vec<DirectoryEntry> {
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>> {
+        Ok(self.iter().map(copy_entry).collect())
+    }
+}
+
+/// Implementation of a DirLike over `std::fs::ReadDir`, adding the "." and ".." entries (which it
+/// omits). The entries are read on the first `readdir`, and kept for the following ones.
+#[derive(Debug)]
+pub struct FsReadDir {
+    inner: Mutex<FsReadDirInner>,
+}
+
+#[derive(Debug)]
+enum FsReadDirInner {
+    Pending(ReadDir),
+    Read(Vec<(OsString, FileType)>),
+}
+
+impl FsReadDir {
+    /// Wrap an already-opened `ReadDir`.
+    pub fn new(read_dir: ReadDir) -> FsReadDir {
+        FsReadDir { inner: Mutex::new(FsReadDirInner::Pending(read_dir)) }
+    }
+
+    /// Open the directory at the given path (of the underlying filesystem).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FsReadDir> {
+        Ok(FsReadDir::new(fs::read_dir(path)?))
+    }
+}
+
+impl From<ReadDir> for FsReadDir {
+    fn from(read_dir: ReadDir) -> FsReadDir {
+        FsReadDir::new(read_dir)
+    }
+}
+
+impl ReadDirLike for FsReadDir {
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let FsReadDirInner::Pending(ref mut read_dir) = *inner {
+            let mut entries = vec![(".".into(), FileType::Directory), ("..".into(), FileType::Directory)];
+            for entry in read_dir {
+                let entry = entry?;
+                entries.push((entry.file_name(), kind_from_file_type(entry.file_type()?)));
+            }
+            *inner = FsReadDirInner::Read(entries);
+        }
+        match *inner {
+            FsReadDirInner::Read(ref entries) => {
+                Ok(entries.iter()
+                    .map(|&(ref name, kind)| DirectoryEntry { name: name.clone(), kind })
+                    .collect())
+            }
+            FsReadDirInner::Pending(_) => unreachable!(),
+        }
+    }
+}
+
+/// A position in a directory listing, as returned by `PagedReadDirLike::readdir_page`, for
+/// continuing the listing after it. The contents are up to the implementation, e.g. the name of the
+/// last entry returned, or a continuation token of a paginated store.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DirCursor(pub Vec<u8>);
+
+/// Result of a `readdir_page` call: the entries of the page, and the cursor for getting the next
+/// one, or `None` if this is the last page.
+pub type ResultDirPage = Result<(Vec<DirectoryEntry>, Option<DirCursor>)>;
+
+/// Trait to be implemented for providing the "lister" functionality page by page, for directories
+/// backed by a paginated store. To be used with FilesystemFLOpenDir through `PagedReadDir`.
+pub trait PagedReadDirLike {
+    /// Get the entries following the given cursor (or the first ones, for `None`), including "."
+    /// and "..".
+    /// The entries should be returned in a stable order, with the cursor denoting a position in
+    /// that order rather than an index (e.g. the last name returned, when listing by name), so
+    /// that entries present throughout the listing are neither skipped nor repeated, even if
+    /// others are added or removed in between pages. A cursor should stay valid as long as the
+    /// handler is open.
+    fn readdir_page(&self, cursor: Option<&DirCursor>) -> ResultDirPage;
+
+    /// Synchronize directory contents.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Adaptor implementing `ReadDirLike` for a `PagedReadDirLike`, keeping the position of the
+/// listing per handler. Pages are fetched one at a time by `next_page`. Note that `readdir` still
+/// fetches all the pages and returns them at once, as fuse-mt (as of 0.3) requires the whole
+/// listing, not passing on the offsets the kernel reads at; the listing is then held in memory
+/// like any other, only fetched in pages. Names which show up again on a later page (e.g. by being
+/// renamed in between pages) are only listed once by `readdir`, and a cycle of cursors fails the
+/// listing with `EIO`, rather than looping forever.
+#[derive(Debug)]
+pub struct PagedReadDir<T> {
+    inner: T,
+    state: Mutex<PagedState>,
+}
+
+#[derive(Debug)]
+struct PagedState {
+    // Where the next page starts, or `None` for the first one.
+    cursor: Option<DirCursor>,
+    done: bool,
+    // For detecting cycles of cursors with Brent's algorithm, in constant memory: the cursor
+    // saved at the last power of two of pages, the number of pages since, and that power of two.
+    saved: Option<DirCursor>,
+    since_saved: u64,
+    power: u64,
+}
+
+impl Default for PagedState {
+    fn default() -> PagedState {
+        PagedState { cursor: None, done: false, saved: None, since_saved: 0, power: 1 }
+    }
+}
+
+impl<T> PagedReadDir<T> where T: PagedReadDirLike {
+    /// Wrap a lister, starting at the first page.
+    pub fn new(inner: T) -> PagedReadDir<T> {
+        PagedReadDir { inner, state: Mutex::new(PagedState::default()) }
+    }
+
+    /// The wrapped lister.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Fetch the entries of the next page, or `None` after the last page.
+    pub fn next_page(&self) -> Result<Option<Vec<DirectoryEntry>>> {
+        self.fetch_page(&mut self.state.lock().unwrap())
+    }
+
+    /// Start the listing over, from the first page.
+    pub fn rewind(&self) {
+        *self.state.lock().unwrap() = PagedState::default();
+    }
+
+    fn fetch_page(&self, state: &mut PagedState) -> Result<Option<Vec<DirectoryEntry>>> {
+        if state.done {
+            return Ok(None);
+        }
+        let (page, next) = self.inner.readdir_page(state.cursor.as_ref())?;
+        match next {
+            Some(ref next) if state.saved.as_ref() == Some(next) => {
+                state.done = true;
+                return Err(Errno::EIO.with_context("readdir_page returned a cycle of cursors"));
+            }
+            Some(ref next) => {
+                state.since_saved += 1;
+                if state.since_saved == state.power {
+                    state.saved = Some(next.clone());
+                    state.since_saved = 0;
+                    state.power *= 2;
+                }
+            }
+            None => state.done = true,
+        }
+        state.cursor = next;
+        Ok(Some(page))
+    }
+}
+
+/// Remove the entries whose names were already listed, keeping the first of each.
+fn dedup_names(entries: &mut Vec<DirectoryEntry>) {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by(|&a, &b| entries[a].name.cmp(&entries[b].name).then(a.cmp(&b)));
+    let mut repeated = vec![false; entries.len()];
+    for pair in order.windows(2) {
+        if entries[pair[0]].name == entries[pair[1]].name {
+            repeated[pair[1]] = true;
+        }
+    }
+    let mut index = 0;
+    entries.retain(|_| {
+        index += 1;
+        !repeated[index - 1]
+    });
+}
+
+impl<T> ReadDirLike for PagedReadDir<T> where T: PagedReadDirLike {
+    /// List from the first page to the last, holding the handler's position throughout, so that
+    /// concurrent calls don't interleave their pages.
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>> {
+        let mut state = self.state.lock().unwrap();
+        *state = PagedState::default();
+        let mut entries = vec![];
+        while let Some(page) = self.fetch_page(&mut state)? {
+            entries.extend(page);
+        }
+        dedup_names(&mut entries);
+        Ok(entries)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Trait for standard usecase of `FilesystemFL::DirLike` - opendir returns a DirLike object, which
+/// supports listing the entries, and then the readdir and fsyncdir calls are passed directly to
+/// it. Types implementing this, `FilesystemFLOpen` and `FilesystemFLMeta` can be wrapped in
+/// `OpenFs`.
+pub trait FilesystemFLOpenDir {
+    /// The type of a directory handler used by this FS.
+    type DirLike: ReadDirLike;
+
+    /// Open a directory - matches `FilesystemFL::opendir` for overriding, see there.
+    /// This should be implemented.
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get the entries of a directory - matches `FilesystemFL::readdir` for overriding, see there.
+    /// This provides the functionality of this trait.
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        _dl.readdir()
+    }
+
+    /// Release a directory - matches `FilesystemFL::releasedir` for overriding, see there.
+    /// By default this does nothing (besides dropping the handler).
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        Ok(())
+    }
+
+    /// Write out any pending changes to a directory - matches `FilesystemFL::fsyncdir` for
+    /// overriding, see there.
+    /// This provides the functionality of this trait.
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        _dl.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::env;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    // Lists "0", "1", ... two names per page, with the cursor being the index of the next page,
+    // mapped through `next`.
+    struct Pages {
+        pages: usize,
+        next: fn(usize) -> usize,
+        calls: Mutex<usize>,
+    }
+
+    impl PagedReadDirLike for Pages {
+        fn readdir_page(&self, cursor: Option<&DirCursor>) -> ResultDirPage {
+            *self.calls.lock().unwrap() += 1;
+            let page = cursor.map_or(0, |cursor| cursor.0[0] as usize);
+            let entries = (2 * page..2 * page + 2)
+                .map(|i| DirectoryEntry { name: i.to_string().into(), kind: FileType::RegularFile })
+                .collect();
+            let next = (self.next)(page);
+            Ok((entries, if next < self.pages { Some(DirCursor(vec![next as u8])) } else { None }))
+        }
+    }
+
+    fn pages(pages: usize, next: fn(usize) -> usize) -> PagedReadDir<Pages> {
+        PagedReadDir::new(Pages { pages, next, calls: Mutex::new(0) })
+    }
+
+    fn names(entries: Vec<DirectoryEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.name.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn fetches_pages_lazily() {
+        let dir = pages(2, |page| page + 1);
+        assert_eq!(names(dir.next_page().unwrap().unwrap()), ["0", "1"]);
+        assert_eq!(*dir.inner().calls.lock().unwrap(), 1);
+        assert_eq!(names(dir.next_page().unwrap().unwrap()), ["2", "3"]);
+        assert!(dir.next_page().unwrap().is_none());
+        assert_eq!(*dir.inner().calls.lock().unwrap(), 2);
+        // `readdir` starts over.
+        assert_eq!(names(dir.readdir().unwrap()), ["0", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn skips_repeated_names() {
+        struct Renamed(Cell<bool>);
+        impl PagedReadDirLike for Renamed {
+            fn readdir_page(&self, cursor: Option<&DirCursor>) -> ResultDirPage {
+                let entry = |name: &str| DirectoryEntry { name: name.into(), kind: FileType::RegularFile };
+                match cursor {
+                    None => Ok((vec![entry("a"), entry("b")], Some(DirCursor(b"b".to_vec())))),
+                    Some(_) => {
+                        self.0.set(true);
+                        Ok((vec![entry("c"), entry("a")], None))
+                    }
+                }
+            }
+        }
+        let dir = PagedReadDir::new(Renamed(Cell::new(false)));
+        assert_eq!(names(dir.readdir().unwrap()), ["a", "b", "c"]);
+        assert!(dir.inner().0.get());
+    }
+
+    #[test]
+    fn fails_on_cursor_cycles() {
+        // Back and forth between the first two pages.
+        let dir = pages(3, |page| 1 - page);
+        assert_eq!(dir.readdir().map(names), Err(Errno::EIO));
+        assert!(dir.next_page().unwrap().is_none());
+        let dir = pages(3, |page| page);
+        assert_eq!(dir.readdir().map(names), Err(Errno::EIO));
+        // A longer cycle, entered after a few pages: 0, 1, 2, 3, 4, 5, 6, 2, 3, ...
+        let dir = pages(8, |page| if page == 6 { 2 } else { page + 1 });
+        assert_eq!(dir.readdir().map(names), Err(Errno::EIO));
+        assert!(*dir.inner().calls.lock().unwrap() < 30);
+        // Long listings without cycles are fine.
+        let dir = pages(200, |page| page + 1);
+        assert_eq!(dir.readdir().unwrap().len(), 400);
+    }
+
+    #[test]
+    fn concurrent_listings() {
+        let dir = Arc::new(pages(50, |page| page + 1));
+        let threads: Vec<_> = (0..4).map(|_| {
+            let dir = dir.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let listed = names(dir.readdir().unwrap());
+                    assert_eq!(listed.len(), 100);
+                    assert_eq!(listed[99], "99");
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    fn kinds(entries: Vec<DirectoryEntry>) -> Vec<(String, FileType)> {
+        let mut kinds: Vec<_> =
+            entries.into_iter().map(|entry| (entry.name.into_string().unwrap(), entry.kind)).collect();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+        kinds
+    }
+
+    #[test]
+    fn fs_read_dir() {
+        let dir = env::temp_dir().join(format!("fuse_fl-dirlike-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        fs::write(dir.join("f"), b"").unwrap();
+        let read_dir = FsReadDir::open(&dir).unwrap();
+        let listed = vec![(".".to_string(), FileType::Directory),
+                          ("..".to_string(), FileType::Directory),
+                          ("d".to_string(), FileType::Directory),
+                          ("f".to_string(), FileType::RegularFile)];
+        assert_eq!(kinds(read_dir.readdir().unwrap()), listed);
+        // The entries are kept for the following calls.
+        fs::remove_file(dir.join("f")).unwrap();
+        assert_eq!(kinds(read_dir.readdir().unwrap()), listed);
+        assert_eq!(kinds(FsReadDir::open(&dir).unwrap().readdir().unwrap()).len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(FsReadDir::open(&dir).err(), Some(Errno::ENOENT));
+    }
+
+    #[test]
+    fn open_dir_delegates_to_handler() {
+        #[derive(Default)]
+        struct Recording(Mutex<Vec<&'static str>>);
+        impl ReadDirLike for Recording {
+            fn readdir(&self) -> Result<Vec<DirectoryEntry>> {
+                self.0.lock().unwrap().push("readdir");
+                Ok(vec![DirectoryEntry { name: "a".into(), kind: FileType::Symlink }])
+            }
+
+            fn flush(&self) -> Result<()> {
+                self.0.lock().unwrap().push("flush");
+                Err(Errno::EROFS)
+            }
+        }
+        struct Fs;
+        impl FilesystemFLOpenDir for Fs {
+            type DirLike = Recording;
+        }
+        let req = || RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+        let path = Path::new("/d");
+        let dl = Recording::default();
+        assert_eq!(kinds(Fs.readdir(req(), path, &dl).unwrap()), [("a".to_string(), FileType::Symlink)]);
+        assert_eq!(Fs.fsyncdir(req(), path, &dl, true), Err(Errno::EROFS));
+        assert_eq!(*dl.0.lock().unwrap(), ["readdir", "flush"]);
+        assert_eq!(Fs.releasedir(req(), path, dl, OpenFlags::DIRECTORY), Ok(()));
+        assert_eq!(Fs.opendir(req(), path, OpenFlags::DIRECTORY).err(), Some(Errno::ENOSYS));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! The error type used throughout this crate.
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io;
+use libc::{self, c_int};
+
+/// An error number, as returned to the kernel, optionally with some context for logging.
+/// Converts to and from a raw `c_int` (so `?` works between this and the fuse_mt results), and
+/// from an `io::Error`, mapping errors with no OS error number by their kind.
+/// Two `Errno`-s are equal if their numbers are, regardless of context.
+#[derive(Debug, Clone)]
+pub struct Errno {
+    code: c_int,
+    context: Option<Cow<'static, str>>,
+}
+
+macro_rules! errno_consts {
+    ($($name:ident),*) => {
+        impl Errno {
+            $(
+                #[doc = concat!("`", stringify!($name), "`.")]
+                pub const $name: Errno = Errno { code: libc::$name, context: None };
+            )*
+
+            /// The symbolic name of the error number (e.g. `"ENOENT"`), if it is a known one.
+            pub fn name(&self) -> Option<&'static str> {
+                $(
+                    if self.code == libc::$name {
+                        return Some(stringify!($name));
+                    }
+                )*
+                None
+            }
+        }
+    }
+}
+
+errno_consts!(EPERM, ENOENT, ESRCH, EINTR, EIO, ENXIO, E2BIG, EBADF, EAGAIN, ENOMEM, EACCES,
+              EFAULT, EBUSY, EEXIST, EXDEV, ENODEV, ENOTDIR, EISDIR, EINVAL, ENFILE, EMFILE,
+              ENOTTY, ETXTBSY, EFBIG, ENOSPC, ESPIPE, EROFS, EMLINK, EPIPE, ERANGE, EDEADLK,
+              ENAMETOOLONG, ENOLCK, ENOSYS, ENOTEMPTY, ELOOP, ENOTSUP, ETIMEDOUT, ECONNREFUSED,
+              ECONNRESET, ECONNABORTED, ENOTCONN, EADDRINUSE, EADDRNOTAVAIL, EDQUOT, ESTALE,
+              ECANCELED, EWOULDBLOCK, ENODATA);
+
+impl Errno {
+    /// An error from a raw error number.
+    pub fn new(code: c_int) -> Errno {
+        Errno { code, context: None }
+    }
+
+    /// The error of the last OS call (i.e. `errno`).
+    pub fn last_os_error() -> Errno {
+        io::Error::last_os_error().into()
+    }
+
+    /// The raw error number.
+    pub fn code(&self) -> c_int {
+        self.code
+    }
+
+    /// The context attached to the error, if any.
+    pub fn context(&self) -> Option<&str> {
+        self.context.as_ref().map(|x| x.as_ref())
+    }
+
+    /// Attach context to the error, replacing any previous context.
+    pub fn with_context<S>(self, context: S) -> Errno where S: Into<Cow<'static, str>> {
+        Errno { code: self.code, context: Some(context.into()) }
+    }
+}
+
+impl PartialEq for Errno {
+    fn eq(&self, other: &Errno) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for Errno {}
+
+impl PartialEq<c_int> for Errno {
+    fn eq(&self, other: &c_int) -> bool {
+        self.code == *other
+    }
+}
+
+impl From<c_int> for Errno {
+    fn from(code: c_int) -> Errno {
+        Errno::new(code)
+    }
+}
+
+impl From<Errno> for c_int {
+    fn from(e: Errno) -> c_int {
+        e.code
+    }
+}
+
+fn errno_for_kind(kind: io::ErrorKind) -> c_int {
+    use std::io::ErrorKind::*;
+    match kind {
+        NotFound => libc::ENOENT,
+        PermissionDenied => libc::EACCES,
+        ConnectionRefused => libc::ECONNREFUSED,
+        ConnectionReset => libc::ECONNRESET,
+        ConnectionAborted => libc::ECONNABORTED,
+        NotConnected => libc::ENOTCONN,
+        AddrInUse => libc::EADDRINUSE,
+        AddrNotAvailable => libc::EADDRNOTAVAIL,
+        BrokenPipe => libc::EPIPE,
+        AlreadyExists => libc::EEXIST,
+        WouldBlock => libc::EAGAIN,
+        InvalidInput => libc::EINVAL,
+        TimedOut => libc::ETIMEDOUT,
+        Interrupted => libc::EINTR,
+        Unsupported => libc::ENOTSUP,
+        OutOfMemory => libc::ENOMEM,
+        // InvalidData, WriteZero, UnexpectedEof, Other, ...
+        _ => libc::EIO,
+    }
+}
+
+impl From<io::Error> for Errno {
+    fn from(e: io::Error) -> Errno {
+        match e.raw_os_error() {
+            Some(code) => Errno::new(code),
+            None => Errno::new(errno_for_kind(e.kind())).with_context(e.to_string()),
+        }
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(e: Errno) -> io::Error {
+        io::Error::from_raw_os_error(e.code)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "errno {}", self.code)?,
+        }
+        if let Some(ref context) = self.context {
+            write!(f, ": {}", context)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Errno {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_io_error() {
+        let e = Errno::from(io::Error::from_raw_os_error(libc::ENOENT));
+        assert_eq!(e, Errno::ENOENT);
+        assert_eq!(e.context(), None);
+        let e = Errno::from(io::Error::new(io::ErrorKind::UnexpectedEof, "short file"));
+        assert_eq!(e, Errno::EIO);
+        assert_eq!(e.context(), Some("short file"));
+        assert_eq!(e.to_string(), "EIO: short file");
+        let e = Errno::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(e, Errno::ENOENT);
+        assert_eq!(io::Error::from(e).raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn c_int_conversion() {
+        let e: Errno = libc::EXDEV.into();
+        assert_eq!(e.name(), Some("EXDEV"));
+        assert_eq!(c_int::from(e.with_context("across mounts")), libc::EXDEV);
+        assert_eq!(Errno::new(-1).to_string(), "errno -1");
+    }
+}
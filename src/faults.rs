@@ -0,0 +1,600 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A wrapper injecting faults into the calls of a `FilesystemFL`, for testing how programs cope
+//! with errors, short reads and writes, or a slow filesystem.
+//! Faults are described by `FaultRule`-s, which are checked in the order they were added on every
+//! call; the first one matching the call (by its name, path, probability and number of matching
+//! calls so far) is applied. Calls of entries in a directory (e.g. `lookup`, `create`, `rename`)
+//! are matched by the path of the entry, i.e. the parent joined with the name.
+//! NOTE: errors injected into `release` and `releasedir` are returned after the handler is
+//! released by the wrapped filesystem anyway, as it can't be kept open.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::Timespec;
+
+use super::flags::{OpenFlags, OpenReplyFlags};
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite,
+            ResultReaddir, ResultStatfs, ResultXattr};
+
+/// A fault to inject into a call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Fail the call with the given error, without passing it on.
+    Error(Errno),
+    /// Read or write at most the given number of bytes (on other calls, this does nothing).
+    /// NOTE: unless the file is opened with `direct_io` (see `FaultInjector::with_direct_io`), the
+    /// kernel reads through its page cache, and fills the rest of a short read with zeroes rather
+    /// than returning it short.
+    Short(u32),
+    /// Sleep for the given duration before passing the call on.
+    Delay(Duration),
+}
+
+/// A rule for injecting a fault: the fault, and which calls it applies to. By default it applies
+/// to all calls; each of the conditions narrows that down.
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    fault: Fault,
+    op: Option<&'static str>,
+    path: Option<Vec<u8>>,
+    probability: f64,
+    nth: Option<u64>,
+}
+
+impl FaultRule {
+    /// A rule injecting the given fault into all calls.
+    pub fn new(fault: Fault) -> FaultRule {
+        FaultRule { fault, op: None, path: None, probability: 1.0, nth: None }
+    }
+
+    /// Only apply to calls of the given name, e.g. `"read"`.
+    pub fn op(self, op: &'static str) -> FaultRule {
+        FaultRule { op: Some(op), ..self }
+    }
+
+    /// Only apply to calls of paths matching the given glob pattern: `?` matches any character
+    /// and `*` any number of characters, other than "/"; `**` matches any number of characters,
+    /// including "/". E.g. `"/logs/*.log"` or `"/data/**"`.
+    pub fn path<S: AsRef<OsStr> + ?Sized>(self, glob: &S) -> FaultRule {
+        FaultRule { path: Some(glob.as_ref().as_bytes().to_vec()), ..self }
+    }
+
+    /// Only apply to the matching calls with the given probability (between 0 and 1).
+    pub fn probability(self, probability: f64) -> FaultRule {
+        FaultRule { probability, ..self }
+    }
+
+    /// Only apply to the `n`-th matching call (counting from 1), e.g. to fail the third write.
+    pub fn nth(self, n: u64) -> FaultRule {
+        FaultRule { nth: Some(n), ..self }
+    }
+
+    fn matches(&self, op: &str, path: &Path) -> bool {
+        self.op.map_or(true, |o| o == op) &&
+        self.path.as_ref().map_or(true, |glob| glob_match(glob, path.as_os_str().as_bytes()))
+    }
+}
+
+fn glob_match(glob: &[u8], s: &[u8]) -> bool {
+    match glob.first() {
+        None => s.is_empty(),
+        Some(&b'*') if glob.get(1) == Some(&b'*') => {
+            (0..s.len() + 1).any(|i| glob_match(&glob[2..], &s[i..]))
+        }
+        Some(&b'*') => {
+            let end = s.iter().position(|&c| c == b'/').unwrap_or(s.len());
+            (0..end + 1).any(|i| glob_match(&glob[1..], &s[i..]))
+        }
+        Some(&b'?') => s.first().map_or(false, |&c| c != b'/') && glob_match(&glob[1..], &s[1..]),
+        Some(c) => s.first() == Some(c) && glob_match(&glob[1..], &s[1..]),
+    }
+}
+
+#[derive(Debug)]
+struct RulesInner {
+    // The rules, with the number of calls matching each so far.
+    rules: Vec<(FaultRule, u64)>,
+    // State of a xorshift generator, for the probabilities.
+    rng: u64,
+}
+
+impl RulesInner {
+    fn random(&mut self) -> f64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn fault(&mut self, op: &str, path: &Path) -> Option<Fault> {
+        for i in 0..self.rules.len() {
+            if !self.rules[i].0.matches(op, path) {
+                continue;
+            }
+            self.rules[i].1 += 1;
+            let (ref rule, calls) = self.rules[i];
+            if rule.nth.map_or(false, |n| n != calls) {
+                continue;
+            }
+            let probability = rule.probability;
+            let fault = rule.fault.clone();
+            if probability >= 1.0 || self.random() < probability {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+/// A handle to the rules of a `FaultInjector`, for changing them at any time (e.g. while it is
+/// mounted).
+#[derive(Debug, Clone)]
+pub struct FaultRules(Arc<Mutex<RulesInner>>);
+
+impl FaultRules {
+    /// Add a rule, after the existing ones.
+    pub fn add(&self, rule: FaultRule) {
+        self.0.lock().unwrap().rules.push((rule, 0));
+    }
+
+    /// Remove all the rules.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().rules.clear();
+    }
+}
+
+/// A `FilesystemFL` forwarding all calls to `T`, injecting faults into them by its rules.
+#[derive(Debug)]
+pub struct FaultInjector<T> {
+    inner: T,
+    rules: FaultRules,
+    direct_io: bool,
+}
+
+impl<T> FaultInjector<T> where T: FilesystemFL {
+    /// Wrap the given filesystem, with no rules yet.
+    pub fn new(inner: T) -> FaultInjector<T> {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64);
+        FaultInjector::with_seed(inner, seed.unwrap_or(0))
+    }
+
+    /// Wrap the given filesystem, with a fixed seed for the probabilities of the rules, so that
+    /// runs with the same calls inject the same faults.
+    pub fn with_seed(inner: T, seed: u64) -> FaultInjector<T> {
+        // xorshift never leaves 0.
+        let rng = if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed };
+        FaultInjector {
+            inner,
+            rules: FaultRules(Arc::new(Mutex::new(RulesInner { rules: vec![], rng }))),
+            direct_io: false,
+        }
+    }
+
+    /// Add a rule, after the existing ones.
+    pub fn with_rule(self, rule: FaultRule) -> FaultInjector<T> {
+        self.rules.add(rule);
+        self
+    }
+
+    /// Open all files with `direct_io`, bypassing the page cache of the kernel, so that short
+    /// reads reach the programs reading them (see `Fault::Short`).
+    pub fn with_direct_io(self) -> FaultInjector<T> {
+        FaultInjector { direct_io: true, ..self }
+    }
+
+    /// A handle to the rules.
+    pub fn rules(&self) -> FaultRules {
+        self.rules.clone()
+    }
+
+    /// The wrapped filesystem.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // Apply the fault for the call, if any: sleep for a delay, fail for an error, or return the
+    // limit of a short read or write.
+    fn inject(&self, op: &str, path: &Path) -> Result<Option<u32>> {
+        let fault = self.rules.0.lock().unwrap().fault(op, path);
+        match fault {
+            Some(Fault::Error(e)) => Err(e),
+            Some(Fault::Short(limit)) => Ok(Some(limit)),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn reply_flags(&self, flags: OpenReplyFlags) -> OpenReplyFlags {
+        if self.direct_io { flags | OpenReplyFlags::DIRECT_IO } else { flags }
+    }
+}
+
+impl<T> FilesystemFL for FaultInjector<T> where T: FilesystemFL {
+    type FileLike = T::FileLike;
+    type DirLike = T::DirLike;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.inject("init", Path::new("/"))?;
+        self.inner.init(_req)
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        // Nothing to fail, but delays apply.
+        let _ = self.inject("destroy", Path::new("/"));
+        self.inner.destroy(_req)
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.inject("lookup", &_parent.join(_name))?;
+        self.inner.lookup(_req, _parent, _name)
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        self.inject("getattr", _path)?;
+        self.inner.getattr(_req, _path, _fl)
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        self.inject("chmod", _path)?;
+        self.inner.chmod(_req, _path, _fl, _mode)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        self.inject("chown", _path)?;
+        self.inner.chown(_req, _path, _fl, _uid, _gid)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        self.inject("truncate", _path)?;
+        self.inner.truncate(_req, _path, _fl, _size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        self.inject("utimens", _path)?;
+        self.inner.utimens(_req, _path, _fl, _atime, _mtime)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        self.inject("utimens_macos", _path)?;
+        self.inner.utimens_macos(_req, _path, _fl, _crtime, _chgtime, _bkuptime, _flags)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.inject("readlink", _path)?;
+        self.inner.readlink(_req, _path)
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        self.inject("mknod", &_parent.join(_name))?;
+        self.inner.mknod(_req, _parent, _name, _mode, _rdev)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        self.inject("mkdir", &_parent.join(_name))?;
+        self.inner.mkdir(_req, _parent, _name, _mode)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inject("unlink", &_parent.join(_name))?;
+        self.inner.unlink(_req, _parent, _name)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inject("rmdir", &_parent.join(_name))?;
+        self.inner.rmdir(_req, _parent, _name)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        self.inject("symlink", &_parent.join(_name))?;
+        self.inner.symlink(_req, _parent, _name, _target)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        self.inject("rename", &_parent.join(_name))?;
+        self.inner.rename(_req, _parent, _name, _newparent, _newname)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.inject("link", _path)?;
+        self.inner.link(_req, _path, _newparent, _newname)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        self.inject("open", _path)?;
+        let (fl, flags) = self.inner.open(_req, _path, _flags)?;
+        Ok((fl, self.reply_flags(flags)))
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        let size = match self.inject("read", _path)? {
+            Some(limit) => limit.min(_size),
+            None => _size,
+        };
+        self.inner.read(_req, _path, _fl, _offset, size)
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        let mut data = _data;
+        if let Some(limit) = self.inject("write", _path)? {
+            data.truncate(limit as usize);
+        }
+        self.inner.write(_req, _path, _fl, _offset, data, _flags)
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        self.inject("flush", _path)?;
+        self.inner.flush(_req, _path, _fl, _lock_owner)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        let fault = self.inject("release", _path);
+        let res = self.inner.release(_req, _path, _fl, _flags, _lock_owner, _flush);
+        fault?;
+        res
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        self.inject("fsync", _path)?;
+        self.inner.fsync(_req, _path, _fl, _datasync)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        self.inject("opendir", _path)?;
+        self.inner.opendir(_req, _path, _flags)
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        self.inject("readdir", _path)?;
+        self.inner.readdir(_req, _path, _dl)
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        let fault = self.inject("releasedir", _path);
+        let res = self.inner.releasedir(_req, _path, _dl, _flags);
+        fault?;
+        res
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        self.inject("fsyncdir", _path)?;
+        self.inner.fsyncdir(_req, _path, _dl, _datasync)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.inject("statfs", _path)?;
+        self.inner.statfs(_req, _path)
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.inject("setxattr", _path)?;
+        self.inner.setxattr(_req, _path, _name, _value, _flags, _position)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.inject("getxattr", _path)?;
+        self.inner.getxattr(_req, _path, _name, _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.inject("listxattr", _path)?;
+        self.inner.listxattr(_req, _path, _size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inject("removexattr", _path)?;
+        self.inner.removexattr(_req, _path, _name)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.inject("access", _path)?;
+        self.inner.access(_req, _path, _mask)
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        self.inject("create", &_parent.join(_name))?;
+        let created = self.inner.create(_req, _parent, _name, _mode, _flags)?;
+        Ok(CreatedEntryObj { flags: self.reply_flags(created.flags), ..created })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::flags::AccessMode;
+    use super::super::memfs::{InMemory, MemFs};
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_match(glob.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(matches("/logs/*.log", "/logs/a.log"));
+        assert!(matches("/logs/*.log", "/logs/.log"));
+        assert!(!matches("/logs/*.log", "/logs/a/b.log"));
+        assert!(!matches("/logs/*.log", "/logs/a.txt"));
+        assert!(matches("/data/**", "/data/a/b/c"));
+        assert!(matches("/data/**", "/data/"));
+        assert!(!matches("/data/**", "/data"));
+        assert!(matches("/**/*.tmp", "/a/b/c.tmp"));
+        assert!(matches("/f?", "/f1"));
+        assert!(!matches("/f?", "/f"));
+        assert!(!matches("/a?b", "/a/b"));
+        assert!(matches("", ""));
+        assert!(!matches("/a", "/ab"));
+    }
+
+    fn file(fs: &FaultInjector<MemFs>) -> <MemFs as FilesystemFL>::FileLike {
+        let flags = AccessMode::ReadWrite.into();
+        fs.inner().create(req(), Path::new("/"), OsStr::new("f"), 0o644, flags).unwrap().fl
+    }
+
+    #[test]
+    fn rules() {
+        let fs = FaultInjector::with_seed(MemFs::new(InMemory::new()), 1)
+            .with_rule(FaultRule::new(Fault::Error(Errno::EIO)).op("write").nth(2))
+            .with_rule(FaultRule::new(Fault::Short(2)).path("/f"));
+        let fl = file(&fs);
+        let path = Path::new("/f");
+        assert_eq!(fs.write(req(), path, &fl, 0, b"abcd".to_vec(), 0), Ok(2));
+        assert_eq!(fs.write(req(), path, &fl, 2, b"cd".to_vec(), 0), Err(Errno::EIO));
+        assert_eq!(fs.write(req(), path, &fl, 2, b"cd".to_vec(), 0), Ok(2));
+        assert_eq!(fs.read(req(), path, &fl, 0, 4), Ok(b"ab".to_vec()));
+        assert_eq!(fs.read(req(), Path::new("/g"), &fl, 0, 4), Ok(b"abcd".to_vec()));
+        fs.rules().clear();
+        assert_eq!(fs.read(req(), path, &fl, 0, 4), Ok(b"abcd".to_vec()));
+        fs.rules().add(FaultRule::new(Fault::Error(Errno::EIO)).probability(0.0));
+        assert_eq!(fs.read(req(), path, &fl, 0, 4), Ok(b"abcd".to_vec()));
+    }
+
+    #[test]
+    fn direct_io() {
+        let fs = FaultInjector::new(MemFs::new(InMemory::new()));
+        file(&fs);
+        let flags = fs.open(req(), Path::new("/f"), AccessMode::ReadOnly.into()).unwrap().1;
+        assert!(!flags.contains(OpenReplyFlags::DIRECT_IO));
+        let fs = fs.with_direct_io();
+        let flags = fs.open(req(), Path::new("/f"), AccessMode::ReadOnly.into()).unwrap().1;
+        assert!(flags.contains(OpenReplyFlags::DIRECT_IO));
+        let created = fs.create(req(), Path::new("/"), OsStr::new("g"), 0o644,
+                                AccessMode::WriteOnly.into()).unwrap();
+        assert!(created.flags.contains(OpenReplyFlags::DIRECT_IO));
+    }
+}
@@ -11,19 +11,29 @@
 //! Handler objects implement either both of `ReadFileLike` and `WriteFileLike`, for
 //! general-purpose opening, and used with `FilesystemFLOpen`; or different handlers are used for
 //! read-only, write-only and read-write opening, and used with `FilesystemFLRwOpen`.
+//! A filesystem implementing `FilesystemFLOpen` (directly or through `FilesystemFLRwOpen`),
+//! `dirlike::FilesystemFLOpenDir` and `FilesystemFLMeta` can then be wrapped in `OpenFs`, which
+//! implements `FilesystemFL`.
 
-use std::fs::File;
+use std::fs::{self, File, Metadata, Permissions};
+use std::io;
 use std::cmp::min;
 use std::cell::RefCell;
-use std::sync::{Mutex, RwLock};
-use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::ffi::OsStr;
 use std::path::Path;
 use libc;
+use time::{self, Timespec};
 
+use super::dirlike::FilesystemFLOpenDir;
+use super::flags::{AccessMode, OpenFlags};
 use super::fusefl::*;
+use super::sys::{check, to_libc_timespec};
 use fuse_mt::*;
-use super::Result;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite,
+            ResultReaddir, ResultStatfs, ResultXattr};
 
 /// Trait to be implemented for providing the "reader" functionality, to be used with
 /// FilesystemFLOpen or FilesystemFLRwOpen.
@@ -34,6 +44,7 @@ pub trait ReadFileLike {
     /// this is when the file has been opened in 'direct_io' mode, in which case the
     /// return value of the read system call will reflect the return value of this
     /// operation.
+    /// Implementations which may return short reads anyway can be wrapped in `FillingAdaptor`.
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
 }
 
@@ -45,25 +56,98 @@ pub trait WriteFileLike {
     /// exception to this is when the file has been opened in 'direct_io' mode, in
     /// which case the return value of the write system call will reflect the return
     /// value of this operation.
+    /// Implementations which may return short writes anyway can be wrapped in `FillingAdaptor`.
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize>;
 
     /// Synchronize file contents.
     fn flush(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Write out data buffered in the handler (if any), without synchronizing it to storage like
+    /// `flush`, returning errors in writing it. `FilesystemFLOpen` calls this on `close`.
+    fn write_buffered(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Read into `buf` with repeated calls to `read_at`, until it is full or EOF is reached (i.e. a
+/// read returns 0), retrying on `EINTR`. Returns the number of bytes read.
+pub fn read_full_at<R>(reader: &R, buf: &mut [u8], offset: u64) -> Result<usize>
+    where R: ReadFileLike + ?Sized {
+    let mut done = 0;
+    while done < buf.len() {
+        match reader.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(ref e) if *e == Errno::EINTR => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Write all of `buf` with repeated calls to `write_at`, retrying on `EINTR`. A write of 0 bytes
+/// fails with `EIO`.
+pub fn write_full_at<W>(writer: &W, buf: &[u8], offset: u64) -> Result<usize>
+    where W: WriteFileLike + ?Sized {
+    let mut done = 0;
+    while done < buf.len() {
+        match writer.write_at(&buf[done..], offset + done as u64) {
+            Ok(0) => return Err(Errno::EIO.with_context("write_at wrote 0 bytes")),
+            Ok(n) => done += n,
+            Err(ref e) if *e == Errno::EINTR => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Adaptor guaranteeing the contract of `ReadFileLike` and `WriteFileLike` for implementations
+/// which may read or write less than requested: short reads are retried until the buffer is
+/// filled or EOF is reached, short writes until everything is written, and `EINTR` is retried.
+#[derive(Debug)]
+pub struct FillingAdaptor<T>(pub T);
+
+impl<T> ReadFileLike for FillingAdaptor<T> where T: ReadFileLike {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        read_full_at(&self.0, buf, offset)
+    }
 }
 
-//FIXME: both of the below impl-s don't guarantee filling the buffer!
+impl<T> WriteFileLike for FillingAdaptor<T> where T: WriteFileLike {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        write_full_at(&self.0, buf, offset)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn write_buffered(&self) -> Result<()> {
+        self.0.write_buffered()
+    }
+}
 
 impl ReadFileLike for File {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        FileExt::read_at(self, buf, offset).map_err(|x| x.raw_os_error().unwrap())
+        let mut done = 0;
+        while done < buf.len() {
+            match FileExt::read_at(self, &mut buf[done..], offset + done as u64) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(done)
     }
 }
 
 impl WriteFileLike for File {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        FileExt::write_at(self, buf, offset).map_err(|x| x.raw_os_error().unwrap())
+        FileExt::write_all_at(self, buf, offset)?;
+        Ok(buf.len())
     }
 
     // NOTE: we can't use the flush method from Write, because that wants a &mut. However, for now
@@ -79,43 +163,416 @@ impl ReadFileLike for [u8] {
     }
 }
 
+fn do_read_at(this: &[u8], buf: &mut [u8], offset: u64) -> usize {
+    if offset >= this.len() as u64 {
+        return 0;
+    }
+    let offset = offset as usize;
+    let len = min(buf.len(), this.len() - offset);
+    buf[..len].copy_from_slice(&this[offset..offset + len]);
+    len
+}
+
 impl<'a> ReadFileLike for &'a [u8] {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        if offset > usize::max_value() as u64 {
-            return Ok(0);
-        }
-        let offset = offset as usize;
-        let len = min(buf.len(), self.len() - offset);
-        buf[..len].copy_from_slice(&self[offset..offset + len]);
-        Ok(len)
+        Ok(do_read_at(self, buf, offset))
     }
 }
 
-fn do_write_at(this: &mut [u8], buf: &[u8], offset: u64) -> usize {
-    if offset > usize::max_value() as u64 {
-        return 0;
+// The buffers behind these can't grow, so writes are cut at their end (and fail with `ENOSPC` if
+// nothing fits).
+fn do_write_at(this: &mut [u8], buf: &[u8], offset: u64) -> Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    if offset >= this.len() as u64 {
+        return Err(Errno::ENOSPC);
     }
     let offset = offset as usize;
     let len = min(buf.len(), this.len() - offset);
     this[offset..offset + len].copy_from_slice(&buf[..len]);
-    len
+    Ok(len)
 }
 
 impl WriteFileLike for RefCell<[u8]> {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        Ok(do_write_at(&mut *self.borrow_mut(), buf, offset))
+        do_write_at(&mut self.borrow_mut(), buf, offset)
     }
 }
 
 impl WriteFileLike for Mutex<[u8]> {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        Ok(do_write_at(&mut *self.lock().unwrap(), buf, offset))
+        do_write_at(&mut self.lock().unwrap(), buf, offset)
     }
 }
 
 impl WriteFileLike for RwLock<[u8]> {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        Ok(do_write_at(&mut *self.write().unwrap(), buf, offset))
+        do_write_at(&mut self.write().unwrap(), buf, offset)
+    }
+}
+
+impl<T> ReadFileLike for Arc<T> where T: ReadFileLike + ?Sized {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+impl<T> WriteFileLike for Arc<T> where T: WriteFileLike + ?Sized {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn write_buffered(&self) -> Result<()> {
+        (**self).write_buffered()
+    }
+}
+
+/// An in-memory file, which can be read and written concurrently, growing as needed (filling holes
+/// with zeroes) and truncated. Reads past its end are short, like for a regular file.
+/// Growing the file beyond its maximum size (see `set_max_size`) fails with `EFBIG`, and failing
+/// to allocate the memory for it fails with `ENOMEM`, rather than aborting.
+/// It also keeps its own attributes (see `AttrFileLike`), updating the size and times as it is
+/// written to.
+/// To share the same file among several handlers, wrap it in an `Arc`.
+#[derive(Debug)]
+pub struct MemFile {
+    inner: RwLock<MemFileInner>,
+}
+
+#[derive(Debug)]
+struct MemFileInner {
+    data: Vec<u8>,
+    attr: FileAttr,
+    max_size: u64,
+}
+
+impl MemFileInner {
+    fn touch(&mut self) {
+        let now = time::get_time();
+        self.attr.mtime = now;
+        self.attr.ctime = now;
+    }
+
+    // Truncate or extend the data to `size`.
+    fn resize(&mut self, size: u64) -> Result<()> {
+        if size > self.max_size || size > usize::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let size = size as usize;
+        if size > self.data.len() {
+            let additional = size - self.data.len();
+            self.data.try_reserve(additional).map_err(|_| Errno::ENOMEM)?;
+        }
+        self.data.resize(size, 0);
+        Ok(())
+    }
+}
+
+impl MemFile {
+    /// Create a new, empty file.
+    pub fn new() -> MemFile {
+        MemFile::default()
+    }
+
+    /// Create a file with the given contents. It starts out as a regular file with mode 0644,
+    /// owned by root, with all times set to now.
+    pub fn from_vec(data: Vec<u8>) -> MemFile {
+        let now = time::get_time();
+        let attr = FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        MemFile { inner: RwLock::new(MemFileInner { data, attr, max_size: u64::MAX }) }
+    }
+
+    /// Set the maximum size the file may grow to, with writes and truncates beyond it failing with
+    /// `EFBIG` (by default, it is only limited by the memory available). A file already larger
+    /// than that is left as is.
+    pub fn set_max_size(&self, max_size: u64) {
+        self.inner.write().unwrap().max_size = max_size;
+    }
+
+    /// The size of the file.
+    pub fn len(&self) -> u64 {
+        self.inner.read().unwrap().data.len() as u64
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().data.is_empty()
+    }
+
+    /// Truncate or extend (with zeroes) the file to the given size.
+    pub fn set_len(&self, size: u64) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.resize(size)?;
+        inner.touch();
+        Ok(())
+    }
+
+    /// Set the number of hard links reported for the file (1 to begin with), for filesystems
+    /// which link it under several names.
+    pub fn set_nlink(&self, nlink: u32) {
+        let mut inner = self.inner.write().unwrap();
+        inner.attr.nlink = nlink;
+        inner.attr.ctime = time::get_time();
+    }
+
+    /// Get a copy of the contents of the file.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.inner.read().unwrap().data.clone()
+    }
+
+    /// Unwrap, returning the contents of the file.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.inner.into_inner().unwrap().data
+    }
+}
+
+impl Default for MemFile {
+    fn default() -> MemFile {
+        MemFile::from_vec(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for MemFile {
+    fn from(data: Vec<u8>) -> MemFile {
+        MemFile::from_vec(data)
+    }
+}
+
+impl ReadFileLike for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(do_read_at(&self.inner.read().unwrap().data, buf, offset))
+    }
+}
+
+impl WriteFileLike for MemFile {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        let mut inner = self.inner.write().unwrap();
+        if (inner.data.len() as u64) < end {
+            inner.resize(end)?;
+        }
+        inner.data[offset as usize..end as usize].copy_from_slice(buf);
+        inner.touch();
+        Ok(buf.len())
+    }
+}
+
+/// Trait to be implemented for providing the "truncate" functionality on an open file, to be used
+/// with FilesystemFLOpen or FilesystemFLRwOpen (for the write and read-write handlers). The
+/// default returns ENOSYS, so that `OpenFs` falls back to `FilesystemFLMeta::truncate`.
+pub trait SetLenFileLike {
+    /// Truncate or extend (with zeroes) the file to the given size, i.e. `ftruncate`.
+    fn set_len(&self, _size: u64) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+impl<T> SetLenFileLike for FillingAdaptor<T> where T: SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.0.set_len(size)
+    }
+}
+
+impl SetLenFileLike for File {
+    fn set_len(&self, size: u64) -> Result<()> {
+        File::set_len(self, size).map_err(Into::into)
+    }
+}
+
+impl SetLenFileLike for MemFile {
+    fn set_len(&self, size: u64) -> Result<()> {
+        MemFile::set_len(self, size)
+    }
+}
+
+impl<T> SetLenFileLike for Arc<T> where T: SetLenFileLike + ?Sized {
+    fn set_len(&self, size: u64) -> Result<()> {
+        (**self).set_len(size)
+    }
+}
+
+/// Trait to be implemented for providing attribute management on an open file (`fstat`,
+/// `fchmod`, `fchown` and `futimens`), to be used with FilesystemFLOpen or FilesystemFLRwOpen.
+/// This keeps working after the file was unlinked or renamed. Every method defaults to `ENOSYS`,
+/// in which case `OpenFs` falls back to the path-based method of `FilesystemFLMeta`.
+pub trait AttrFileLike {
+    /// Get the attributes of the file. The inode number is filled in by fuse-mt.
+    fn getattr(&self) -> Result<FileAttr> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Change the mode of the file.
+    fn chmod(&self, _mode: u32) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Change the owner UID and/or group GID of the file.
+    fn chown(&self, _uid: Option<u32>, _gid: Option<u32>) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set the access and/or modification times of the file.
+    fn utimens(&self, _atime: Option<Timespec>, _mtime: Option<Timespec>) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+impl<T> AttrFileLike for FillingAdaptor<T> where T: AttrFileLike {
+    fn getattr(&self) -> Result<FileAttr> {
+        self.0.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.0.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.0.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.0.utimens(atime, mtime)
+    }
+}
+
+/// Convert the metadata of a file, as returned by `std::fs`, to the attributes reported to FUSE.
+pub fn attr_from_metadata(md: &Metadata) -> FileAttr {
+    FileAttr {
+        ino: md.ino(),
+        size: md.size(),
+        blocks: md.blocks(),
+        atime: Timespec::new(md.atime(), md.atime_nsec() as i32),
+        mtime: Timespec::new(md.mtime(), md.mtime_nsec() as i32),
+        ctime: Timespec::new(md.ctime(), md.ctime_nsec() as i32),
+        crtime: Timespec::new(0, 0),
+        kind: kind_from_file_type(md.file_type()),
+        perm: (md.mode() & 0o7777) as u16,
+        nlink: md.nlink() as u32,
+        uid: md.uid(),
+        gid: md.gid(),
+        rdev: md.rdev() as u32,
+        flags: 0,
+    }
+}
+
+/// Convert the type of a file, as returned by `std::fs`, to the one reported to FUSE.
+pub fn kind_from_file_type(ft: fs::FileType) -> FileType {
+    if ft.is_dir() {
+        FileType::Directory
+    } else if ft.is_symlink() {
+        FileType::Symlink
+    } else if ft.is_block_device() {
+        FileType::BlockDevice
+    } else if ft.is_char_device() {
+        FileType::CharDevice
+    } else if ft.is_fifo() {
+        FileType::NamedPipe
+    } else if ft.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    }
+}
+
+impl AttrFileLike for File {
+    fn getattr(&self) -> Result<FileAttr> {
+        Ok(attr_from_metadata(&self.metadata()?))
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.set_permissions(Permissions::from_mode(mode)).map_err(Into::into)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        // -1 leaves the id unchanged.
+        check(unsafe { libc::fchown(self.as_raw_fd(), uid.unwrap_or(!0), gid.unwrap_or(!0)) })
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        let times = [to_libc_timespec(atime), to_libc_timespec(mtime)];
+        check(unsafe { libc::futimens(self.as_raw_fd(), times.as_ptr()) })
+    }
+}
+
+impl AttrFileLike for MemFile {
+    fn getattr(&self) -> Result<FileAttr> {
+        let inner = self.inner.read().unwrap();
+        let mut attr = inner.attr;
+        attr.size = inner.data.len() as u64;
+        attr.blocks = (attr.size + 511) / 512;
+        Ok(attr)
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.attr.perm = (mode & 0o7777) as u16;
+        inner.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(uid) = uid {
+            inner.attr.uid = uid;
+        }
+        if let Some(gid) = gid {
+            inner.attr.gid = gid;
+        }
+        inner.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(atime) = atime {
+            inner.attr.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            inner.attr.mtime = mtime;
+        }
+        inner.attr.ctime = time::get_time();
+        Ok(())
+    }
+}
+
+impl<T> AttrFileLike for Arc<T> where T: AttrFileLike + ?Sized {
+    fn getattr(&self) -> Result<FileAttr> {
+        (**self).getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        (**self).chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        (**self).chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        (**self).utimens(atime, mtime)
     }
 }
 
@@ -127,21 +584,25 @@ pub enum NoFile {}
 #[allow(unused_variables)]
 impl ReadFileLike for NoFile {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 }
 
 #[allow(unused_variables)]
 impl WriteFileLike for NoFile {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     fn flush(&self) -> Result<()> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 }
 
+impl SetLenFileLike for NoFile {}
+
+impl AttrFileLike for NoFile {}
+
 /// Naive implementation of a read-write FileLike, given a read FileLike and
 /// a write FileLike implementation.
 #[derive(Debug)]
@@ -164,11 +625,41 @@ impl<R, W> WriteFileLike for ReadWriteAdaptor<R, W> where W: WriteFileLike {
     fn flush(&self) -> Result<()> {
         self.writer.flush()
     }
+
+    fn write_buffered(&self) -> Result<()> {
+        self.writer.write_buffered()
+    }
+}
+
+impl<R, W> SetLenFileLike for ReadWriteAdaptor<R, W> where W: SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.writer.set_len(size)
+    }
+}
+
+/// The attributes are managed through the writer.
+impl<R, W> AttrFileLike for ReadWriteAdaptor<R, W> where W: AttrFileLike {
+    fn getattr(&self) -> Result<FileAttr> {
+        self.writer.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.writer.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.writer.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.writer.utimens(atime, mtime)
+    }
 }
 
 /// Implementation of a FileLike which can be either read-only, write-only or read-write.
 /// Implementes both `ReadLike` and `WriteLike`, returning EBADF in case of trying to write to a
-/// read-only file or vice-versa (just like you'd expect).
+/// read-only file or vice-versa (just like you'd expect). Also implements `SetLenFileLike`,
+/// returning EINVAL for a read-only file (like `ftruncate`).
 #[derive(Debug)]
 pub enum ModalFileLike<R, W, RW> {
     /// Read-only file - will EBADF on `write` or `flush`.
@@ -188,7 +679,7 @@ impl<R, W, RW> ReadFileLike for ModalFileLike<R, W, RW>
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         match *self {
             ReadOnly(ref r) => r.read_at(buf, offset),
-            WriteOnly(_) => Err(libc::EBADF),
+            WriteOnly(_) => Err(Errno::EBADF),
             ReadWrite(ref rw) => rw.read_at(buf, offset),
         }
     }
@@ -199,7 +690,7 @@ impl<R, W, RW> WriteFileLike for ModalFileLike<R, W, RW>
           RW: WriteFileLike {
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
         match *self {
-            ReadOnly(_) => Err(libc::EBADF),
+            ReadOnly(_) => Err(Errno::EBADF),
             WriteOnly(ref w) => w.write_at(buf, offset),
             ReadWrite(ref rw) => rw.write_at(buf, offset),
         }
@@ -207,17 +698,75 @@ impl<R, W, RW> WriteFileLike for ModalFileLike<R, W, RW>
 
     fn flush(&self) -> Result<()> {
         match *self {
-            ReadOnly(_) => Err(libc::EBADF),
+            ReadOnly(_) => Err(Errno::EBADF),
             WriteOnly(ref w) => w.flush(),
             ReadWrite(ref rw) => rw.flush(),
         }
     }
+
+    /// Nothing is buffered for a read-only file, so this succeeds.
+    fn write_buffered(&self) -> Result<()> {
+        match *self {
+            ReadOnly(_) => Ok(()),
+            WriteOnly(ref w) => w.write_buffered(),
+            ReadWrite(ref rw) => rw.write_buffered(),
+        }
+    }
+}
+
+impl<R, W, RW> SetLenFileLike for ModalFileLike<R, W, RW>
+    where W: SetLenFileLike,
+          RW: SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        match *self {
+            ReadOnly(_) => Err(Errno::EINVAL),
+            WriteOnly(ref w) => w.set_len(size),
+            ReadWrite(ref rw) => rw.set_len(size),
+        }
+    }
+}
+
+impl<R, W, RW> AttrFileLike for ModalFileLike<R, W, RW>
+    where R: AttrFileLike,
+          W: AttrFileLike,
+          RW: AttrFileLike {
+    fn getattr(&self) -> Result<FileAttr> {
+        match *self {
+            ReadOnly(ref r) => r.getattr(),
+            WriteOnly(ref w) => w.getattr(),
+            ReadWrite(ref rw) => rw.getattr(),
+        }
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        match *self {
+            ReadOnly(ref r) => r.chmod(mode),
+            WriteOnly(ref w) => w.chmod(mode),
+            ReadWrite(ref rw) => rw.chmod(mode),
+        }
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        match *self {
+            ReadOnly(ref r) => r.chown(uid, gid),
+            WriteOnly(ref w) => w.chown(uid, gid),
+            ReadWrite(ref rw) => rw.chown(uid, gid),
+        }
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        match *self {
+            ReadOnly(ref r) => r.utimens(atime, mtime),
+            WriteOnly(ref w) => w.utimens(atime, mtime),
+            ReadWrite(ref rw) => rw.utimens(atime, mtime),
+        }
+    }
 }
 
 /// Trait for using different types for the read, write and read-write files. The read-write type
 /// can be a ReadWriteAdaptor over the read and write ones.
 /// Everything implementing this implements `FilesystemFLOpen`, dispatching the open and create calls
-/// according to the `ACCMODE` to the open or create method for the right type of file.
+/// according to the `AccessMode` to the open or create method for the right type of file.
 /// NOTE: fsync_metadata is not dispatched, but rather gets an enum over the possible file types,
 /// because persumably the synchronization of metadata is mostly unaffected by how the file was
 /// opened.
@@ -227,38 +776,38 @@ impl<R, W, RW> WriteFileLike for ModalFileLike<R, W, RW>
 /// create_readwrite to return `EACCES`.
 pub trait FilesystemFLRwOpen {
     /// Type for read-only file handlers.
-    type ReadLike: ReadFileLike; // = NoFile;
+    type ReadLike: ReadFileLike + AttrFileLike; // = NoFile;
     /// Type for write-only file handlers.
-    type WriteLike: WriteFileLike; // = NoFile;
+    type WriteLike: WriteFileLike + SetLenFileLike + AttrFileLike; // = NoFile;
     /// Type for read-write file handlers.
-    type ReadWriteLike: ReadFileLike + WriteFileLike;
+    type ReadWriteLike: ReadFileLike + WriteFileLike + SetLenFileLike + AttrFileLike;
         // = ReadWriteAdaptor<Self::ReadLike, Self::WriteLike>;
 
     /// Open a file read-only.
     fn open_read(&self,
                  _req: RequestInfo,
                  _path: &Path,
-                 _flags: u32)
+                 _flags: OpenFlags)
                  -> ResultOpenObj<Self::ReadLike> {
-        Err(libc::EACCES)
+        Err(Errno::EACCES)
     }
 
     /// Open a file write-only.
     fn open_write(&self,
                   _req: RequestInfo,
                   _path: &Path,
-                  _flags: u32)
+                  _flags: OpenFlags)
                   -> ResultOpenObj<Self::WriteLike> {
-        Err(libc::EROFS)
+        Err(Errno::EROFS)
     }
 
     /// Open a file read-write.
     fn open_readwrite(&self,
                       _req: RequestInfo,
                       _path: &Path,
-                      _flags: u32)
+                      _flags: OpenFlags)
                       -> ResultOpenObj<Self::ReadWriteLike> {
-        Err(libc::EROFS)
+        Err(Errno::EROFS)
     }
 
     /// Create a file, open for read-only.
@@ -267,9 +816,9 @@ pub trait FilesystemFLRwOpen {
                    _parent: &Path,
                    _name: &OsStr,
                    _mode: u32,
-                   _flags: u32)
+                   _flags: OpenFlags)
                    -> ResultCreateObj<Self::ReadLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a file, open for write-only.
@@ -278,9 +827,9 @@ pub trait FilesystemFLRwOpen {
                     _parent: &Path,
                     _name: &OsStr,
                     _mode: u32,
-                    _flags: u32)
+                    _flags: OpenFlags)
                     -> ResultCreateObj<Self::WriteLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a file, open for read-write.
@@ -289,9 +838,9 @@ pub trait FilesystemFLRwOpen {
                         _parent: &Path,
                         _name: &OsStr,
                         _mode: u32,
-                        _flags: u32)
+                        _flags: OpenFlags)
                         -> ResultCreateObj<Self::ReadWriteLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// `fsync` (i.e. flush) only the metadata of a file (with given path and handler). For
@@ -304,24 +853,23 @@ pub trait FilesystemFLRwOpen {
                       _path: &Path,
                       _fl: &ModalFileLike<Self::ReadLike, Self::WriteLike, Self::ReadWriteLike>)
                       -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 }
 
 /// Trait for standard usecase of FilesystemFL - open and create methods return a FileLike object,
 /// which supports reading, writing and flushing of data, and then these calls are passed directly
-/// to it. Once specialization (RFC #1210) lands, this will be integrated as a default impl of
-/// FilesystemFL for types implementing this; for now, this repeats some methods from FilesystemFL,
-/// and these should be manually called in the implementation of FilesystemFL.
-// Part of this will become a default impl of FilesystemFL when RFC #1210 lands.
+/// to it. Types implementing this and `FilesystemFLMeta` can be wrapped in `OpenFs`, which
+/// implements FilesystemFL by dispatching the file calls here and all the rest to
+/// `FilesystemFLMeta`.
 pub trait FilesystemFLOpen {
     /// The type of a file handler used by this FS.
-    type FileLike: ReadFileLike + WriteFileLike;
+    type FileLike: ReadFileLike + WriteFileLike + SetLenFileLike + AttrFileLike;
 
     /// Open a file - matches `FilesystemFL::open` for overriding, see there.
     /// This should be implemented.
-    fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpenObj<Self::FileLike> {
-        Err(libc::ENOSYS)
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        Err(Errno::ENOSYS)
     }
 
     /// Create a file - matches `FilesystemFL::create` for overriding, see there.
@@ -332,9 +880,9 @@ pub trait FilesystemFLOpen {
               _parent: &Path,
               _name: &OsStr,
               _mode: u32,
-              _flags: u32)
+              _flags: OpenFlags)
               -> ResultCreateObj<Self::FileLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Read from a file - matches `FilesystemFL::read` for overriding, see there.
@@ -347,11 +895,10 @@ pub trait FilesystemFLOpen {
             _size: u32)
             -> ResultData {
         let _size = _size as usize;
-        let mut vec = Vec::<u8>::with_capacity(_size);
-        unsafe { vec.set_len(_size) };
+        let mut vec = vec![0; _size];
         let num_read = _fl.read_at(vec.as_mut_slice(), _offset)?;
         assert!(num_read <= _size);
-        unsafe { vec.set_len(num_read) };
+        vec.truncate(num_read);
         Ok(vec)
     }
 
@@ -385,27 +932,111 @@ pub trait FilesystemFLOpen {
         }
     }
 
+    /// Get the attributes of an open file - matches `FilesystemFL::getattr` when called on an open
+    /// file, for overriding, see there. Getting them by path only is left to `FilesystemFLMeta`.
+    /// This provides the functionality of this trait; the attributes are returned with a zero TTL,
+    /// as they might not match those of the path anymore.
+    fn getattr(&self, _req: RequestInfo, _path: &Path, _fl: &Self::FileLike) -> ResultGetattr {
+        Ok((Timespec::new(0, 0), _fl.getattr()?))
+    }
+
+    /// Change the mode of an open file - matches `FilesystemFL::chmod` when called on an open
+    /// file, for overriding, see there.
+    /// This provides the functionality of this trait.
+    fn chmod(&self, _req: RequestInfo, _path: &Path, _fl: &Self::FileLike, _mode: u32) -> ResultEmpty {
+        _fl.chmod(_mode)
+    }
+
+    /// Change the owner of an open file - matches `FilesystemFL::chown` when called on an open
+    /// file, for overriding, see there.
+    /// This provides the functionality of this trait.
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        _fl.chown(_uid, _gid)
+    }
+
+    /// Set the timestamps of an open file - matches `FilesystemFL::utimens` when called on an open
+    /// file, for overriding, see there.
+    /// This provides the functionality of this trait.
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: &Self::FileLike,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        _fl.utimens(_atime, _mtime)
+    }
+
+    /// Set the length of an open file - matches `FilesystemFL::truncate` when called on an open
+    /// file, for overriding, see there. Truncating by path only is left to `FilesystemFLMeta`.
+    /// This provides the functionality of this trait.
+    fn truncate(&self, _req: RequestInfo, _path: &Path, _fl: &Self::FileLike, _size: u64) -> ResultEmpty {
+        _fl.set_len(_size)
+    }
+
+    /// Flush a file on close - matches `FilesystemFL::flush` for overriding, see there.
+    /// By default this writes out the data buffered by the handler (see
+    /// `WriteFileLike::write_buffered`), so that errors in doing so are returned to `close`.
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        _fl.write_buffered()
+    }
+
+    /// Release a file - matches `FilesystemFL::release` for overriding, see there.
+    /// By default this calls `flush` if `flush` is set, and then writes out the data still
+    /// buffered by the handler, before it is dropped.
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        let res = if _flush {
+            self.flush(_req, _path, &_fl, _lock_owner)
+        } else {
+            Ok(())
+        };
+        res.and(_fl.write_buffered())
+    }
+
     /// `fsync` (i.e. flush) only the metadata of a file (with given path and handler). For
     /// `fsync`-ing the contents of the file, implement `WriteFileLike::flush` for
     /// `Self::FileLike`.
     /// This should be implemented, it is used by `fsync` (in conjuction with `FileLike::flush`).
     fn fsync_metadata(&self, _req: RequestInfo, _path: &Path, _fl: &Self::FileLike) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 }
 
-// Part of this will become a default impl of FilesystemFL when RFC #1210 lands.
 impl<T> FilesystemFLOpen for T where T: FilesystemFLRwOpen {
     type FileLike = ModalFileLike<<Self as FilesystemFLRwOpen>::ReadLike,
         <Self as FilesystemFLRwOpen>::WriteLike,
                   <Self as FilesystemFLRwOpen>::ReadWriteLike>;
 
-    fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpenObj<Self::FileLike> {
-        match _flags as i32 & libc::O_ACCMODE {
-            libc::O_RDONLY => map_res_open(self.open_read(_req, _path, _flags), |fl| ReadOnly(fl)),
-            libc::O_WRONLY => map_res_open(self.open_write(_req, _path, _flags), |fl| WriteOnly(fl)),
-            libc::O_RDWR => map_res_open(self.open_readwrite(_req, _path, _flags), |fl| ReadWrite(fl)),
-            _ => Err(libc::EINVAL),
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        match _flags.access_mode()? {
+            AccessMode::ReadOnly => {
+                map_res_open(self.open_read(_req, _path, _flags), ReadOnly)
+            }
+            AccessMode::WriteOnly => {
+                map_res_open(self.open_write(_req, _path, _flags), WriteOnly)
+            }
+            AccessMode::ReadWrite => {
+                map_res_open(self.open_readwrite(_req, _path, _flags), ReadWrite)
+            }
         }
     }
 
@@ -416,22 +1047,21 @@ impl<T> FilesystemFLOpen for T where T: FilesystemFLRwOpen {
               _parent: &Path,
               _name: &OsStr,
               _mode: u32,
-              _flags: u32)
+              _flags: OpenFlags)
               -> ResultCreateObj<Self::FileLike> {
-        match _flags as i32 & libc::O_ACCMODE {
-            libc::O_RDONLY => {
+        match _flags.access_mode()? {
+            AccessMode::ReadOnly => {
                 map_res_create(self.create_read(_req, _parent, _name, _mode, _flags),
-                               |fl| ReadOnly(fl))
+                               ReadOnly)
             }
-            libc::O_WRONLY => {
+            AccessMode::WriteOnly => {
                 map_res_create(self.create_write(_req, _parent, _name, _mode, _flags),
-                               |fl| WriteOnly(fl))
+                               WriteOnly)
             }
-            libc::O_RDWR => {
+            AccessMode::ReadWrite => {
                 map_res_create(self.create_readwrite(_req, _parent, _name, _mode, _flags),
-                               |fl| ReadWrite(fl))
+                               ReadWrite)
             }
-            _ => Err(libc::EINVAL),
         }
     }
 
@@ -439,3 +1069,843 @@ impl<T> FilesystemFLOpen for T where T: FilesystemFLRwOpen {
         FilesystemFLRwOpen::fsync_metadata(self, _req, _path, _fl)
     }
 }
+
+/// Trait for all the methods of FilesystemFL which are not handled by `FilesystemFLOpen` or
+/// `FilesystemFLOpenDir`, i.e. everything except for `open`, `create`, `read`, `write`, `flush`,
+/// `release`, `fsync`, `opendir`, `readdir`, `releasedir` and `fsyncdir`. Each method matches the
+/// method of the same name in FilesystemFL, see there.
+/// Together with `FilesystemFLOpen` and `FilesystemFLOpenDir`, this is used by `OpenFs` to
+/// implement FilesystemFL.
+/// NOTE: when called on an open file, `getattr`, `chmod`, `chown`, `utimens` and `truncate` are
+/// dispatched by `OpenFs` to `FilesystemFLOpen` first, and only get here if that fails with
+/// `ENOSYS`.
+pub trait FilesystemFLMeta: FilesystemFLOpen + FilesystemFLOpenDir {
+    /// Called on mount, before any other function.
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Err(Errno::new(0))
+    }
+
+    /// Called on filesystem unmount.
+    fn destroy(&self, _req: RequestInfo) {
+        // Nothing.
+    }
+
+    /// Look up a filesystem entry and get its attributes.
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get the attributes of a filesystem entry.
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Change the mode of a filesystem entry.
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Change the owner UID and/or group GID of a filesystem entry.
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set the length of a file.
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set timestamps of a filesystem entry.
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set timestamps of a filesystem entry (with extra options only used on MacOS).
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Read a symbolic link.
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a special file.
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a directory.
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a file.
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove a directory.
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a symbolic link.
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Rename a filesystem entry.
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Create a hard link.
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get filesystem statistics.
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Set a file extended attribute.
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Get a file extended attribute.
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        Err(Errno::ENOSYS)
+    }
+
+    /// List extended attributes for a file.
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Remove an extended attribute for a file.
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+
+    /// Check for access to a file.
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// Adaptor struct implementing FilesystemFL for a type implementing `FilesystemFLOpen`,
+/// `FilesystemFLOpenDir` and `FilesystemFLMeta`: the file calls (`open`, `create`, `read`,
+/// `write`, `flush`, `release` and `fsync`) are dispatched to the first, the directory calls
+/// (`opendir`, `readdir`, `releasedir` and `fsyncdir`) to the second, and all the rest to the
+/// last, except for attribute calls on an open file, which go to the first first (see
+/// `FilesystemFLMeta`).
+#[derive(Debug)]
+pub struct OpenFs<T> {
+    inner: T,
+}
+
+impl<T> OpenFs<T> where T: FilesystemFLMeta {
+    /// Wrap a filesystem.
+    pub fn new(inner: T) -> OpenFs<T> {
+        OpenFs { inner }
+    }
+
+    /// Get a reference to the wrapped filesystem.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Unwrap, returning the wrapped filesystem.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> FilesystemFL for OpenFs<T> where T: FilesystemFLMeta {
+    type FileLike = <T as FilesystemFLOpen>::FileLike;
+    type DirLike = <T as FilesystemFLOpenDir>::DirLike;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.inner.init(_req)
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        self.inner.destroy(_req)
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.inner.lookup(_req, _parent, _name)
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        if let Some(fl) = _fl {
+            match FilesystemFLOpen::getattr(&self.inner, clone_req(&_req), _path, fl) {
+                Err(ref e) if *e == Errno::ENOSYS => (),
+                res => return res,
+            }
+        }
+        FilesystemFLMeta::getattr(&self.inner, _req, _path, _fl)
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        if let Some(fl) = _fl {
+            match FilesystemFLOpen::chmod(&self.inner, clone_req(&_req), _path, fl, _mode) {
+                Err(ref e) if *e == Errno::ENOSYS => (),
+                res => return res,
+            }
+        }
+        FilesystemFLMeta::chmod(&self.inner, _req, _path, _fl, _mode)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        if let Some(fl) = _fl {
+            match FilesystemFLOpen::chown(&self.inner, clone_req(&_req), _path, fl, _uid, _gid) {
+                Err(ref e) if *e == Errno::ENOSYS => (),
+                res => return res,
+            }
+        }
+        FilesystemFLMeta::chown(&self.inner, _req, _path, _fl, _uid, _gid)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        if let Some(fl) = _fl {
+            match FilesystemFLOpen::truncate(&self.inner, clone_req(&_req), _path, fl, _size) {
+                Err(ref e) if *e == Errno::ENOSYS => (),
+                res => return res,
+            }
+        }
+        FilesystemFLMeta::truncate(&self.inner, _req, _path, _fl, _size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        if let Some(fl) = _fl {
+            match FilesystemFLOpen::utimens(&self.inner, clone_req(&_req), _path, fl, _atime, _mtime) {
+                Err(ref e) if *e == Errno::ENOSYS => (),
+                res => return res,
+            }
+        }
+        FilesystemFLMeta::utimens(&self.inner, _req, _path, _fl, _atime, _mtime)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        self.inner.utimens_macos(_req, _path, _fl, _crtime, _chgtime, _bkuptime, _flags)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.inner.readlink(_req, _path)
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        self.inner.mknod(_req, _parent, _name, _mode, _rdev)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        self.inner.mkdir(_req, _parent, _name, _mode)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inner.unlink(_req, _parent, _name)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inner.rmdir(_req, _parent, _name)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        self.inner.symlink(_req, _parent, _name, _target)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        self.inner.rename(_req, _parent, _name, _newparent, _newname)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.inner.link(_req, _path, _newparent, _newname)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        FilesystemFLOpen::open(&self.inner, _req, _path, _flags)
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        FilesystemFLOpen::read(&self.inner, _req, _path, _fl, _offset, _size)
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        FilesystemFLOpen::write(&self.inner, _req, _path, _fl, _offset, _data, _flags)
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        FilesystemFLOpen::flush(&self.inner, _req, _path, _fl, _lock_owner)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        FilesystemFLOpen::release(&self.inner, _req, _path, _fl, _flags, _lock_owner, _flush)
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        FilesystemFLOpen::fsync(&self.inner, _req, _path, _fl, _datasync)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        FilesystemFLOpenDir::opendir(&self.inner, _req, _path, _flags)
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        FilesystemFLOpenDir::readdir(&self.inner, _req, _path, _dl)
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        FilesystemFLOpenDir::releasedir(&self.inner, _req, _path, _dl, _flags)
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        FilesystemFLOpenDir::fsyncdir(&self.inner, _req, _path, _dl, _datasync)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.inner.statfs(_req, _path)
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.inner.setxattr(_req, _path, _name, _value, _flags, _position)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.inner.getxattr(_req, _path, _name, _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.inner.listxattr(_req, _path, _size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.inner.removexattr(_req, _path, _name)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.inner.access(_req, _path, _mask)
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        FilesystemFLOpen::create(&self.inner, _req, _parent, _name, _mode, _flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::super::flags::OpenReplyFlags;
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// A file handler with only the data of a file, leaving its attributes and length to the
+    /// filesystem.
+    #[derive(Debug)]
+    struct DataOnly {
+        file: Arc<MemFile>,
+        calls: Calls,
+    }
+
+    impl ReadFileLike for DataOnly {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+    }
+
+    impl WriteFileLike for DataOnly {
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            self.file.write_at(buf, offset)
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.calls.lock().unwrap().push("flush".to_string());
+            Ok(())
+        }
+
+        fn write_buffered(&self) -> Result<()> {
+            self.calls.lock().unwrap().push("write_buffered".to_string());
+            Ok(())
+        }
+    }
+
+    impl SetLenFileLike for DataOnly {}
+
+    impl AttrFileLike for DataOnly {}
+
+    /// A filesystem with a single file, whichever the path.
+    #[derive(Debug, Default)]
+    struct OneFile {
+        file: Arc<MemFile>,
+        calls: Calls,
+    }
+
+    impl OneFile {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn handler(&self) -> DataOnly {
+            DataOnly { file: self.file.clone(), calls: self.calls.clone() }
+        }
+    }
+
+    impl FilesystemFLOpen for OneFile {
+        type FileLike = DataOnly;
+
+        fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<DataOnly> {
+            self.record(format!("open {}", _path.display()));
+            Ok((self.handler(), OpenReplyFlags::KEEP_CACHE))
+        }
+
+        fn create(&self,
+                  _req: RequestInfo,
+                  _parent: &Path,
+                  _name: &OsStr,
+                  _mode: u32,
+                  _flags: OpenFlags)
+                  -> ResultCreateObj<DataOnly> {
+            self.record(format!("create {}", _parent.join(_name).display()));
+            Ok(CreatedEntryObj {
+                ttl: Timespec::new(1, 0),
+                attr: self.file.getattr()?,
+                fl: self.handler(),
+                flags: OpenReplyFlags::DIRECT_IO,
+            })
+        }
+
+        fn fsync_metadata(&self, _req: RequestInfo, _path: &Path, _fl: &DataOnly) -> ResultEmpty {
+            self.record("fsync_metadata".to_string());
+            Ok(())
+        }
+    }
+
+    impl FilesystemFLOpenDir for OneFile {
+        type DirLike = Vec<DirectoryEntry>;
+    }
+
+    impl FilesystemFLMeta for OneFile {
+        fn getattr(&self, _req: RequestInfo, _path: &Path, _fl: Option<&DataOnly>) -> ResultGetattr {
+            self.record(format!("getattr {}", _fl.is_some()));
+            Ok((Timespec::new(1, 0), self.file.getattr()?))
+        }
+
+        fn chmod(&self, _req: RequestInfo, _path: &Path, _fl: Option<&DataOnly>, _mode: u32) -> ResultEmpty {
+            self.record(format!("chmod {}", _fl.is_some()));
+            self.file.chmod(_mode)
+        }
+
+        fn truncate(&self, _req: RequestInfo, _path: &Path, _fl: Option<&DataOnly>, _size: u64) -> ResultEmpty {
+            self.record(format!("truncate {}", _fl.is_some()));
+            self.file.set_len(_size)
+        }
+    }
+
+    /// A file handler reading and writing at most 3 bytes at a time, and interrupted on every
+    /// other call.
+    #[derive(Debug, Default)]
+    struct Choppy {
+        file: MemFile,
+        calls: AtomicUsize,
+    }
+
+    impl Choppy {
+        fn interrupted(&self) -> bool {
+            self.calls.fetch_add(1, Ordering::SeqCst) % 2 == 0
+        }
+    }
+
+    impl ReadFileLike for Choppy {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            if self.interrupted() {
+                return Err(Errno::EINTR);
+            }
+            let len = min(buf.len(), 3);
+            self.file.read_at(&mut buf[..len], offset)
+        }
+    }
+
+    impl WriteFileLike for Choppy {
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            if self.interrupted() {
+                return Err(Errno::EINTR);
+            }
+            self.file.write_at(&buf[..min(buf.len(), 3)], offset)
+        }
+    }
+
+    fn calls(fs: &OpenFs<OneFile>) -> Vec<String> {
+        fs.get_ref().calls.lock().unwrap().drain(..).collect()
+    }
+
+    /// A fresh path under the system temporary directory.
+    fn temp_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        env::temp_dir().join(format!("fuse_fl-filelike-{}-{}",
+                                     process::id(),
+                                     NEXT.fetch_add(1, Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn mem_file_reads_past_eof() {
+        let file = MemFile::from_vec(b"hello".to_vec());
+        let mut buf = [0; 8];
+        assert_eq!(file.read_at(&mut buf, 3), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(file.read_at(&mut buf, 5), Ok(0));
+        assert_eq!(file.read_at(&mut buf, 1 << 60), Ok(0));
+    }
+
+    #[test]
+    fn mem_file_fills_holes() {
+        let file = MemFile::from_vec(b"ab".to_vec());
+        assert_eq!(file.write_at(b"cd", 4), Ok(2));
+        assert_eq!(file.to_vec(), b"ab\0\0cd");
+        assert_eq!(file.write_at(b"X", 1), Ok(1));
+        assert_eq!(file.to_vec(), b"aX\0\0cd");
+        // Empty writes don't extend the file.
+        assert_eq!(file.write_at(b"", 100), Ok(0));
+        assert_eq!(file.len(), 6);
+        assert_eq!(file.getattr().unwrap().size, 6);
+    }
+
+    #[test]
+    fn mem_file_truncates() {
+        let file = MemFile::from_vec(b"hello".to_vec());
+        file.set_len(2).unwrap();
+        assert_eq!(file.to_vec(), b"he");
+        file.set_len(4).unwrap();
+        assert_eq!(file.to_vec(), b"he\0\0");
+        file.set_len(0).unwrap();
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn mem_file_size_limits() {
+        let file = MemFile::new();
+        file.set_max_size(8);
+        assert_eq!(file.write_at(b"12345678", 0), Ok(8));
+        assert_eq!(file.write_at(b"9", 8), Err(Errno::EFBIG));
+        assert_eq!(file.set_len(9), Err(Errno::EFBIG));
+        assert_eq!(file.len(), 8);
+        // Without a limit, sizes which can't be allocated fail rather than abort.
+        let file = MemFile::new();
+        assert!(file.write_at(b"x", 1 << 60).is_err());
+        assert!(file.set_len(1 << 60).is_err());
+        assert_eq!(file.write_at(b"x", u64::MAX), Err(Errno::EFBIG));
+        assert!(file.is_empty());
+    }
+
+    #[test]
+    fn set_len() {
+        let file = MemFile::from_vec(b"hello".to_vec());
+        assert_eq!(SetLenFileLike::set_len(&file, 3), Ok(()));
+        assert_eq!(file.to_vec(), b"hel");
+        assert_eq!(SetLenFileLike::set_len(&Arc::new(file), 0), Ok(()));
+
+        let path = temp_path();
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        assert_eq!(SetLenFileLike::set_len(&file, 10), Ok(()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 10);
+        assert_eq!(SetLenFileLike::set_len(&file, 4), Ok(()));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn modal_file_like_modes() {
+        type Modal = ModalFileLike<MemFile, MemFile, MemFile>;
+        let read_only: Modal = ReadOnly(MemFile::from_vec(b"abc".to_vec()));
+        let mut buf = [0; 3];
+        assert_eq!(read_only.read_at(&mut buf, 0), Ok(3));
+        assert_eq!(read_only.write_at(b"x", 0), Err(Errno::EBADF));
+        assert_eq!(read_only.flush(), Err(Errno::EBADF));
+        assert_eq!(read_only.write_buffered(), Ok(()));
+        assert_eq!(read_only.set_len(0), Err(Errno::EINVAL));
+
+        let write_only: Modal = WriteOnly(MemFile::new());
+        assert_eq!(write_only.read_at(&mut buf, 0), Err(Errno::EBADF));
+        assert_eq!(write_only.write_at(b"x", 0), Ok(1));
+        assert_eq!(write_only.set_len(5), Ok(()));
+        assert_eq!(write_only.getattr().unwrap().size, 5);
+
+        let read_write: Modal = ReadWrite(MemFile::new());
+        assert_eq!(read_write.write_at(b"xyz", 0), Ok(3));
+        assert_eq!(read_write.read_at(&mut buf, 0), Ok(3));
+        assert_eq!(&buf, b"xyz");
+        assert_eq!(read_write.set_len(1), Ok(()));
+    }
+
+    #[test]
+    fn open_fs_dispatches_file_calls() {
+        let fs = OpenFs::new(OneFile::default());
+        let path = Path::new("/f");
+        let created = fs.create(req(), Path::new("/"), OsStr::new("f"), 0o644, AccessMode::ReadWrite.into())
+            .unwrap();
+        assert_eq!(created.flags, OpenReplyFlags::DIRECT_IO);
+        assert_eq!(fs.write(req(), path, &created.fl, 0, b"hello".to_vec(), 0), Ok(5));
+        fs.release(req(), path, created.fl, AccessMode::ReadWrite.into(), 0, true).unwrap();
+        assert_eq!(calls(&fs), ["create /f", "write_buffered", "write_buffered"]);
+
+        let (fl, flags) = fs.open(req(), path, AccessMode::ReadOnly.into()).unwrap();
+        assert_eq!(flags, OpenReplyFlags::KEEP_CACHE);
+        assert_eq!(fs.read(req(), path, &fl, 1, 10), Ok(b"ello".to_vec()));
+        fs.fsync(req(), path, &fl, true).unwrap();
+        fs.fsync(req(), path, &fl, false).unwrap();
+        fs.flush(req(), path, &fl, 0).unwrap();
+        assert_eq!(calls(&fs), ["open /f", "flush", "flush", "fsync_metadata", "write_buffered"]);
+    }
+
+    #[test]
+    fn open_fs_falls_back_to_meta() {
+        let fs = OpenFs::new(OneFile::default());
+        let path = Path::new("/f");
+        let (fl, _) = fs.open(req(), path, AccessMode::ReadWrite.into()).unwrap();
+        fs.write(req(), path, &fl, 0, b"hello".to_vec(), 0).unwrap();
+        // The handler has neither attributes nor a length to set, so these go to FilesystemFLMeta,
+        // with the handler.
+        fs.truncate(req(), path, Some(&fl), 2).unwrap();
+        assert_eq!(fs.getattr(req(), path, Some(&fl)).unwrap().1.size, 2);
+        fs.chmod(req(), path, Some(&fl), 0o600).unwrap();
+        assert_eq!(fs.getattr(req(), path, None).unwrap().1.perm, 0o600);
+        assert_eq!(calls(&fs), ["open /f", "truncate true", "getattr true", "chmod true", "getattr false"]);
+    }
+
+    #[test]
+    fn filling_adaptor_retries() {
+        let file = FillingAdaptor(Choppy::default());
+        assert_eq!(file.write_at(b"0123456789", 2), Ok(10));
+        assert_eq!(file.0.file.to_vec(), b"\0\00123456789");
+        let mut buf = [0xff; 8];
+        assert_eq!(file.read_at(&mut buf, 2), Ok(8));
+        assert_eq!(&buf, b"01234567");
+        // Short only at EOF.
+        assert_eq!(file.read_at(&mut buf, 7), Ok(5));
+        assert_eq!(&buf[..5], b"56789");
+        assert_eq!(file.read_at(&mut buf, 12), Ok(0));
+        assert_eq!(file.write_at(b"", 0), Ok(0));
+    }
+
+    #[test]
+    fn file_reads_full_buffers() {
+        let path = temp_path();
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        assert_eq!(WriteFileLike::write_at(&file, &data, 10), Ok(data.len()));
+        let mut buf = vec![0xff; 60_000];
+        assert_eq!(ReadFileLike::read_at(&file, &mut buf, 10), Ok(60_000));
+        assert_eq!(buf, &data[..60_000]);
+        assert_eq!(ReadFileLike::read_at(&file, &mut buf, 50_010), Ok(50_000));
+        assert_eq!(&buf[..50_000], &data[50_000..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_attrs_follow_the_handle() {
+        let path = temp_path();
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        WriteFileLike::write_at(&file, b"hello", 0).unwrap();
+        let renamed = temp_path();
+        fs::rename(&path, &renamed).unwrap();
+        AttrFileLike::chmod(&file, 0o600).unwrap();
+        assert_eq!(fs::metadata(&renamed).unwrap().permissions().mode() & 0o7777, 0o600);
+
+        fs::remove_file(&renamed).unwrap();
+        let mtime = Timespec::new(1_000_000_000, 5);
+        AttrFileLike::utimens(&file, None, Some(mtime)).unwrap();
+        let attr = AttrFileLike::getattr(&file).unwrap();
+        assert_eq!((attr.size, attr.perm, attr.nlink), (5, 0o600, 0));
+        assert_eq!(attr.mtime, mtime);
+    }
+}
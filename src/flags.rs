@@ -0,0 +1,257 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Typed flags for `open`, `create` and `opendir`: the flags passed in by the kernel, and the flags
+//! replied to it.
+
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+use libc;
+
+use super::{Errno, Result};
+
+/// The access mode a file is opened with, i.e. the `O_ACCMODE` part of the open flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessMode {
+    /// `O_RDONLY`.
+    ReadOnly,
+    /// `O_WRONLY`.
+    WriteOnly,
+    /// `O_RDWR`.
+    ReadWrite,
+}
+
+impl AccessMode {
+    /// Whether this mode allows reading.
+    pub fn readable(self) -> bool {
+        self != AccessMode::WriteOnly
+    }
+
+    /// Whether this mode allows writing.
+    pub fn writable(self) -> bool {
+        self != AccessMode::ReadOnly
+    }
+}
+
+/// Flags passed to `open`, `create` and `opendir`: the access mode, and other flags such as
+/// `O_APPEND` or `O_TRUNC`. Converts to and from the raw value, keeping any bits which have no
+/// constant here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// `O_RDONLY`; note that this is 0, so `contains` is always true for it - use `access_mode`.
+    pub const RDONLY: OpenFlags = OpenFlags(libc::O_RDONLY as u32);
+    /// `O_WRONLY`.
+    pub const WRONLY: OpenFlags = OpenFlags(libc::O_WRONLY as u32);
+    /// `O_RDWR`.
+    pub const RDWR: OpenFlags = OpenFlags(libc::O_RDWR as u32);
+    /// `O_ACCMODE`, the mask of the access mode bits.
+    pub const ACCMODE: OpenFlags = OpenFlags(libc::O_ACCMODE as u32);
+    /// `O_APPEND`.
+    pub const APPEND: OpenFlags = OpenFlags(libc::O_APPEND as u32);
+    /// `O_TRUNC`.
+    pub const TRUNC: OpenFlags = OpenFlags(libc::O_TRUNC as u32);
+    /// `O_CREAT`.
+    pub const CREAT: OpenFlags = OpenFlags(libc::O_CREAT as u32);
+    /// `O_EXCL`.
+    pub const EXCL: OpenFlags = OpenFlags(libc::O_EXCL as u32);
+    /// `O_NONBLOCK`.
+    pub const NONBLOCK: OpenFlags = OpenFlags(libc::O_NONBLOCK as u32);
+    /// `O_SYNC`.
+    pub const SYNC: OpenFlags = OpenFlags(libc::O_SYNC as u32);
+    /// `O_DSYNC`.
+    pub const DSYNC: OpenFlags = OpenFlags(libc::O_DSYNC as u32);
+    /// `O_NOFOLLOW`.
+    pub const NOFOLLOW: OpenFlags = OpenFlags(libc::O_NOFOLLOW as u32);
+    /// `O_DIRECTORY`.
+    pub const DIRECTORY: OpenFlags = OpenFlags(libc::O_DIRECTORY as u32);
+    /// `O_DIRECT`.
+    #[cfg(target_os = "linux")]
+    pub const DIRECT: OpenFlags = OpenFlags(libc::O_DIRECT as u32);
+    /// `O_NOATIME`.
+    #[cfg(target_os = "linux")]
+    pub const NOATIME: OpenFlags = OpenFlags(libc::O_NOATIME as u32);
+
+    /// Flags from a raw value.
+    pub fn from_bits(bits: u32) -> OpenFlags {
+        OpenFlags(bits)
+    }
+
+    /// The raw value of the flags.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether all the flags in `other` are set.
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The access mode of the flags; fails with `EINVAL` if it's not a valid one.
+    pub fn access_mode(self) -> Result<AccessMode> {
+        match self.0 as i32 & libc::O_ACCMODE {
+            libc::O_RDONLY => Ok(AccessMode::ReadOnly),
+            libc::O_WRONLY => Ok(AccessMode::WriteOnly),
+            libc::O_RDWR => Ok(AccessMode::ReadWrite),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// The same flags, with the access mode replaced.
+    pub fn with_access_mode(self, mode: AccessMode) -> OpenFlags {
+        OpenFlags(self.0 & !OpenFlags::ACCMODE.0) | mode.into()
+    }
+}
+
+impl From<AccessMode> for OpenFlags {
+    fn from(mode: AccessMode) -> OpenFlags {
+        match mode {
+            AccessMode::ReadOnly => OpenFlags::RDONLY,
+            AccessMode::WriteOnly => OpenFlags::WRONLY,
+            AccessMode::ReadWrite => OpenFlags::RDWR,
+        }
+    }
+}
+
+impl From<u32> for OpenFlags {
+    fn from(bits: u32) -> OpenFlags {
+        OpenFlags(bits)
+    }
+}
+
+impl From<OpenFlags> for u32 {
+    fn from(flags: OpenFlags) -> u32 {
+        flags.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for OpenFlags {
+    fn bitor_assign(&mut self, other: OpenFlags) {
+        self.0 |= other.0
+    }
+}
+
+impl BitAnd for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitand(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 & other.0)
+    }
+}
+
+/// Flags returned from `open`, `create` and `opendir`, telling the kernel how to treat the opened
+/// file (the `FOPEN_*` flags of FUSE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct OpenReplyFlags(u32);
+
+impl OpenReplyFlags {
+    /// `FOPEN_DIRECT_IO`: bypass the page cache for this file.
+    pub const DIRECT_IO: OpenReplyFlags = OpenReplyFlags(1 << 0);
+    /// `FOPEN_KEEP_CACHE`: don't invalidate the data cache on open.
+    pub const KEEP_CACHE: OpenReplyFlags = OpenReplyFlags(1 << 1);
+    /// `FOPEN_NONSEEKABLE`: the file is not seekable.
+    pub const NONSEEKABLE: OpenReplyFlags = OpenReplyFlags(1 << 2);
+
+    /// No flags.
+    pub fn empty() -> OpenReplyFlags {
+        OpenReplyFlags(0)
+    }
+
+    /// Flags from a raw value.
+    pub fn from_bits(bits: u32) -> OpenReplyFlags {
+        OpenReplyFlags(bits)
+    }
+
+    /// The raw value of the flags.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether all the flags in `other` are set.
+    pub fn contains(self, other: OpenReplyFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl From<u32> for OpenReplyFlags {
+    fn from(bits: u32) -> OpenReplyFlags {
+        OpenReplyFlags(bits)
+    }
+}
+
+impl From<OpenReplyFlags> for u32 {
+    fn from(flags: OpenReplyFlags) -> u32 {
+        flags.0
+    }
+}
+
+impl BitOr for OpenReplyFlags {
+    type Output = OpenReplyFlags;
+
+    fn bitor(self, other: OpenReplyFlags) -> OpenReplyFlags {
+        OpenReplyFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for OpenReplyFlags {
+    fn bitor_assign(&mut self, other: OpenReplyFlags) {
+        self.0 |= other.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_round_trip() {
+        let raw = (libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT) as u32 | 1 << 30;
+        let flags = OpenFlags::from(raw);
+        assert_eq!(u32::from(flags), raw);
+        assert_eq!(OpenFlags::from_bits(raw).bits(), raw);
+        assert!(flags.contains(OpenFlags::APPEND | OpenFlags::CREAT));
+        assert!(!flags.contains(OpenFlags::TRUNC));
+
+        let reply = OpenReplyFlags::from(u32::from(OpenReplyFlags::KEEP_CACHE) | 1 << 20);
+        assert_eq!(u32::from(reply), 1 << 1 | 1 << 20);
+    }
+
+    #[test]
+    fn access_mode() {
+        assert_eq!(OpenFlags::RDONLY.access_mode(), Ok(AccessMode::ReadOnly));
+        assert_eq!(OpenFlags::WRONLY.access_mode(), Ok(AccessMode::WriteOnly));
+        assert_eq!((OpenFlags::RDWR | OpenFlags::TRUNC).access_mode(), Ok(AccessMode::ReadWrite));
+        assert_eq!(OpenFlags::ACCMODE.access_mode(), Err(Errno::EINVAL));
+
+        let flags = (OpenFlags::RDWR | OpenFlags::APPEND).with_access_mode(AccessMode::ReadOnly);
+        assert_eq!(flags, OpenFlags::RDONLY | OpenFlags::APPEND);
+        assert!(AccessMode::ReadWrite.readable() && AccessMode::ReadWrite.writable());
+        assert!(!AccessMode::WriteOnly.readable() && !AccessMode::ReadOnly.writable());
+    }
+
+    #[test]
+    fn reply_flag_bits() {
+        assert_eq!(OpenReplyFlags::empty().bits(), 0);
+        assert_eq!(OpenReplyFlags::DIRECT_IO.bits(), 1);
+        assert_eq!(OpenReplyFlags::KEEP_CACHE.bits(), 2);
+        assert_eq!(OpenReplyFlags::NONSEEKABLE.bits(), 4);
+        let mut flags = OpenReplyFlags::DIRECT_IO;
+        flags |= OpenReplyFlags::NONSEEKABLE;
+        assert_eq!(flags.bits(), 5);
+        assert!(flags.contains(OpenReplyFlags::NONSEEKABLE));
+        assert!(!flags.contains(OpenReplyFlags::KEEP_CACHE));
+    }
+}
@@ -8,14 +8,17 @@
 //
 
 use fuse_mt::*;
-use libc;
 use time::Timespec;
   
 use std::ffi::OsStr;
+use std::fmt;
 use std::path::Path;
+use std::sync::Arc;
 
-use super::handler_table::HandlerTable;
-use super::Result;
+use super::flags::{OpenFlags, OpenReplyFlags};
+use super::handler_table::{Handler, HandlerTable};
+use super::locks::{FlockLocks, RecordLocks};
+use super::{Errno, Result};
 
 /// The return value for `create`: contains info on the newly-created file, as well as a FileLike
 /// object to handle the opened file.
@@ -27,20 +30,45 @@ pub struct CreatedEntryObj<T> {
     pub attr: FileAttr,
     /// The handler object to be passed to calls on this file descriptor.
     pub fl: T,
-    /// Creation flags, see fuse docs.
-    pub flags: u32,
+    /// Flags for the kernel on how to treat the opened file, see fuse docs.
+    pub flags: OpenReplyFlags,
 }
 
+/// Result of calls on FilesystemFL returning nothing.
+pub type ResultEmpty = Result<()>;
+/// Result of calls on FilesystemFL returning a new entry: its TTL and attributes.
+pub type ResultEntry = Result<(Timespec, FileAttr)>;
+/// Result of a `getattr` call on FilesystemFL: the TTL and attributes of the entry.
+pub type ResultGetattr = Result<(Timespec, FileAttr)>;
+/// Result of `read` and `readlink` calls on FilesystemFL.
+pub type ResultData = Result<Vec<u8>>;
+/// Result of a `write` call on FilesystemFL: the number of bytes written.
+pub type ResultWrite = Result<u32>;
+/// Result of a `readdir` call on FilesystemFL.
+pub type ResultReaddir = Result<Vec<DirectoryEntry>>;
+/// Result of a `statfs` call on FilesystemFL.
+pub type ResultStatfs = Result<Statfs>;
+/// Result of the `getxattr` and `listxattr` calls on FilesystemFL.
+pub type ResultXattr = Result<Xattr>;
 /// Result of an `open` call on FilesystemFL.
-pub type ResultOpenObj<T> = Result<(T, u32)>;
+pub type ResultOpenObj<T> = Result<(T, OpenReplyFlags)>;
 /// Result of an `create` call on FilesystemFL.
 pub type ResultCreateObj<T> = Result<CreatedEntryObj<T>>;
 
+pub fn clone_req(req: &RequestInfo) -> RequestInfo {
+    RequestInfo { unique: req.unique, uid: req.uid, gid: req.gid, pid: req.pid }
+}
+
 pub fn map_res_open<T, S, F>(this: ResultOpenObj<T>, f: F) -> ResultOpenObj<S>
     where F: FnOnce(T) -> S {
     this.map(|x| (f(x.0), x.1))
 }
 
+pub fn map_res_open2<T, F>(this: ResultOpenObj<T>, f: F) -> ResultOpen
+    where F: FnOnce(T) -> u64 {
+    this.map(|x| (f(x.0), x.1.bits())).map_err(Into::into)
+}
+
 pub fn map_res_create<T, S, F>(this: ResultCreateObj<T>, f: F) -> ResultCreateObj<S>
     where F: FnOnce(T) -> S {
     match this {
@@ -74,10 +102,10 @@ pub fn map_res_create2<T, F>(this: ResultCreateObj<T>, f: F) -> ResultCreate
                    ttl,
                    attr,
                    fh: f(fl),
-                   flags,
+                   flags: flags.bits(),
                })
         }
-        Err(e) => Err(e),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -91,7 +119,7 @@ pub trait FilesystemFL {
 
     /// Called on mount, before any other function.
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        Err(0)
+        Err(Errno::new(0))
     }
 
     /// Called on filesystem unmount.
@@ -104,7 +132,7 @@ pub trait FilesystemFL {
     /// * `parent`: path to the parent of the entry being looked up
     /// * `name`: the name of the entry (under `parent`) being looked up.
     fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Get the attributes of a filesystem entry.
@@ -115,7 +143,7 @@ pub trait FilesystemFL {
                _path: &Path,
                _fl: Option<&Self::FileLike>)
                -> ResultGetattr {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     // The following operations in the FUSE C API are all one kernel call: setattr
@@ -131,7 +159,7 @@ pub trait FilesystemFL {
              _fl: Option<&Self::FileLike>,
              _mode: u32)
              -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Change the owner UID and/or group GID of a filesystem entry.
@@ -146,7 +174,7 @@ pub trait FilesystemFL {
              _uid: Option<u32>,
              _gid: Option<u32>)
              -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Set the length of a file.
@@ -159,7 +187,7 @@ pub trait FilesystemFL {
                 _fl: Option<&Self::FileLike>,
                 _size: u64)
                 -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Set timestamps of a filesystem entry.
@@ -174,11 +202,11 @@ pub trait FilesystemFL {
                _atime: Option<Timespec>,
                _mtime: Option<Timespec>)
                -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Set timestamps of a filesystem entry (with extra options only used on MacOS).
-    #[allow(unknown_lints, too_many_arguments)]
+    #[allow(unknown_lints, clippy::too_many_arguments)]
     fn utimens_macos(&self,
                      _req: RequestInfo,
                      _path: &Path,
@@ -188,14 +216,14 @@ pub trait FilesystemFL {
                      _bkuptime: Option<Timespec>,
                      _flags: Option<u32>)
                      -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     // END OF SETATTR FUNCTIONS
 
     /// Read a symbolic link.
     fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a special file.
@@ -212,7 +240,7 @@ pub trait FilesystemFL {
              _mode: u32,
              _rdev: u32)
              -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a directory.
@@ -221,7 +249,7 @@ pub trait FilesystemFL {
     /// * `name`: name of the directory.
     /// * `mode`: permissions for the new directory.
     fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Remove a file.
@@ -229,7 +257,7 @@ pub trait FilesystemFL {
     /// * `parent`: path to the directory containing the file to delete.
     /// * `name`: name of the file to delete.
     fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Remove a directory.
@@ -237,7 +265,7 @@ pub trait FilesystemFL {
     /// * `parent`: path to the directory containing the directory to delete.
     /// * `name`: name of the directory to delete.
     fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a symbolic link.
@@ -251,7 +279,7 @@ pub trait FilesystemFL {
                _name: &OsStr,
                _target: &Path)
                -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Rename a filesystem entry.
@@ -268,7 +296,7 @@ pub trait FilesystemFL {
               _newparent: &Path,
               _newname: &OsStr)
               -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create a hard link.
@@ -282,7 +310,7 @@ pub trait FilesystemFL {
             _newparent: &Path,
             _newname: &OsStr)
             -> ResultEntry {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Open a file.
@@ -290,11 +318,11 @@ pub trait FilesystemFL {
     /// * `path`: path to the file.
     /// * `flags`: one of `O_RDONLY`, `O_WRONLY`, or `O_RDWR`, plus maybe additional flags.
     ///
-    /// Return a tuple of (file handle, flags). The file handle will be passed to any subsequent
+    /// Return a tuple of (FileLike object, flags). The file handle will be passed to any subsequent
     /// calls that operate on the file, and can be any value you choose, though it should allow
     /// your filesystem to identify the file opened even without any path info.
-    fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpenObj<Self::FileLike> {
-        Err(libc::ENOSYS)
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        Err(Errno::ENOSYS)
     }
 
     /// Read from a file.
@@ -316,7 +344,7 @@ pub trait FilesystemFL {
             _offset: u64,
             _size: u32)
             -> ResultData {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Write to a file.
@@ -336,7 +364,7 @@ pub trait FilesystemFL {
              _data: Vec<u8>,
              _flags: u32)
              -> ResultWrite {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Called each time a program calls `close` on an open file.
@@ -348,20 +376,47 @@ pub trait FilesystemFL {
     ///
     /// NOTE: the name of the method is misleading, since (unlike fsync) the filesystem is not
     /// forced to flush pending writes. One reason to flush data, is if the filesystem wants to
-    /// return write errors. (Currently unsupported) If the filesystem supports file locking
-    /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
+    /// return write errors. File locks are not passed through by fuse_mt yet (see the `locks`
+    /// module), but FuseFL releases the lock owner's record locks before this is called, if
+    /// set up to with `FuseFL::set_record_locks`.
     ///
     /// * `path`: path to the file.
     /// * `fl`: FileLike object returned from the `open` call.
-    /// * `lock_owner`: if the filesystem supports locking (`setlk`, `getlk`), remove all locks
-    ///   belonging to this lock owner.
+    /// * `lock_owner`: the lock owner closing the file.
     fn flush(&self,
              _req: RequestInfo,
              _path: &Path,
              _fl: &Self::FileLike,
              _lock_owner: u64)
              -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
+    }
+
+    /// Called when an open file is closed for the last time, i.e. the file handler is released.
+    ///
+    /// This receives the FileLike object itself, which is dropped afterwards, so this is the
+    /// place to commit or upload the file, and to report errors in doing so. By default, this
+    /// calls `flush` if `flush` is set, and otherwise does nothing.
+    ///
+    /// * `path`: path to the file.
+    /// * `fl`: FileLike object returned from the `open` call.
+    /// * `flags`: the flags the file was opened with.
+    /// * `lock_owner`: the lock owner closing the file.
+    /// * `flush`: whether pending data should be flushed.
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        if _flush {
+            self.flush(_req, _path, &_fl, _lock_owner)
+        } else {
+            Ok(())
+        }
     }
 
     /// Write out any pending changes of a file.
@@ -377,7 +432,7 @@ pub trait FilesystemFL {
              _fl: &Self::FileLike,
              _datasync: bool)
              -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Open a directory.
@@ -393,9 +448,9 @@ pub trait FilesystemFL {
     fn opendir(&self,
                _req: RequestInfo,
                _path: &Path,
-               _flags: u32)
+               _flags: OpenFlags)
                -> ResultOpenObj<Self::DirLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Get the entries of a directory.
@@ -405,7 +460,24 @@ pub trait FilesystemFL {
     ///
     /// Return all the entries of the directory.
     fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
+    }
+
+    /// Called when an open directory is closed, i.e. the file handler is released.
+    ///
+    /// This receives the DirLike object itself, which is dropped afterwards. By default, this does
+    /// nothing.
+    ///
+    /// * `path`: path to the directory.
+    /// * `dl`: DirLike object returned from the `opendir` call.
+    /// * `flags`: the flags the directory was opened with.
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        Ok(())
     }
 
     /// Write out any pending changes to a directory.
@@ -417,7 +489,7 @@ pub trait FilesystemFL {
                 _dl: &Self::DirLike,
                 _datasync: bool)
                 -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Get filesystem statistics.
@@ -426,7 +498,7 @@ pub trait FilesystemFL {
     ///
     /// See the `Statfs` struct for more details.
     fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Set a file extended attribute.
@@ -444,7 +516,7 @@ pub trait FilesystemFL {
                 _flags: u32,
                 _position: u32)
                 -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Get a file extended attribute.
@@ -456,7 +528,7 @@ pub trait FilesystemFL {
     /// If `size` is 0, return `Xattr::Size(n)` where `n` is the size of the attribute data.
     /// Otherwise, return `Xattr::Data(data)` with the requested data.
     fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// List extended attributes for a file.
@@ -469,7 +541,7 @@ pub trait FilesystemFL {
     /// Otherwise, return `Xattr::Data(data)` where `data` is all the null-terminated attribute
     /// names.
     fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Remove an extended attribute for a file.
@@ -477,7 +549,7 @@ pub trait FilesystemFL {
     /// * `path`: path to the file.
     /// * `name`: name of the attribute to remove.
     fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Check for access to a file.
@@ -488,7 +560,7 @@ pub trait FilesystemFL {
     /// Return `Ok(())` if all requested permissions are allowed, otherwise return `Err(EACCES)`
     /// or other error code as appropriate (e.g. `ENOENT` if the file doesn't exist).
     fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
     /// Create and open a new file.
@@ -505,25 +577,52 @@ pub trait FilesystemFL {
               _parent: &Path,
               _name: &OsStr,
               _mode: u32,
-              _flags: u32)
+              _flags: OpenFlags)
               -> ResultCreateObj<Self::FileLike> {
-        Err(libc::ENOSYS)
+        Err(Errno::ENOSYS)
     }
 
-    // getlk
+    // bmap
+}
 
-    // setlk
 
-    // bmap
+type BadHandlerFn = dyn Fn(&str, &Path, u64) + Send + Sync;
+
+/// Hook called by FuseFL whenever a call refers to a file handler it doesn't know of, with the
+/// name of the call, the path and the file handler.
+struct BadHandlerHook(Box<BadHandlerFn>);
+
+impl fmt::Debug for BadHandlerHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BadHandlerHook")
+    }
 }
 
+type FileIdFn = dyn Fn(&Path) -> Option<u64> + Send + Sync;
+
+/// Record lock table of a filesystem serving locks, with the function giving the id under which
+/// the locks of the file at a path are kept.
+struct LockedFiles {
+    locks: Arc<RecordLocks>,
+    file_id: Box<FileIdFn>,
+}
+
+impl fmt::Debug for LockedFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LockedFiles").field("locks", &self.locks).finish()
+    }
+}
 
 /// Adaptor struct for using a filesystem - holds a FilesystemFL and implements FilesystemMT.
+/// Calls with an unknown file handler (e.g. a stale one) fail with `EBADF`.
 #[derive(Debug)]
 pub struct FuseFL<T> where T: FilesystemFL {
     inner: T,
     files: HandlerTable<T::FileLike>,
     dirs: HandlerTable<T::DirLike>,
+    bad_handler_hook: Option<BadHandlerHook>,
+    record_locks: Option<LockedFiles>,
+    flock_locks: Option<Arc<FlockLocks>>,
 }
 
 
@@ -534,40 +633,107 @@ impl<T> FuseFL<T> where T: FilesystemFL {
             inner: target_fs,
             files: HandlerTable::new(),
             dirs: HandlerTable::new(),
+            bad_handler_hook: None,
+            record_locks: None,
+            flock_locks: None,
         }
     }
+
+    /// The number of open file handlers, i.e. files opened (or created) and not yet released.
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The number of open directory handlers.
+    pub fn open_dirs(&self) -> usize {
+        self.dirs.len()
+    }
+
+    /// Set a hook to be called whenever a call refers to an unknown file handler, e.g. for
+    /// logging or counting these. The hook gets the name of the call, the path and the file
+    /// handler; the call itself fails with `EBADF` regardless.
+    pub fn set_bad_handler_hook<F>(&mut self, hook: F)
+        where F: Fn(&str, &Path, u64) + Send + Sync + 'static {
+        self.bad_handler_hook = Some(BadHandlerHook(Box::new(hook)));
+    }
+
+    /// Set the record lock table of a filesystem serving locks, so that a lock owner's locks on a
+    /// file are released when it closes it, i.e. on `flush` and `release`, as POSIX requires.
+    /// `file_id` gives the id under which the locks of the file at a path are kept (e.g. its inode
+    /// number), or `None` if there's no such file.
+    pub fn set_record_locks<F>(&mut self, locks: Arc<RecordLocks>, file_id: F)
+        where F: Fn(&Path) -> Option<u64> + Send + Sync + 'static {
+        self.record_locks = Some(LockedFiles { locks, file_id: Box::new(file_id) });
+    }
+
+    /// Set the `flock` lock table of a filesystem serving locks, keyed by FuseFL's file handlers,
+    /// so that the lock held through a file handler is released when it is released.
+    pub fn set_flock_locks(&mut self, locks: Arc<FlockLocks>) {
+        self.flock_locks = Some(locks);
+    }
+
+    fn release_record_locks(&self, path: &Path, lock_owner: u64) {
+        if let Some(ref locked) = self.record_locks {
+            if let Some(file) = (locked.file_id)(path) {
+                locked.locks.release_owner(file, lock_owner);
+            }
+        }
+    }
+
+    fn bad_handler(&self, op: &str, path: &Path, fh: u64) -> Errno {
+        if let Some(BadHandlerHook(ref hook)) = self.bad_handler_hook {
+            hook(op, path, fh);
+        }
+        Errno::EBADF
+    }
+
+    fn get_file(&self,
+                op: &str,
+                path: &Path,
+                fh: u64)
+                -> Result<Handler<'_, T::FileLike>> {
+        self.files.get(fh).ok_or_else(|| self.bad_handler(op, path, fh))
+    }
+
+    fn get_dir(&self,
+               op: &str,
+               path: &Path,
+               fh: u64)
+               -> Result<Handler<'_, T::DirLike>> {
+        self.dirs.get(fh).ok_or_else(|| self.bad_handler(op, path, fh))
+    }
 }
 
 
 impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
-    fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        self.inner.init(_req)
+    fn init(&self, _req: RequestInfo) -> fuse_mt::ResultEmpty {
+        self.inner.init(_req).map_err(Into::into)
     }
 
     fn destroy(&self, _req: RequestInfo) {
         self.inner.destroy(_req)
     }
 
-    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
-        self.inner.lookup(_req, _parent, _name)
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> fuse_mt::ResultEntry {
+        self.inner.lookup(_req, _parent, _name).map_err(Into::into)
     }
 
-    fn getattr(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>) -> ResultGetattr {
+    fn getattr(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>) -> fuse_mt::ResultGetattr {
         if let Some(_fh) = _fh {
-            self.inner.getattr(_req, _path, Some(self.files.get(_fh).unwrap()))
+            self.inner.getattr(_req, _path, Some(&*self.get_file("getattr", _path, _fh)?)).map_err(Into::into)
         } else {
-            self.inner.getattr(_req, _path, None)
+            self.inner.getattr(_req, _path, None).map_err(Into::into)
         }
     }
 
     // The following operations in the FUSE C API are all one kernel call: setattr
     // We split them out to match the C API's behavior.
 
-    fn chmod(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _mode: u32) -> ResultEmpty {
+    fn chmod(&self, _req: RequestInfo, _path: &Path, _fh: Option<u64>, _mode: u32) -> fuse_mt::ResultEmpty {
         if let Some(_fh) = _fh {
-            self.inner.chmod(_req, _path, Some(self.files.get(_fh).unwrap()), _mode)
+            self.inner.chmod(_req, _path, Some(&*self.get_file("chmod", _path, _fh)?), _mode).map_err(Into::into)
         } else {
-            self.inner.chmod(_req, _path, None, _mode)
+            self.inner.chmod(_req, _path, None, _mode).map_err(Into::into)
         }
     }
 
@@ -577,11 +743,11 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
              _fh: Option<u64>,
              _uid: Option<u32>,
              _gid: Option<u32>)
-             -> ResultEmpty {
+             -> fuse_mt::ResultEmpty {
         if let Some(_fh) = _fh {
-            self.inner.chown(_req, _path, Some(self.files.get(_fh).unwrap()), _uid, _gid)
+            self.inner.chown(_req, _path, Some(&*self.get_file("chown", _path, _fh)?), _uid, _gid).map_err(Into::into)
         } else {
-            self.inner.chown(_req, _path, None, _uid, _gid)
+            self.inner.chown(_req, _path, None, _uid, _gid).map_err(Into::into)
         }
     }
 
@@ -590,11 +756,11 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                 _path: &Path,
                 _fh: Option<u64>,
                 _size: u64)
-                -> ResultEmpty {
+                -> fuse_mt::ResultEmpty {
         if let Some(_fh) = _fh {
-            self.inner.truncate(_req, _path, Some(self.files.get(_fh).unwrap()), _size)
+            self.inner.truncate(_req, _path, Some(&*self.get_file("truncate", _path, _fh)?), _size).map_err(Into::into)
         } else {
-            self.inner.truncate(_req, _path, None, _size)
+            self.inner.truncate(_req, _path, None, _size).map_err(Into::into)
         }
     }
 
@@ -604,15 +770,15 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                _fh: Option<u64>,
                _atime: Option<Timespec>,
                _mtime: Option<Timespec>)
-               -> ResultEmpty {
+               -> fuse_mt::ResultEmpty {
         if let Some(_fh) = _fh {
-            self.inner.utimens(_req, _path, Some(self.files.get(_fh).unwrap()), _atime, _mtime)
+            self.inner.utimens(_req, _path, Some(&*self.get_file("utimens", _path, _fh)?), _atime, _mtime).map_err(Into::into)
         } else {
-            self.inner.utimens(_req, _path, None, _atime, _mtime)
+            self.inner.utimens(_req, _path, None, _atime, _mtime).map_err(Into::into)
         }
     }
 
-    #[allow(unknown_lints, too_many_arguments)]
+    #[allow(unknown_lints, clippy::too_many_arguments)]
     fn utimens_macos(&self,
                      _req: RequestInfo,
                      _path: &Path,
@@ -621,18 +787,18 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                      _chgtime: Option<Timespec>,
                      _bkuptime: Option<Timespec>,
                      _flags: Option<u32>)
-                     -> ResultEmpty {
+                     -> fuse_mt::ResultEmpty {
         if let Some(_fh) = _fh {
-            self.inner.utimens_macos(_req, _path, Some(self.files.get(_fh).unwrap()), _crtime, _chgtime, _bkuptime, _flags)
+            self.inner.utimens_macos(_req, _path, Some(&*self.get_file("utimens_macos", _path, _fh)?), _crtime, _chgtime, _bkuptime, _flags).map_err(Into::into)
         } else {
-            self.inner.utimens_macos(_req, _path, None, _crtime, _chgtime, _bkuptime, _flags)
+            self.inner.utimens_macos(_req, _path, None, _crtime, _chgtime, _bkuptime, _flags).map_err(Into::into)
         }
     }
 
     // END OF SETATTR FUNCTIONS
 
-    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
-        self.inner.readlink(_req, _path)
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> fuse_mt::ResultData {
+        self.inner.readlink(_req, _path).map_err(Into::into)
     }
 
     fn mknod(&self,
@@ -641,20 +807,20 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
              _name: &OsStr,
              _mode: u32,
              _rdev: u32)
-             -> ResultEntry {
-        self.inner.mknod(_req, _parent, _name, _mode, _rdev)
+             -> fuse_mt::ResultEntry {
+        self.inner.mknod(_req, _parent, _name, _mode, _rdev).map_err(Into::into)
     }
 
-    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
-        self.inner.mkdir(_req, _parent, _name, _mode)
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> fuse_mt::ResultEntry {
+        self.inner.mkdir(_req, _parent, _name, _mode).map_err(Into::into)
     }
 
-    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        self.inner.unlink(_req, _parent, _name)
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> fuse_mt::ResultEmpty {
+        self.inner.unlink(_req, _parent, _name).map_err(Into::into)
     }
 
-    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
-        self.inner.rmdir(_req, _parent, _name)
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> fuse_mt::ResultEmpty {
+        self.inner.rmdir(_req, _parent, _name).map_err(Into::into)
     }
 
     fn symlink(&self,
@@ -662,8 +828,8 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                _parent: &Path,
                _name: &OsStr,
                _target: &Path)
-               -> ResultEntry {
-        self.inner.symlink(_req, _parent, _name, _target)
+               -> fuse_mt::ResultEntry {
+        self.inner.symlink(_req, _parent, _name, _target).map_err(Into::into)
     }
 
     fn rename(&self,
@@ -672,16 +838,16 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
               _name: &OsStr,
               _newparent: &Path,
               _newname: &OsStr)
-              -> ResultEmpty {
-        self.inner.rename(_req, _parent, _name, _newparent, _newname)
+              -> fuse_mt::ResultEmpty {
+        self.inner.rename(_req, _parent, _name, _newparent, _newname).map_err(Into::into)
     }
 
-    fn link(&self, _req: RequestInfo, _path: &Path, _newparent: &Path, _newname: &OsStr) -> ResultEntry {
-        self.inner.link(_req, _path, _newparent, _newname)
+    fn link(&self, _req: RequestInfo, _path: &Path, _newparent: &Path, _newname: &OsStr) -> fuse_mt::ResultEntry {
+        self.inner.link(_req, _path, _newparent, _newname).map_err(Into::into)
     }
 
     fn open(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen {
-        map_res_open(self.inner.open(_req, _path, _flags), |fl| self.files.insert(fl))
+        map_res_open2(self.inner.open(_req, _path, _flags.into()), |fl| self.files.insert(fl))
     }
 
     fn read(&self,
@@ -690,8 +856,8 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
             _fh: u64,
             _offset: u64,
             _size: u32)
-            -> ResultData {
-        self.inner.read(_req, _path, self.files.get(_fh).unwrap(), _offset, _size)
+            -> fuse_mt::ResultData {
+        self.inner.read(_req, _path, &*self.get_file("read", _path, _fh)?, _offset, _size).map_err(Into::into)
     }
 
     fn write(&self,
@@ -701,12 +867,14 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
              _offset: u64,
              _data: Vec<u8>,
              _flags: u32)
-             -> ResultWrite {
-        self.inner.write(_req, _path, self.files.get(_fh).unwrap(), _offset, _data, _flags)
+             -> fuse_mt::ResultWrite {
+        self.inner.write(_req, _path, &*self.get_file("write", _path, _fh)?, _offset, _data, _flags).map_err(Into::into)
     }
 
-    fn flush(&self, _req: RequestInfo, _path: &Path, _fh: u64, _lock_owner: u64) -> ResultEmpty {
-        self.inner.flush(_req, _path, self.files.get(_fh).unwrap(), _lock_owner)
+    fn flush(&self, _req: RequestInfo, _path: &Path, _fh: u64, _lock_owner: u64) -> fuse_mt::ResultEmpty {
+        let fl = self.get_file("flush", _path, _fh)?;
+        self.release_record_locks(_path, _lock_owner);
+        self.inner.flush(_req, _path, &*fl, _lock_owner).map_err(Into::into)
     }
 
     fn release(&self,
@@ -716,39 +884,43 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                _flags: u32,
                _lock_owner: u64,
                _flush: bool)
-               -> ResultEmpty {
-        let fl = self.files.remove(_fh).unwrap();
-        if _flush {
-            self.inner.flush(_req, _path, &fl, _lock_owner)
-        } else {
-            // TODO: handle unlocking anyway.
-            Ok(())
+               -> fuse_mt::ResultEmpty {
+        let fl = match self.files.remove(_fh) {
+            Some(fl) => fl,
+            None => return Err(self.bad_handler("release", _path, _fh).into()),
+        };
+        self.release_record_locks(_path, _lock_owner);
+        if let Some(ref locks) = self.flock_locks {
+            locks.release_handler(_fh);
         }
+        self.inner.release(_req, _path, fl, _flags.into(), _lock_owner, _flush).map_err(Into::into)
     }
 
-    fn fsync(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> ResultEmpty {
-        self.inner.fsync(_req, _path, self.files.get(_fh).unwrap(), _datasync)
+    fn fsync(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> fuse_mt::ResultEmpty {
+        self.inner.fsync(_req, _path, &*self.get_file("fsync", _path, _fh)?, _datasync).map_err(Into::into)
     }
 
     fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: u32) -> ResultOpen {
-        map_res_open(self.inner.opendir(_req, _path, _flags), |dl| self.dirs.insert(dl))
+        map_res_open2(self.inner.opendir(_req, _path, _flags.into()), |dl| self.dirs.insert(dl))
     }
 
-    fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: u64) -> ResultReaddir {
-        self.inner.readdir(_req, _path, self.dirs.get(_fh).unwrap())
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _fh: u64) -> fuse_mt::ResultReaddir {
+        self.inner.readdir(_req, _path, &*self.get_dir("readdir", _path, _fh)?).map_err(Into::into)
     }
 
-    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32) -> ResultEmpty {
-        self.dirs.remove(_fh).unwrap();
-        Ok(())
+    fn releasedir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _flags: u32) -> fuse_mt::ResultEmpty {
+        match self.dirs.remove(_fh) {
+            Some(dl) => self.inner.releasedir(_req, _path, dl, _flags.into()).map_err(Into::into),
+            None => Err(self.bad_handler("releasedir", _path, _fh).into()),
+        }
     }
 
-    fn fsyncdir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> ResultEmpty {
-        self.inner.fsyncdir(_req, _path, self.dirs.get(_fh).unwrap(), _datasync)
+    fn fsyncdir(&self, _req: RequestInfo, _path: &Path, _fh: u64, _datasync: bool) -> fuse_mt::ResultEmpty {
+        self.inner.fsyncdir(_req, _path, &*self.get_dir("fsyncdir", _path, _fh)?, _datasync).map_err(Into::into)
     }
 
-    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
-        self.inner.statfs(_req, _path)
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> fuse_mt::ResultStatfs {
+        self.inner.statfs(_req, _path).map_err(Into::into)
     }
 
     fn setxattr(&self,
@@ -758,24 +930,24 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
                 _value: &[u8],
                 _flags: u32,
                 _position: u32)
-                -> ResultEmpty {
-        self.inner.setxattr(_req, _path, _name, _value, _flags, _position)
+                -> fuse_mt::ResultEmpty {
+        self.inner.setxattr(_req, _path, _name, _value, _flags, _position).map_err(Into::into)
     }
 
-    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
-        self.inner.getxattr(_req, _path, _name, _size)
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> fuse_mt::ResultXattr {
+        self.inner.getxattr(_req, _path, _name, _size).map_err(Into::into)
     }
 
-    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
-        self.inner.listxattr(_req, _path, _size)
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> fuse_mt::ResultXattr {
+        self.inner.listxattr(_req, _path, _size).map_err(Into::into)
     }
 
-    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
-        self.inner.removexattr(_req, _path, _name)
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> fuse_mt::ResultEmpty {
+        self.inner.removexattr(_req, _path, _name).map_err(Into::into)
     }
 
-    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
-        self.inner.access(_req, _path, _mask)
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> fuse_mt::ResultEmpty {
+        self.inner.access(_req, _path, _mask).map_err(Into::into)
     }
 
     fn create(&self,
@@ -785,7 +957,7 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
               _mode: u32,
               _flags: u32)
               -> ResultCreate {
-        map_res_create2(self.inner.create(_req, _parent, _name, _mode, _flags), |fl| self.files.insert(fl))
+        map_res_create2(self.inner.create(_req, _parent, _name, _mode, _flags.into()), |fl| self.files.insert(fl))
     }
 
     // getlk
@@ -794,3 +966,190 @@ impl<T: FilesystemFL + Sync + Send + 'static> FilesystemMT for FuseFL<T> {
 
     // bmap
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use libc;
+    use super::super::locks::RecordLock;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    /// A filesystem opening every file and directory as the number 7, which records the calls it
+    /// gets on them.
+    #[derive(Debug, Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl FilesystemFL for Recorder {
+        type FileLike = u64;
+        type DirLike = u64;
+
+        fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<u64> {
+            Ok((7, OpenReplyFlags::empty()))
+        }
+
+        fn flush(&self, _req: RequestInfo, _path: &Path, _fl: &u64, _lock_owner: u64) -> ResultEmpty {
+            self.record(format!("flush {} {}", _fl, _lock_owner));
+            Ok(())
+        }
+
+        fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<u64> {
+            Ok((7, OpenReplyFlags::empty()))
+        }
+    }
+
+    fn calls(fs: &FuseFL<Recorder>) -> Vec<String> {
+        fs.inner.calls.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn releases_record_locks_on_close() {
+        let locks = Arc::new(RecordLocks::new());
+        let mut fs = FuseFL::new(Recorder::default());
+        fs.set_record_locks(locks.clone(), |path| if path == Path::new("/f") { Some(1) } else { None });
+        let first = RecordLock { start: 0, end: 9, typ: libc::F_WRLCK as u32, pid: 1 };
+        let second = RecordLock { start: 10, end: 19, ..first };
+        let unlocked = |lock| locks.getlk(1, 30, lock).unwrap().typ == libc::F_UNLCK as u32;
+
+        let fh = fs.open(req(), path("/f"), libc::O_RDWR as u32).unwrap().0;
+        locks.setlk(1, 10, first, false).unwrap();
+        locks.setlk(1, 20, second, false).unwrap();
+        fs.flush(req(), path("/f"), fh, 10).unwrap();
+        assert!(unlocked(first));
+        assert!(!unlocked(second));
+        fs.release(req(), path("/f"), fh, libc::O_RDWR as u32, 20, false).unwrap();
+        assert!(unlocked(second));
+        assert_eq!(calls(&fs), vec!["flush 7 10"]);
+
+        // Files without an id, and unknown handlers, leave locks alone.
+        locks.setlk(1, 10, first, false).unwrap();
+        let fh = fs.open(req(), path("/g"), libc::O_RDWR as u32).unwrap().0;
+        fs.flush(req(), path("/g"), fh, 10).unwrap();
+        assert!(fs.flush(req(), path("/f"), fh + 1, 10).is_err());
+        assert!(!unlocked(first));
+    }
+
+    #[test]
+    fn releases_flock_locks_on_release() {
+        let locks = Arc::new(FlockLocks::new());
+        let mut fs = FuseFL::new(Recorder::default());
+        fs.set_flock_locks(locks.clone());
+        let fh = fs.open(req(), path("/f"), libc::O_RDWR as u32).unwrap().0;
+        locks.flock(1, fh, libc::LOCK_EX as u32).unwrap();
+        assert_eq!(locks.flock(1, fh + 1, (libc::LOCK_SH | libc::LOCK_NB) as u32), Err(Errno::EWOULDBLOCK));
+
+        // Flushing keeps the lock, while releasing drops it and wakes up the waiters.
+        fs.flush(req(), path("/f"), fh, 10).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || tx.send(locks.flock(1, fh + 1, libc::LOCK_SH as u32)).unwrap())
+        };
+        fs.release(req(), path("/f"), fh, libc::O_RDWR as u32, 10, false).unwrap();
+        assert_eq!(rx.recv().unwrap(), Ok(()));
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_handlers() {
+        let bad = Arc::new(Mutex::new(Vec::new()));
+        let mut fs = FuseFL::new(Recorder::default());
+        {
+            let bad = bad.clone();
+            fs.set_bad_handler_hook(move |op, path, fh| {
+                bad.lock().unwrap().push(format!("{} {} {}", op, path.display(), fh))
+            });
+        }
+        let fh = fs.open(req(), path("/f"), libc::O_RDONLY as u32).unwrap().0;
+        assert_eq!(fs.read(req(), path("/f"), fh + 1, 0, 10).err(), Some(libc::EBADF));
+        fs.release(req(), path("/f"), fh, libc::O_RDONLY as u32, 0, false).unwrap();
+        assert_eq!(fs.release(req(), path("/f"), fh, libc::O_RDONLY as u32, 0, false), Err(libc::EBADF));
+        assert_eq!(fs.read(req(), path("/f"), fh, 0, 10).err(), Some(libc::EBADF));
+        assert_eq!(*bad.lock().unwrap(),
+                   vec![format!("read /f {}", fh + 1),
+                        format!("release /f {}", fh),
+                        format!("read /f {}", fh)]);
+        assert_eq!(fs.open_files(), 0);
+    }
+
+    /// A filesystem opening files and directories as their path, which keeps them on release.
+    #[derive(Debug, Default)]
+    struct Keeper {
+        released: Mutex<Vec<(String, OpenFlags, Option<u64>)>>,
+    }
+
+    impl FilesystemFL for Keeper {
+        type FileLike = String;
+        type DirLike = String;
+
+        fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<String> {
+            Ok((_path.display().to_string(), OpenReplyFlags::empty()))
+        }
+
+        fn release(&self,
+                   _req: RequestInfo,
+                   _path: &Path,
+                   _fl: String,
+                   _flags: OpenFlags,
+                   _lock_owner: u64,
+                   _flush: bool)
+                   -> ResultEmpty {
+            self.released.lock().unwrap().push((_fl, _flags, Some(_lock_owner)));
+            Ok(())
+        }
+
+        fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<String> {
+            Ok((_path.display().to_string(), OpenReplyFlags::empty()))
+        }
+
+        fn releasedir(&self, _req: RequestInfo, _path: &Path, _dl: String, _flags: OpenFlags) -> ResultEmpty {
+            self.released.lock().unwrap().push((_dl, _flags, None));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hands_over_handlers_on_release() {
+        let fs = FuseFL::new(Keeper::default());
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let fh = fs.open(req(), path("/f"), flags).unwrap().0;
+        let dh = fs.opendir(req(), path("/d"), libc::O_DIRECTORY as u32).unwrap().0;
+        fs.release(req(), path("/f"), fh, flags, 42, true).unwrap();
+        fs.releasedir(req(), path("/d"), dh, libc::O_DIRECTORY as u32).unwrap();
+        assert_eq!(*fs.inner.released.lock().unwrap(),
+                   vec![("/f".to_string(), OpenFlags::from(flags), Some(42)),
+                        ("/d".to_string(), OpenFlags::DIRECTORY, None)]);
+        assert_eq!((fs.open_files(), fs.open_dirs()), (0, 0));
+    }
+
+    #[test]
+    fn release_defaults() {
+        let fs = FuseFL::new(Recorder::default());
+        let fh = fs.open(req(), path("/f"), libc::O_RDWR as u32).unwrap().0;
+        fs.release(req(), path("/f"), fh, libc::O_RDWR as u32, 42, true).unwrap();
+        let fh = fs.open(req(), path("/f"), libc::O_RDWR as u32).unwrap().0;
+        fs.release(req(), path("/f"), fh, libc::O_RDWR as u32, 43, false).unwrap();
+        assert_eq!(calls(&fs), vec!["flush 7 42"]);
+
+        let dh = fs.opendir(req(), path("/d"), libc::O_DIRECTORY as u32).unwrap().0;
+        fs.releasedir(req(), path("/d"), dh, libc::O_DIRECTORY as u32).unwrap();
+        assert_eq!(fs.open_dirs(), 0);
+    }
+}
@@ -8,38 +8,26 @@
 //
 //
 use std::collections::HashMap;
-use std::sync::{RwLock, RwLockReadGuard};
-use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 
 
 /// Table for storing objects for handlers, and issuing handlers for new objects, all available via
 /// inner mutability.
+/// Objects are handed out as `Handler`-s, so the table is only locked for the duration of the
+/// lookup itself, and not while the object is being used.
 #[derive(Debug)]
 pub struct HandlerTable<T> {
     inner: RwLock<InnerTable<T>>,
+    // Notified whenever a `Handler` is dropped, for `remove` to wait on.
+    idle_lock: Mutex<()>,
+    idle: Condvar,
 }
 
 #[derive(Debug)]
 struct InnerTable<T> {
     next_fh: u64,
-    map: HashMap<u64, T>,
-}
-
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct HandlerTableGetGuard<'a, T: 'a> {
-    map_guard: RwLockReadGuard<'a, InnerTable<T>>,
-    val: Option<*const T>,
-}
-
-impl<'a, T> HandlerTableGetGuard<'a, T> {
-    pub fn as_opt_ref<'b>(&'b self) -> Option<&'b T> {
-        unsafe { self.val.map(|x| mem::transmute::<*const T, &'b T>(x)) }
-    }
-
-    pub fn unwrap<'b>(&'b self) -> &'b T {
-        self.as_opt_ref().unwrap()
-    }
+    map: HashMap<u64, Arc<T>>,
 }
 
 impl<T> HandlerTable<T> {
@@ -50,17 +38,20 @@ impl<T> HandlerTable<T> {
                 next_fh: 0, 
                 map: HashMap::new(),
             }),
+            idle_lock: Mutex::new(()),
+            idle: Condvar::new(),
         }
     }
 
     /// Get the object associated with a file handler, if it exists.
-    pub fn get<'a>(&'a self, fh: u64) -> HandlerTableGetGuard<'a, T> {
-        let map_guard = self.inner.read().unwrap();
-        let val: Option<*const T> = map_guard.map.get(&fh).map(|x| x as *const T);
-        HandlerTableGetGuard {
-            map_guard: map_guard,
-            val: val,
-        }
+    pub fn get(&self, fh: u64) -> Option<Handler<'_, T>> {
+        let obj = self.inner.read().unwrap().map.get(&fh).cloned()?;
+        Some(Handler { table: self, obj: Some(obj) })
+    }
+
+    /// The number of objects in the table.
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().map.len()
     }
 
     /// Insert a new object, returning the file handler generated for it.
@@ -70,13 +61,85 @@ impl<T> HandlerTable<T> {
             next_fh: ref mut fh,
             ref mut map,
         } = *inner;
-        assert!(map.insert(*fh, obj).is_none());
+        assert!(map.insert(*fh, Arc::new(obj)).is_none());
         *fh += 1;
         *fh - 1
     }
 
     /// Remove an object associated with a file handler, if it exists.
+    /// Since calls which got the object before its removal may still be using it, this waits
+    /// for them to finish before returning the object.
     pub fn remove(&self, fh: u64) -> Option<T> {
-        self.inner.write().unwrap().map.remove(&fh)
+        let mut obj = self.inner.write().unwrap().map.remove(&fh)?;
+        let mut idle = self.idle_lock.lock().unwrap();
+        loop {
+            match Arc::try_unwrap(obj) {
+                Ok(obj) => return Some(obj),
+                Err(shared) => {
+                    obj = shared;
+                    idle = self.idle.wait(idle).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// An object of a `HandlerTable`, in use by a call. Removing the object from the table waits for
+/// this to be dropped.
+#[derive(Debug)]
+pub struct Handler<'a, T: 'a> {
+    table: &'a HandlerTable<T>,
+    // Only taken on drop.
+    obj: Option<Arc<T>>,
+}
+
+impl<'a, T> Deref for Handler<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.obj.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> Drop for Handler<'a, T> {
+    fn drop(&mut self) {
+        // Drop the reference under the lock, so a `remove` checking for references can't miss the
+        // notification.
+        let _idle = self.table.idle_lock.lock().unwrap();
+        self.obj.take();
+        self.table.idle.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn remove_waits_for_handlers() {
+        let table = Arc::new(HandlerTable::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let fh = table.insert(42);
+        let (tx, rx) = mpsc::channel();
+        let user = {
+            let (table, done) = (table.clone(), done.clone());
+            thread::spawn(move || {
+                let handler = table.get(fh).unwrap();
+                tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(*handler, 42);
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        rx.recv().unwrap();
+        assert_eq!(table.remove(fh), Some(42));
+        assert!(done.load(Ordering::SeqCst));
+        assert!(table.get(fh).is_none());
+        assert_eq!(table.remove(fh), None);
+        user.join().unwrap();
     }
 }
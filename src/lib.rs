@@ -17,30 +17,56 @@ extern crate fuse_mt;
 extern crate libc;
 extern crate time;
 
+mod errno;
+mod flags;
 mod fusefl;
 mod handler_table;
+mod sys;
+pub mod buffered;
+pub mod cache;
+pub mod dirlike;
+pub mod faults;
 pub mod filelike;
+pub mod locks;
+pub mod memfs;
+pub mod metered;
+pub mod mux;
+pub mod overlay;
+pub mod passthrough;
+pub mod readahead;
+pub mod readonly;
+pub mod traced;
 
+pub use errno::Errno;
+pub use flags::{
+    AccessMode,
+    OpenFlags,
+    OpenReplyFlags,
+};
 pub use fusefl::{
     CreatedEntryObj,
+    ResultEmpty,
+    ResultEntry,
+    ResultGetattr,
+    ResultData,
+    ResultWrite,
+    ResultReaddir,
+    ResultStatfs,
+    ResultXattr,
     ResultOpenObj,
     ResultCreateObj,
     FilesystemFL,
     FuseFL,
 };
+pub use locks::{
+    RecordLock,
+    ResultLock,
+};
 pub use fuse_mt::{
     RequestInfo,
     DirectoryEntry,
     Statfs,
     Xattr,
-    ResultEmpty,
-    ResultGetattr,
-    ResultEntry,
-    ResultReaddir,
-    ResultData,
-    ResultWrite,
-    ResultStatfs,
-    ResultXattr,
     FuseMT,
     // These come from fuse.
     FileAttr,
@@ -50,8 +76,10 @@ pub use fuse_mt::{
 };
 
 use std::result;
-/// All results for fuse calls have `libc::c_int` as the Err variant.
-pub type Result<T> = result::Result<T, libc::c_int>;
+/// Results of the calls of this crate (including those of `FilesystemFL`) have `Errno` as the Err
+/// variant. It converts to and from the `libc::c_int` used as the Err variant of the fuse_mt
+/// results, which `FuseFL` converts to.
+pub type Result<T> = result::Result<T, Errno>;
 
 #[test]
 fn it_works() {}
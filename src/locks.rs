@@ -0,0 +1,439 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Lock tables for filesystems serving locks themselves: POSIX advisory record locks (`fcntl`
+//! F_GETLK/F_SETLK/F_SETLKW), and BSD whole-file locks (`flock`).
+//! Files are identified by a stable id (e.g. their inode number), so locks follow a file across
+//! renames and are shared by its hard links.
+//!
+//! NOTE: fuse_mt does not pass lock calls through yet, so `FuseFL` can't serve them; nor does fuse
+//! negotiate lock support with the kernel, which therefore handles locks on a mount by itself,
+//! locally (i.e. they are only seen by processes on the same machine). These tables are for
+//! filesystems which get lock calls otherwise, or for when fuse_mt passes them through; a
+//! `RecordLocks` table can be given to `FuseFL::set_record_locks`, which then releases a lock
+//! owner's locks as it closes the file, and a `FlockLocks` table to `FuseFL::set_flock_locks`.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use libc;
+
+use super::{Errno, Result};
+
+/// A POSIX record lock, like `struct flock`: either a lock held on a file, or (for `getlk`) the
+/// lock which is checked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLock {
+    /// First byte of the locked range.
+    pub start: u64,
+    /// Last byte of the locked range (inclusive); `u64::MAX` for a lock up to the end of file.
+    pub end: u64,
+    /// Type of the lock: one of `F_RDLCK`, `F_WRLCK` or `F_UNLCK`.
+    pub typ: u32,
+    /// Pid of the process holding the lock.
+    pub pid: u32,
+}
+
+/// Result of a `getlk` call.
+pub type ResultLock = Result<RecordLock>;
+
+#[derive(Debug, Clone, Copy)]
+struct HeldLock {
+    owner: u64,
+    start: u64,
+    end: u64,
+    write: bool,
+    pid: u32,
+}
+
+impl HeldLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn touches(&self, other: &HeldLock) -> bool {
+        self.overlaps(other.start, other.end) ||
+            other.end.checked_add(1) == Some(self.start) ||
+            self.end.checked_add(1) == Some(other.start)
+    }
+}
+
+/// Table of the POSIX record locks held on files, by file id. Locks belong to a lock owner, and a
+/// lock owner's locks never conflict with each other; setting a lock over a range replaces
+/// (splitting or merging as needed) whatever the owner held over it.
+/// NOTE: deadlocks between waiting owners are not detected.
+#[derive(Debug, Default)]
+pub struct RecordLocks {
+    locks: Mutex<HashMap<u64, Vec<HeldLock>>>,
+    released: Condvar,
+}
+
+fn lock_type(typ: u32) -> Result<Option<bool>> {
+    match typ as i32 {
+        libc::F_RDLCK => Ok(Some(false)),
+        libc::F_WRLCK => Ok(Some(true)),
+        libc::F_UNLCK => Ok(None),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+fn find_conflict(held: &[HeldLock], owner: u64, start: u64, end: u64, write: bool) -> Option<HeldLock> {
+    held.iter()
+        .find(|l| l.owner != owner && l.overlaps(start, end) && (write || l.write))
+        .cloned()
+}
+
+/// Remove the range `start..=end` from all locks of `owner`, splitting locks where needed.
+fn unlock_range(held: &mut Vec<HeldLock>, owner: u64, start: u64, end: u64) {
+    let mut split = Vec::new();
+    held.retain(|l| {
+        if l.owner != owner || !l.overlaps(start, end) {
+            return true;
+        }
+        if l.start < start {
+            split.push(HeldLock { end: start - 1, ..*l });
+        }
+        if l.end > end {
+            split.push(HeldLock { start: end + 1, ..*l });
+        }
+        false
+    });
+    held.extend(split);
+}
+
+impl RecordLocks {
+    /// Create a new, empty lock table.
+    pub fn new() -> RecordLocks {
+        RecordLocks::default()
+    }
+
+    /// Test for a lock on a file, like F_GETLK: if a lock of another owner conflicts with the given
+    /// one, return it; otherwise, return the given range with type `F_UNLCK`.
+    pub fn getlk(&self, file: u64, lock_owner: u64, lock: RecordLock) -> ResultLock {
+        let write = match lock_type(lock.typ)? {
+            Some(write) => write,
+            None => return Err(Errno::EINVAL),
+        };
+        if lock.start > lock.end {
+            return Err(Errno::EINVAL);
+        }
+        let locks = self.locks.lock().unwrap();
+        let conflict = locks.get(&file)
+            .and_then(|held| find_conflict(held, lock_owner, lock.start, lock.end, write));
+        Ok(match conflict {
+            Some(l) => RecordLock {
+                start: l.start,
+                end: l.end,
+                typ: (if l.write { libc::F_WRLCK } else { libc::F_RDLCK }) as u32,
+                pid: l.pid,
+            },
+            None => RecordLock { typ: libc::F_UNLCK as u32, ..lock },
+        })
+    }
+
+    /// Acquire or release a lock on a file, like F_SETLK, or F_SETLKW if `sleep` is set. Without
+    /// `sleep`, a conflicting lock of another owner fails the call with `EAGAIN`; with it, the call
+    /// blocks until the conflicting locks are released.
+    pub fn setlk(&self, file: u64, lock_owner: u64, lock: RecordLock, sleep: bool) -> Result<()> {
+        let write = lock_type(lock.typ)?;
+        if lock.start > lock.end {
+            return Err(Errno::EINVAL);
+        }
+        let mut locks = self.locks.lock().unwrap();
+        let write = match write {
+            Some(write) => write,
+            None => {
+                let now_empty = match locks.get_mut(&file) {
+                    Some(held) => {
+                        unlock_range(held, lock_owner, lock.start, lock.end);
+                        held.is_empty()
+                    }
+                    None => false,
+                };
+                if now_empty {
+                    locks.remove(&file);
+                }
+                self.released.notify_all();
+                return Ok(());
+            }
+        };
+        loop {
+            let conflict = locks.get(&file)
+                .and_then(|held| find_conflict(held, lock_owner, lock.start, lock.end, write));
+            if conflict.is_none() {
+                break;
+            }
+            if !sleep {
+                return Err(Errno::EAGAIN);
+            }
+            locks = self.released.wait(locks).unwrap();
+        }
+        let held = locks.entry(file).or_default();
+        unlock_range(held, lock_owner, lock.start, lock.end);
+        let mut new = HeldLock {
+            owner: lock_owner,
+            start: lock.start,
+            end: lock.end,
+            write,
+            pid: lock.pid,
+        };
+        // Merge with adjacent locks of the same owner and type.
+        held.retain(|l| {
+            if l.owner == lock_owner && l.write == write && l.touches(&new) {
+                new.start = ::std::cmp::min(new.start, l.start);
+                new.end = ::std::cmp::max(new.end, l.end);
+                false
+            } else {
+                true
+            }
+        });
+        held.push(new);
+        // Downgrading a write lock may let others in.
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// Release all the locks held by `lock_owner` on a file, e.g. on `flush` and `release`.
+    pub fn release_owner(&self, file: u64, lock_owner: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        let now_empty = match locks.get_mut(&file) {
+            Some(held) => {
+                let len = held.len();
+                held.retain(|l| l.owner != lock_owner);
+                if held.len() == len {
+                    return;
+                }
+                held.is_empty()
+            }
+            None => return,
+        };
+        if now_empty {
+            locks.remove(&file);
+        }
+        self.released.notify_all();
+    }
+}
+
+#[derive(Debug, Default)]
+struct FlockTable {
+    /// For each file, the file handlers holding a lock on it, and whether it's exclusive.
+    files: HashMap<u64, HashMap<u64, bool>>,
+    /// For each file handler holding a lock, the file it is on.
+    handlers: HashMap<u64, u64>,
+}
+
+impl FlockTable {
+    fn unlock(&mut self, fh: u64) -> bool {
+        let file = match self.handlers.remove(&fh) {
+            Some(file) => file,
+            None => return false,
+        };
+        let now_empty = {
+            let holders = self.files.get_mut(&file).unwrap();
+            holders.remove(&fh);
+            holders.is_empty()
+        };
+        if now_empty {
+            self.files.remove(&file);
+        }
+        true
+    }
+
+    fn conflicts(&self, file: u64, fh: u64, exclusive: bool) -> bool {
+        match self.files.get(&file) {
+            Some(holders) => holders.iter().any(|(&h, &ex)| h != fh && (exclusive || ex)),
+            None => false,
+        }
+    }
+}
+
+/// Table of the BSD `flock` locks held on files, by file id. Unlike record locks, these belong to
+/// an open file (i.e. a file handler), and cover the whole file.
+#[derive(Debug, Default)]
+pub struct FlockLocks {
+    table: Mutex<FlockTable>,
+    released: Condvar,
+}
+
+impl FlockLocks {
+    /// Create a new, empty lock table.
+    pub fn new() -> FlockLocks {
+        FlockLocks::default()
+    }
+
+    /// Apply a `flock` operation through the file handler `fh` of a file: `LOCK_SH`, `LOCK_EX` or
+    /// `LOCK_UN`, possibly or-ed with `LOCK_NB`.
+    /// Like `flock`, converting an existing lock is not atomic: the old lock is released before the
+    /// new one is waited for. With `LOCK_NB`, a conflicting lock fails the call with
+    /// `EWOULDBLOCK`.
+    pub fn flock(&self, file: u64, fh: u64, op: u32) -> Result<()> {
+        let op = op as i32;
+        let nonblock = op & libc::LOCK_NB != 0;
+        let exclusive = match op & !libc::LOCK_NB {
+            libc::LOCK_SH => false,
+            libc::LOCK_EX => true,
+            libc::LOCK_UN => {
+                if self.table.lock().unwrap().unlock(fh) {
+                    self.released.notify_all();
+                }
+                return Ok(());
+            }
+            _ => return Err(Errno::EINVAL),
+        };
+        let mut table = self.table.lock().unwrap();
+        if table.files.get(&file).and_then(|holders| holders.get(&fh)) == Some(&exclusive) {
+            return Ok(());
+        }
+        if table.unlock(fh) {
+            self.released.notify_all();
+        }
+        while table.conflicts(file, fh, exclusive) {
+            if nonblock {
+                return Err(Errno::EWOULDBLOCK);
+            }
+            table = self.released.wait(table).unwrap();
+        }
+        table.files.entry(file).or_default().insert(fh, exclusive);
+        table.handlers.insert(fh, file);
+        Ok(())
+    }
+
+    /// Release the lock held through a file handler, if any, e.g. on `release`.
+    pub fn release_handler(&self, fh: u64) {
+        if self.table.lock().unwrap().unlock(fh) {
+            self.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn lock(start: u64, end: u64, typ: i32) -> RecordLock {
+        RecordLock { start, end, typ: typ as u32, pid: 1 }
+    }
+
+    fn held(locks: &RecordLocks, file: u64, owner: u64) -> Vec<(u64, u64, bool)> {
+        let mut held: Vec<_> = locks.locks.lock().unwrap().get(&file).map_or(vec![], |held| {
+            held.iter().filter(|l| l.owner == owner).map(|l| (l.start, l.end, l.write)).collect()
+        });
+        held.sort();
+        held
+    }
+
+    #[test]
+    fn unlock_splits() {
+        let locks = RecordLocks::new();
+        locks.setlk(1, 10, lock(0, 99, libc::F_WRLCK), false).unwrap();
+        locks.setlk(1, 10, lock(40, 59, libc::F_UNLCK), false).unwrap();
+        assert_eq!(held(&locks, 1, 10), vec![(0, 39, true), (60, 99, true)]);
+        // Changing the type of the middle of a lock splits it too.
+        locks.setlk(1, 10, lock(70, 79, libc::F_RDLCK), false).unwrap();
+        assert_eq!(held(&locks, 1, 10),
+                   vec![(0, 39, true), (60, 69, true), (70, 79, false), (80, 99, true)]);
+    }
+
+    #[test]
+    fn adjacent_locks_merge() {
+        let locks = RecordLocks::new();
+        locks.setlk(1, 10, lock(0, 9, libc::F_RDLCK), false).unwrap();
+        locks.setlk(1, 10, lock(20, 29, libc::F_RDLCK), false).unwrap();
+        locks.setlk(1, 10, lock(10, 19, libc::F_RDLCK), false).unwrap();
+        assert_eq!(held(&locks, 1, 10), vec![(0, 29, false)]);
+        // Locks of another type don't.
+        locks.setlk(1, 10, lock(30, u64::MAX, libc::F_WRLCK), false).unwrap();
+        assert_eq!(held(&locks, 1, 10), vec![(0, 29, false), (30, u64::MAX, true)]);
+    }
+
+    #[test]
+    fn conflicts() {
+        let locks = RecordLocks::new();
+        locks.setlk(1, 10, lock(0, 9, libc::F_RDLCK), false).unwrap();
+        locks.setlk(1, 10, lock(20, 29, libc::F_WRLCK), false).unwrap();
+        // Read locks are shared, write locks aren't.
+        locks.setlk(1, 11, lock(5, 14, libc::F_RDLCK), false).unwrap();
+        assert_eq!(locks.setlk(1, 11, lock(5, 14, libc::F_WRLCK), false), Err(Errno::EAGAIN));
+        assert_eq!(locks.setlk(1, 11, lock(25, 25, libc::F_RDLCK), false), Err(Errno::EAGAIN));
+        // Other files and other ranges are independent.
+        locks.setlk(2, 11, lock(25, 25, libc::F_WRLCK), false).unwrap();
+        locks.setlk(1, 11, lock(30, 39, libc::F_WRLCK), false).unwrap();
+        assert_eq!(locks.getlk(1, 11, lock(0, 100, libc::F_WRLCK)),
+                   Ok(RecordLock { start: 0, end: 9, typ: libc::F_RDLCK as u32, pid: 1 }));
+        assert_eq!(locks.getlk(1, 11, lock(15, 19, libc::F_WRLCK)),
+                   Ok(lock(15, 19, libc::F_UNLCK)));
+        // An owner's own locks never conflict.
+        assert_eq!(locks.getlk(1, 10, lock(20, 29, libc::F_WRLCK)),
+                   Ok(lock(20, 29, libc::F_UNLCK)));
+        assert_eq!(locks.setlk(1, 10, lock(5, 4, libc::F_WRLCK), false), Err(Errno::EINVAL));
+        assert_eq!(locks.getlk(1, 10, lock(0, 9, libc::F_UNLCK)), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn setlkw_blocks_until_released() {
+        let locks = Arc::new(RecordLocks::new());
+        locks.setlk(1, 10, lock(0, 9, libc::F_WRLCK), false).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks.setlk(1, 11, lock(5, 5, libc::F_RDLCK), true).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        // Unlocking a range not covering the conflict keeps it waiting.
+        locks.setlk(1, 10, lock(0, 4, libc::F_UNLCK), false).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        locks.release_owner(1, 10);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+        assert_eq!(held(&locks, 1, 11), vec![(5, 5, false)]);
+    }
+
+    #[test]
+    fn flock_conflicts() {
+        let locks = FlockLocks::new();
+        locks.flock(1, 100, libc::LOCK_SH as u32).unwrap();
+        locks.flock(1, 101, libc::LOCK_SH as u32).unwrap();
+        let ex_nb = (libc::LOCK_EX | libc::LOCK_NB) as u32;
+        assert_eq!(locks.flock(1, 102, ex_nb), Err(Errno::EWOULDBLOCK));
+        // Even a holder can't convert its lock while another one is held.
+        assert_eq!(locks.flock(1, 100, ex_nb), Err(Errno::EWOULDBLOCK));
+        locks.flock(1, 101, libc::LOCK_UN as u32).unwrap();
+        locks.flock(1, 100, ex_nb).unwrap();
+        assert_eq!(locks.flock(1, 101, (libc::LOCK_SH | libc::LOCK_NB) as u32),
+                   Err(Errno::EWOULDBLOCK));
+        // Other files are independent.
+        locks.flock(2, 101, ex_nb).unwrap();
+        assert_eq!(locks.flock(1, 100, 0), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn flock_blocks_until_released() {
+        let locks = Arc::new(FlockLocks::new());
+        locks.flock(1, 100, libc::LOCK_EX as u32).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                locks.flock(1, 101, libc::LOCK_SH as u32).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        locks.release_handler(100);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+        assert_eq!(locks.flock(1, 100, (libc::LOCK_EX | libc::LOCK_NB) as u32),
+                   Err(Errno::EWOULDBLOCK));
+    }
+}
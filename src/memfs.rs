@@ -0,0 +1,959 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A filesystem held entirely in memory, like tmpfs: directories, regular files (as
+//! `filelike::MemFile`), symlinks, hard links, device nodes and fifos, with permissions,
+//! timestamps and xattrs.
+//! Open files are shared with the tree, so they keep working after being unlinked or renamed.
+//! The size of the filesystem and its number of inodes are limited, failing with `ENOSPC` beyond
+//! them; file data (and symlink targets) count towards the size, and are freed once a file is
+//! both unlinked and closed.
+//! NOTE: only `access` checks permissions, so the filesystem should be mounted with the
+//! `default_permissions` option to have the kernel check them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use libc;
+use time::{self, Timespec};
+
+use super::dirlike::FilesystemFLOpenDir;
+use super::filelike::{AttrFileLike, FilesystemFLMeta, FilesystemFLOpen, MemFile, OpenFs,
+                      ReadFileLike, SetLenFileLike, WriteFileLike};
+use super::flags::{OpenFlags, OpenReplyFlags};
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultStatfs,
+            ResultXattr};
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+const ROOT: u64 = 1;
+const BLOCK_SIZE: u64 = 4096;
+const NAME_MAX: usize = 255;
+// Flags of setxattr.
+const XATTR_CREATE: u32 = 1;
+const XATTR_REPLACE: u32 = 2;
+
+/// A `FilesystemFL` held in memory, see the module docs.
+pub type MemFs = OpenFs<InMemory>;
+
+/// The implementation of `MemFs`, to be wrapped in `OpenFs`.
+#[derive(Debug)]
+pub struct InMemory {
+    tree: RwLock<Tree>,
+    space: Arc<Space>,
+    max_inodes: u64,
+}
+
+// The bytes used by a filesystem, shared with its files.
+#[derive(Debug)]
+struct Space {
+    used: Mutex<u64>,
+    max: u64,
+}
+
+impl Space {
+    fn reserve(&self, bytes: u64) -> Result<()> {
+        let mut used = self.used.lock().unwrap();
+        if bytes > self.max - *used {
+            return Err(Errno::ENOSPC);
+        }
+        *used += bytes;
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        *self.used.lock().unwrap() -= bytes;
+    }
+}
+
+/// A regular file of `MemFs`: a `MemFile` whose data counts towards the size of the filesystem,
+/// with writes and truncates growing it beyond that failing with `ENOSPC`.
+#[derive(Debug)]
+pub struct InMemoryFile {
+    file: MemFile,
+    space: Arc<Space>,
+}
+
+impl InMemoryFile {
+    fn new(space: Arc<Space>) -> InMemoryFile {
+        InMemoryFile { file: MemFile::new(), space }
+    }
+
+    // Change the file by `f`, which would make it (at most) `size` bytes long, accounting for the
+    // change in size. Changes of size are serialized by the lock of the space.
+    fn resize_with<F, T>(&self, size: u64, f: F) -> Result<T>
+        where F: FnOnce(&MemFile) -> Result<T> {
+        let mut used = self.space.used.lock().unwrap();
+        let old = self.file.len();
+        if size > old && size - old > self.space.max - *used {
+            return Err(Errno::ENOSPC);
+        }
+        let res = f(&self.file);
+        *used = *used - old + self.file.len();
+        res
+    }
+}
+
+impl ReadFileLike for InMemoryFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+}
+
+impl WriteFileLike for InMemoryFile {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let end = offset.saturating_add(buf.len() as u64);
+        self.resize_with(if buf.is_empty() { 0 } else { end }, |file| file.write_at(buf, offset))
+    }
+}
+
+impl SetLenFileLike for InMemoryFile {
+    fn set_len(&self, size: u64) -> Result<()> {
+        self.resize_with(size, |file| file.set_len(size))
+    }
+}
+
+impl AttrFileLike for InMemoryFile {
+    fn getattr(&self) -> Result<FileAttr> {
+        self.file.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.file.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.file.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.file.utimens(atime, mtime)
+    }
+}
+
+impl Drop for InMemoryFile {
+    fn drop(&mut self) {
+        self.space.release(self.file.len());
+    }
+}
+
+#[derive(Debug)]
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    next_ino: u64,
+    space: Arc<Space>,
+}
+
+#[derive(Debug)]
+struct Node {
+    // For regular files, the attributes are kept by the `MemFile`, and only `nlink` is used.
+    attr: FileAttr,
+    data: NodeData,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum NodeData {
+    File(Arc<InMemoryFile>),
+    Dir { parent: u64, entries: BTreeMap<OsString, u64> },
+    Symlink(PathBuf),
+    Special,
+}
+
+fn new_attr(req: &RequestInfo, kind: FileType, mode: u32, rdev: u32) -> FileAttr {
+    let now = time::get_time();
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm: (mode & 0o7777) as u16,
+        nlink: 1,
+        uid: req.uid,
+        gid: req.gid,
+        rdev,
+        flags: 0,
+    }
+}
+
+fn kind_from_mode(mode: u32) -> Result<FileType> {
+    match mode as libc::mode_t & libc::S_IFMT {
+        libc::S_IFREG => Ok(FileType::RegularFile),
+        libc::S_IFCHR => Ok(FileType::CharDevice),
+        libc::S_IFBLK => Ok(FileType::BlockDevice),
+        libc::S_IFIFO => Ok(FileType::NamedPipe),
+        libc::S_IFSOCK => Ok(FileType::Socket),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+fn check_name(name: &OsStr) -> Result<()> {
+    if name.len() > NAME_MAX {
+        Err(Errno::ENAMETOOLONG)
+    } else {
+        Ok(())
+    }
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        matches!(self.data, NodeData::Dir { .. })
+    }
+
+    fn attr(&self, ino: u64) -> FileAttr {
+        let mut attr = match self.data {
+            NodeData::File(ref file) => file.getattr().expect("MemFile always has attributes"),
+            NodeData::Dir { ref entries, .. } => {
+                let mut attr = self.attr;
+                attr.size = entries.len() as u64;
+                attr
+            }
+            NodeData::Symlink(ref target) => {
+                let mut attr = self.attr;
+                attr.size = target.as_os_str().len() as u64;
+                attr
+            }
+            NodeData::Special => self.attr,
+        };
+        attr.ino = ino;
+        attr
+    }
+
+    fn set_nlink(&mut self, nlink: u32) {
+        self.attr.nlink = nlink;
+        if let NodeData::File(ref file) = self.data {
+            file.file.set_nlink(nlink);
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = time::get_time();
+        self.attr.mtime = now;
+        self.attr.ctime = now;
+    }
+}
+
+impl Tree {
+    fn node(&self, ino: u64) -> &Node {
+        self.nodes.get(&ino).expect("dangling inode in MemFs")
+    }
+
+    fn node_mut(&mut self, ino: u64) -> &mut Node {
+        self.nodes.get_mut(&ino).expect("dangling inode in MemFs")
+    }
+
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<OsString, u64>> {
+        match self.node(ino).data {
+            NodeData::Dir { ref entries, .. } => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<OsString, u64>> {
+        match self.node_mut(ino).data {
+            NodeData::Dir { ref mut entries, .. } => Ok(entries),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn parent(&self, ino: u64) -> u64 {
+        match self.node(ino).data {
+            NodeData::Dir { parent, .. } => parent,
+            _ => panic!("parent of a non-directory in MemFs"),
+        }
+    }
+
+    fn resolve(&self, path: &Path) -> Result<u64> {
+        let mut ino = ROOT;
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => ino = self.parent(ino),
+                Component::Normal(name) => ino = self.lookup(ino, name)?,
+                Component::Prefix(_) => return Err(Errno::EINVAL),
+            }
+        }
+        Ok(ino)
+    }
+
+    fn lookup(&self, parent: u64, name: &OsStr) -> Result<u64> {
+        self.entries(parent)?.get(name).cloned().ok_or(Errno::ENOENT)
+    }
+
+    fn insert(&mut self, max_inodes: u64, parent: u64, name: &OsStr, node: Node) -> Result<u64> {
+        check_name(name)?;
+        if self.entries(parent)?.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        if self.nodes.len() as u64 >= max_inodes {
+            return Err(Errno::ENOSPC);
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let is_dir = node.is_dir();
+        self.nodes.insert(ino, node);
+        self.entries_mut(parent)?.insert(name.to_owned(), ino);
+        self.link_into(parent, is_dir);
+        Ok(ino)
+    }
+
+    // Account for a new entry in a directory: a subdirectory links back to it with "..".
+    fn link_into(&mut self, parent: u64, is_dir: bool) {
+        let parent = self.node_mut(parent);
+        if is_dir {
+            let nlink = parent.attr.nlink + 1;
+            parent.set_nlink(nlink);
+        }
+        parent.touch();
+    }
+
+    // Remove an entry from a directory, dropping the node with its last link.
+    fn detach(&mut self, parent: u64, name: &OsStr) -> Result<u64> {
+        let ino = self.entries_mut(parent)?.remove(name).ok_or(Errno::ENOENT)?;
+        let is_dir = {
+            let node = self.node_mut(ino);
+            let nlink = if node.is_dir() { 0 } else { node.attr.nlink - 1 };
+            node.set_nlink(nlink);
+            node.attr.ctime = time::get_time();
+            node.is_dir()
+        };
+        if self.node(ino).attr.nlink == 0 {
+            if let Some(Node { data: NodeData::Symlink(target), .. }) = self.nodes.remove(&ino) {
+                self.space.release(target.as_os_str().len() as u64);
+            }
+        }
+        let parent = self.node_mut(parent);
+        if is_dir {
+            let nlink = parent.attr.nlink - 1;
+            parent.set_nlink(nlink);
+        }
+        parent.touch();
+        Ok(ino)
+    }
+}
+
+impl InMemory {
+    /// Create an empty filesystem, with the root directory owned by the user running it.
+    /// The reported size is 1TiB, with 2^32 inodes.
+    pub fn new() -> InMemory {
+        InMemory::with_limits(1 << 40, 1 << 32)
+    }
+
+    /// Create an empty filesystem of the given size (in bytes), allowing at most the given number
+    /// of inodes.
+    pub fn with_limits(max_bytes: u64, max_inodes: u64) -> InMemory {
+        let req = RequestInfo {
+            unique: 0,
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            pid: 0,
+        };
+        let mut attr = new_attr(&req, FileType::Directory, 0o755, 0);
+        attr.nlink = 2;
+        let root = Node {
+            attr,
+            data: NodeData::Dir { parent: ROOT, entries: BTreeMap::new() },
+            xattrs: BTreeMap::new(),
+        };
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT, root);
+        let space = Arc::new(Space { used: Mutex::new(0), max: max_bytes });
+        InMemory {
+            tree: RwLock::new(Tree { nodes, next_ino: ROOT + 1, space: space.clone() }),
+            space,
+            max_inodes,
+        }
+    }
+
+    fn create_node(&self, req: &RequestInfo, parent: &Path, name: &OsStr, attr: FileAttr, data: NodeData)
+                   -> Result<(Timespec, FileAttr)> {
+        if let NodeData::File(ref file) = data {
+            file.chmod(u32::from(attr.perm))?;
+            file.chown(Some(req.uid), Some(req.gid))?;
+        }
+        let target_len = match data {
+            NodeData::Symlink(ref target) => target.as_os_str().len() as u64,
+            _ => 0,
+        };
+        let node = Node { attr, data, xattrs: BTreeMap::new() };
+        let mut tree = self.tree.write().unwrap();
+        let parent = tree.resolve(parent)?;
+        self.space.reserve(target_len)?;
+        match tree.insert(self.max_inodes, parent, name, node) {
+            Ok(ino) => Ok((TTL, tree.node(ino).attr(ino))),
+            Err(e) => {
+                self.space.release(target_len);
+                Err(e)
+            }
+        }
+    }
+
+    fn set_attr<F>(&self, path: &Path, f: F) -> Result<()>
+        where F: FnOnce(&mut FileAttr) {
+        let mut tree = self.tree.write().unwrap();
+        let ino = tree.resolve(path)?;
+        let node = tree.node_mut(ino);
+        f(&mut node.attr);
+        node.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    fn file(&self, path: &Path) -> Result<Arc<InMemoryFile>> {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(path)?;
+        match tree.node(ino).data {
+            NodeData::File(ref file) => Ok(file.clone()),
+            NodeData::Dir { .. } => Err(Errno::EISDIR),
+            // There are no drivers behind device nodes or fifos.
+            _ => Err(Errno::ENXIO),
+        }
+    }
+}
+
+impl Default for InMemory {
+    fn default() -> InMemory {
+        InMemory::new()
+    }
+}
+
+impl FilesystemFLOpen for InMemory {
+    type FileLike = Arc<InMemoryFile>;
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Arc<InMemoryFile>> {
+        let file = self.file(_path)?;
+        if _flags.contains(OpenFlags::TRUNC) && _flags.access_mode()?.writable() {
+            file.set_len(0)?;
+        }
+        Ok((file, OpenReplyFlags::empty()))
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Arc<InMemoryFile>> {
+        let file = Arc::new(InMemoryFile::new(self.space.clone()));
+        let attr = new_attr(&_req, FileType::RegularFile, _mode, 0);
+        let (ttl, attr) = self.create_node(&_req, _parent, _name, attr, NodeData::File(file.clone()))?;
+        Ok(CreatedEntryObj { ttl, attr, fl: file, flags: OpenReplyFlags::empty() })
+    }
+
+    fn fsync_metadata(&self, _req: RequestInfo, _path: &Path, _fl: &Arc<InMemoryFile>) -> ResultEmpty {
+        Ok(())
+    }
+}
+
+impl FilesystemFLOpenDir for InMemory {
+    type DirLike = Vec<DirectoryEntry>;
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Vec<DirectoryEntry>> {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        let mut list = vec![
+            DirectoryEntry { name: ".".into(), kind: FileType::Directory },
+            DirectoryEntry { name: "..".into(), kind: FileType::Directory },
+        ];
+        for (name, &child) in tree.entries(ino)? {
+            list.push(DirectoryEntry { name: name.clone(), kind: tree.node(child).attr(child).kind });
+        }
+        Ok((list, OpenReplyFlags::empty()))
+    }
+}
+
+impl FilesystemFLMeta for InMemory {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        let tree = self.tree.read().unwrap();
+        let parent = tree.resolve(_parent)?;
+        let ino = tree.lookup(parent, _name)?;
+        Ok((TTL, tree.node(ino).attr(ino)))
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Arc<InMemoryFile>>)
+               -> ResultGetattr {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        Ok((TTL, tree.node(ino).attr(ino)))
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Arc<InMemoryFile>>,
+             _mode: u32)
+             -> ResultEmpty {
+        match self.file(_path) {
+            Ok(file) => file.chmod(_mode),
+            Err(_) => self.set_attr(_path, |attr| attr.perm = (_mode & 0o7777) as u16),
+        }
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Arc<InMemoryFile>>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        match self.file(_path) {
+            Ok(file) => file.chown(_uid, _gid),
+            Err(_) => {
+                self.set_attr(_path, |attr| {
+                    attr.uid = _uid.unwrap_or(attr.uid);
+                    attr.gid = _gid.unwrap_or(attr.gid);
+                })
+            }
+        }
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Arc<InMemoryFile>>,
+                _size: u64)
+                -> ResultEmpty {
+        self.file(_path)?.set_len(_size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Arc<InMemoryFile>>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        match self.file(_path) {
+            Ok(file) => file.utimens(_atime, _mtime),
+            Err(_) => {
+                self.set_attr(_path, |attr| {
+                    attr.atime = _atime.unwrap_or(attr.atime);
+                    attr.mtime = _mtime.unwrap_or(attr.mtime);
+                })
+            }
+        }
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        match tree.node(ino).data {
+            NodeData::Symlink(ref target) => Ok(target.as_os_str().as_bytes().to_vec()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        let kind = kind_from_mode(_mode)?;
+        let data = match kind {
+            FileType::RegularFile => NodeData::File(Arc::new(InMemoryFile::new(self.space.clone()))),
+            _ => NodeData::Special,
+        };
+        let attr = new_attr(&_req, kind, _mode, _rdev);
+        self.create_node(&_req, _parent, _name, attr, data)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        let mut attr = new_attr(&_req, FileType::Directory, _mode, 0);
+        attr.nlink = 2;
+        let mut tree = self.tree.write().unwrap();
+        let parent = tree.resolve(_parent)?;
+        let node = Node {
+            attr,
+            data: NodeData::Dir { parent, entries: BTreeMap::new() },
+            xattrs: BTreeMap::new(),
+        };
+        let ino = tree.insert(self.max_inodes, parent, _name, node)?;
+        Ok((TTL, tree.node(ino).attr(ino)))
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let mut tree = self.tree.write().unwrap();
+        let parent = tree.resolve(_parent)?;
+        let ino = tree.lookup(parent, _name)?;
+        if tree.node(ino).is_dir() {
+            return Err(Errno::EISDIR);
+        }
+        tree.detach(parent, _name)?;
+        Ok(())
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let mut tree = self.tree.write().unwrap();
+        let parent = tree.resolve(_parent)?;
+        let ino = tree.lookup(parent, _name)?;
+        if !tree.entries(ino)?.is_empty() {
+            return Err(Errno::ENOTEMPTY);
+        }
+        tree.detach(parent, _name)?;
+        Ok(())
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        let attr = new_attr(&_req, FileType::Symlink, 0o777, 0);
+        self.create_node(&_req, _parent, _name, attr, NodeData::Symlink(_target.to_owned()))
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        check_name(_newname)?;
+        let mut tree = self.tree.write().unwrap();
+        let parent = tree.resolve(_parent)?;
+        let newparent = tree.resolve(_newparent)?;
+        let ino = tree.lookup(parent, _name)?;
+        tree.entries(newparent)?;
+        let is_dir = tree.node(ino).is_dir();
+        if is_dir {
+            // A directory can't be moved under itself.
+            let mut dir = newparent;
+            while dir != ROOT {
+                if dir == ino {
+                    return Err(Errno::EINVAL);
+                }
+                dir = tree.parent(dir);
+            }
+        }
+        match tree.lookup(newparent, _newname) {
+            Ok(existing) if existing == ino => return Ok(()),
+            Ok(existing) => {
+                match (is_dir, tree.node(existing).is_dir()) {
+                    (true, false) => return Err(Errno::ENOTDIR),
+                    (false, true) => return Err(Errno::EISDIR),
+                    (true, true) if !tree.entries(existing)?.is_empty() => return Err(Errno::ENOTEMPTY),
+                    _ => {}
+                }
+                tree.detach(newparent, _newname)?;
+            }
+            Err(_) => {}
+        }
+        tree.entries_mut(parent)?.remove(_name);
+        tree.entries_mut(newparent)?.insert(_newname.to_owned(), ino);
+        {
+            let node = tree.node_mut(ino);
+            node.attr.ctime = time::get_time();
+            if let NodeData::Dir { ref mut parent, .. } = node.data {
+                *parent = newparent;
+            }
+        }
+        if is_dir {
+            let nlink = tree.node(parent).attr.nlink - 1;
+            tree.node_mut(parent).set_nlink(nlink);
+        }
+        tree.node_mut(parent).touch();
+        tree.link_into(newparent, is_dir);
+        Ok(())
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        check_name(_newname)?;
+        let mut tree = self.tree.write().unwrap();
+        let ino = tree.resolve(_path)?;
+        if tree.node(ino).is_dir() {
+            return Err(Errno::EPERM);
+        }
+        let newparent = tree.resolve(_newparent)?;
+        if tree.entries(newparent)?.contains_key(_newname) {
+            return Err(Errno::EEXIST);
+        }
+        tree.entries_mut(newparent)?.insert(_newname.to_owned(), ino);
+        tree.link_into(newparent, false);
+        let node = tree.node_mut(ino);
+        let nlink = node.attr.nlink + 1;
+        node.set_nlink(nlink);
+        Ok((TTL, node.attr(ino)))
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        let tree = self.tree.read().unwrap();
+        let used = (*self.space.used.lock().unwrap() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let blocks = self.space.max / BLOCK_SIZE;
+        let free = blocks.saturating_sub(used);
+        Ok(Statfs {
+            blocks,
+            bfree: free,
+            bavail: free,
+            files: self.max_inodes,
+            ffree: self.max_inodes.saturating_sub(tree.nodes.len() as u64),
+            bsize: BLOCK_SIZE as u32,
+            namelen: NAME_MAX as u32,
+            frsize: BLOCK_SIZE as u32,
+        })
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        if _position != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let mut tree = self.tree.write().unwrap();
+        let ino = tree.resolve(_path)?;
+        let node = tree.node_mut(ino);
+        let exists = node.xattrs.contains_key(_name);
+        if _flags & XATTR_CREATE != 0 && exists {
+            return Err(Errno::EEXIST);
+        }
+        if _flags & XATTR_REPLACE != 0 && !exists {
+            return Err(Errno::ENODATA);
+        }
+        node.xattrs.insert(_name.to_owned(), _value.to_vec());
+        node.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        let value = tree.node(ino).xattrs.get(_name).ok_or(Errno::ENODATA)?;
+        xattr_reply(value.clone(), _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        let mut list = vec![];
+        for name in tree.node(ino).xattrs.keys() {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        xattr_reply(list, _size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        let mut tree = self.tree.write().unwrap();
+        let ino = tree.resolve(_path)?;
+        let node = tree.node_mut(ino);
+        node.xattrs.remove(_name).ok_or(Errno::ENODATA)?;
+        node.attr.ctime = time::get_time();
+        Ok(())
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        let tree = self.tree.read().unwrap();
+        let ino = tree.resolve(_path)?;
+        let attr = tree.node(ino).attr(ino);
+        let perm = u32::from(attr.perm);
+        let allowed = if _req.uid == 0 {
+            // root may do anything, except executing what no one may execute.
+            if attr.kind == FileType::Directory || perm & 0o111 != 0 { 0o7 } else { 0o6 }
+        } else if _req.uid == attr.uid {
+            (perm >> 6) & 0o7
+        } else if _req.gid == attr.gid {
+            (perm >> 3) & 0o7
+        } else {
+            perm & 0o7
+        };
+        if _mask & !allowed == 0 { Ok(()) } else { Err(Errno::EACCES) }
+    }
+}
+
+fn xattr_reply(value: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(value.len() as u32))
+    } else if value.len() > size as usize {
+        Err(Errno::ERANGE)
+    } else {
+        Ok(Xattr::Data(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::flags::AccessMode;
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    fn create(fs: &MemFs, parent: &str, file: &str, data: &[u8]) -> Arc<InMemoryFile> {
+        let fl = fs.create(req(), path(parent), name(file), 0o644, AccessMode::ReadWrite.into())
+            .unwrap()
+            .fl;
+        fs.write(req(), path(parent), &fl, 0, data.to_vec(), 0).unwrap();
+        fl
+    }
+
+    fn read(fs: &MemFs, file: &str) -> Vec<u8> {
+        let fl = fs.open(req(), path(file), AccessMode::ReadOnly.into()).unwrap().0;
+        fs.read(req(), path(file), &fl, 0, 4096).unwrap()
+    }
+
+    fn used_blocks(fs: &MemFs) -> u64 {
+        let statfs = fs.statfs(req(), path("/")).unwrap();
+        statfs.blocks - statfs.bfree
+    }
+
+    #[test]
+    fn rename_over_existing() {
+        let fs = MemFs::new(InMemory::new());
+        create(&fs, "/", "a", b"new");
+        create(&fs, "/", "b", &[0; 5000]);
+        assert_eq!(used_blocks(&fs), 2);
+        fs.rename(req(), path("/"), name("a"), path("/"), name("b")).unwrap();
+        assert_eq!(read(&fs, "/b"), b"new");
+        assert_eq!(fs.getattr(req(), path("/a"), None).err(), Some(Errno::ENOENT));
+        assert_eq!(used_blocks(&fs), 1);
+        fs.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        let err = fs.rename(req(), path("/"), name("b"), path("/"), name("d")).err();
+        assert_eq!(err, Some(Errno::EISDIR));
+    }
+
+    #[test]
+    fn link_and_unlink() {
+        let fs = MemFs::new(InMemory::new());
+        let fl = create(&fs, "/", "f", &[1; 5000]);
+        let attr = fs.link(req(), path("/f"), path("/"), name("g")).unwrap().1;
+        assert_eq!(attr.nlink, 2);
+        fs.unlink(req(), path("/"), name("f")).unwrap();
+        assert_eq!(fs.getattr(req(), path("/g"), None).unwrap().1.nlink, 1);
+        fs.unlink(req(), path("/"), name("g")).unwrap();
+        assert_eq!(fs.unlink(req(), path("/"), name("g")), Err(Errno::ENOENT));
+        // The data is kept while the file is open.
+        assert_eq!(fs.getattr(req(), path("/g"), Some(&fl)).unwrap().1.nlink, 0);
+        assert_eq!(used_blocks(&fs), 2);
+        fs.release(req(), path("/g"), fl, AccessMode::ReadWrite.into(), 0, true).unwrap();
+        assert_eq!(used_blocks(&fs), 0);
+    }
+
+    #[test]
+    fn attrs_of_open_files() {
+        let fs = MemFs::new(InMemory::new());
+        let fl = create(&fs, "/", "f", b"hello");
+        fs.rename(req(), path("/"), name("f"), path("/"), name("g")).unwrap();
+        fs.chmod(req(), path("/g"), Some(&fl), 0o600).unwrap();
+        assert_eq!(fs.getattr(req(), path("/g"), None).unwrap().1.perm, 0o600);
+
+        fs.unlink(req(), path("/"), name("g")).unwrap();
+        let mtime = Timespec::new(1_000_000_000, 5);
+        fs.utimens(req(), path("/g"), Some(&fl), None, Some(mtime)).unwrap();
+        fs.chmod(req(), path("/g"), Some(&fl), 0o640).unwrap();
+        let attr = fs.getattr(req(), path("/g"), Some(&fl)).unwrap().1;
+        assert_eq!((attr.size, attr.perm, attr.nlink), (5, 0o640, 0));
+        assert_eq!(attr.mtime, mtime);
+    }
+
+    #[test]
+    fn rmdir_non_empty() {
+        let fs = MemFs::new(InMemory::new());
+        fs.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        fs.mkdir(req(), path("/d"), name("e"), 0o755).unwrap();
+        assert_eq!(fs.getattr(req(), path("/d"), None).unwrap().1.nlink, 3);
+        assert_eq!(fs.rmdir(req(), path("/"), name("d")), Err(Errno::ENOTEMPTY));
+        create(&fs, "/d", "f", b"");
+        assert_eq!(fs.rmdir(req(), path("/d"), name("f")), Err(Errno::ENOTDIR));
+        fs.rmdir(req(), path("/d"), name("e")).unwrap();
+        assert_eq!(fs.rmdir(req(), path("/"), name("d")), Err(Errno::ENOTEMPTY));
+        fs.unlink(req(), path("/d"), name("f")).unwrap();
+        fs.rmdir(req(), path("/"), name("d")).unwrap();
+        assert_eq!(fs.getattr(req(), path("/"), None).unwrap().1.nlink, 2);
+    }
+
+    #[test]
+    fn xattr_flags() {
+        let fs = MemFs::new(InMemory::new());
+        let setxattr = |value: &[u8], flags| fs.setxattr(req(), path("/"), name("user.a"), value, flags, 0);
+        assert_eq!(setxattr(b"1", XATTR_REPLACE), Err(Errno::ENODATA));
+        setxattr(b"1", XATTR_CREATE).unwrap();
+        assert_eq!(setxattr(b"2", XATTR_CREATE), Err(Errno::EEXIST));
+        setxattr(b"22", XATTR_REPLACE).unwrap();
+        match fs.getxattr(req(), path("/"), name("user.a"), 0) {
+            Ok(Xattr::Size(2)) => {}
+            _ => panic!("wrong xattr size"),
+        }
+        match fs.getxattr(req(), path("/"), name("user.a"), 2) {
+            Ok(Xattr::Data(ref value)) if value == b"22" => {}
+            _ => panic!("wrong xattr value"),
+        }
+        fs.removexattr(req(), path("/"), name("user.a")).unwrap();
+        assert_eq!(fs.removexattr(req(), path("/"), name("user.a")), Err(Errno::ENODATA));
+    }
+
+    #[test]
+    fn limits() {
+        let fs = MemFs::new(InMemory::with_limits(3 * BLOCK_SIZE, 4));
+        let fl = create(&fs, "/", "f", &[1; 5000]);
+        assert_eq!(used_blocks(&fs), 2);
+        let write = |offset: u64, len| fs.write(req(), path("/f"), &fl, offset, vec![2; len], 0);
+        assert_eq!(write(5000, 8000), Err(Errno::ENOSPC));
+        assert_eq!(write(0, 6000), Ok(6000));
+        assert_eq!(fs.truncate(req(), path("/f"), Some(&fl), 20000), Err(Errno::ENOSPC));
+        assert_eq!(fs.getattr(req(), path("/f"), None).unwrap().1.size, 6000);
+        fs.truncate(req(), path("/f"), None, 3 * BLOCK_SIZE).unwrap();
+        let err = fs.symlink(req(), path("/"), name("s"), path("target")).err();
+        assert_eq!(err, Some(Errno::ENOSPC));
+        fs.truncate(req(), path("/f"), None, 0).unwrap();
+        assert_eq!(used_blocks(&fs), 0);
+        // The root and "f" take two of the inodes.
+        fs.symlink(req(), path("/"), name("s"), path("target")).unwrap();
+        fs.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        assert_eq!(used_blocks(&fs), 1);
+        assert_eq!(fs.mkdir(req(), path("/"), name("e"), 0o755).err(), Some(Errno::ENOSPC));
+        let err = fs.create(req(), path("/"), name("g"), 0o644, AccessMode::WriteOnly.into()).err();
+        assert_eq!(err, Some(Errno::ENOSPC));
+        let err = fs.mknod(req(), path("/"), name("p"), libc::S_IFIFO | 0o644, 0).err();
+        assert_eq!(err, Some(Errno::ENOSPC));
+        let statfs = fs.statfs(req(), path("/")).unwrap();
+        assert_eq!((statfs.files, statfs.ffree), (4, 0));
+        fs.unlink(req(), path("/"), name("s")).unwrap();
+        assert_eq!(used_blocks(&fs), 0);
+        fs.mknod(req(), path("/"), name("p"), libc::S_IFIFO | 0o644, 0).unwrap();
+    }
+}
@@ -0,0 +1,569 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A wrapper collecting metrics of the calls of a `FilesystemFL`: per call, the number of calls,
+//! of errors by error number and a histogram of the latencies; and the number of bytes read and
+//! written, and of open files and directories.
+//! The metrics are read through a `MetricsHandle`, which can be kept by the embedding program
+//! (e.g. for exporting them periodically) after the filesystem is mounted.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libc::c_int;
+use time::Timespec;
+
+use super::flags::OpenFlags;
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite, ResultReaddir,
+            ResultStatfs, ResultXattr};
+
+const BUCKETS: usize = 32;
+
+/// A histogram of latencies, with buckets of powers of 2 microseconds: bucket `i` counts the
+/// latencies under `2^i` microseconds (and not under the limit of the previous bucket), and the
+/// last bucket counts all the longer ones too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Count a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = (128 - latency.as_micros().leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The counts of the buckets.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// The upper limit (exclusive) of the latencies counted in the given bucket, except for the
+    /// last one, which has none.
+    pub fn bucket_limit(bucket: usize) -> Duration {
+        Duration::from_micros(1 << bucket)
+    }
+
+    /// The number of latencies counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The sum of the latencies counted.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The longest latency counted.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The mean of the latencies counted.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::from_secs(0),
+            count => Duration::from_nanos((self.sum.as_nanos() / u128::from(count)) as u64),
+        }
+    }
+
+    /// An estimate of the given percentile (between 0 and 100) of the latencies counted: the
+    /// limit of the bucket it falls in, or the longest latency if that is shorter.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let rank = (self.count() as f64 * percentile / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets[..BUCKETS - 1].iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return LatencyHistogram::bucket_limit(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The metrics of one call of `FilesystemFL`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpMetrics {
+    /// The number of calls.
+    pub calls: u64,
+    /// The number of failed calls, by error number (see `Errno::name`).
+    pub errors: BTreeMap<c_int, u64>,
+    /// The latencies of the calls.
+    pub latency: LatencyHistogram,
+}
+
+impl OpMetrics {
+    /// The number of failed calls.
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+/// The metrics collected by `Metered`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The metrics of each call, by its name (e.g. `"read"`); calls which weren't made yet are
+    /// missing.
+    pub ops: BTreeMap<&'static str, OpMetrics>,
+    /// The number of bytes read.
+    pub bytes_read: u64,
+    /// The number of bytes written.
+    pub bytes_written: u64,
+    /// The number of open files, i.e. opened (or created) and not yet released.
+    pub open_files: usize,
+    /// The number of open directories.
+    pub open_dirs: usize,
+}
+
+/// A handle to the metrics collected by a `Metered`, for reading them from anywhere.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsHandle(Arc<Mutex<Metrics>>);
+
+impl MetricsHandle {
+    /// A snapshot of the metrics collected so far.
+    pub fn snapshot(&self) -> Metrics {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Clear the metrics collected so far, returning them; the numbers of open files and
+    /// directories are kept.
+    pub fn reset(&self) -> Metrics {
+        let mut metrics = self.0.lock().unwrap();
+        let open_files = metrics.open_files;
+        let open_dirs = metrics.open_dirs;
+        ::std::mem::replace(&mut *metrics,
+                            Metrics { open_files, open_dirs, ..Metrics::default() })
+    }
+}
+
+/// A `FilesystemFL` forwarding all calls to `T`, and collecting metrics of them.
+#[derive(Debug)]
+pub struct Metered<T> {
+    inner: T,
+    metrics: MetricsHandle,
+}
+
+impl<T> Metered<T> where T: FilesystemFL {
+    /// Wrap the given filesystem.
+    pub fn new(inner: T) -> Metered<T> {
+        Metered { inner, metrics: MetricsHandle::default() }
+    }
+
+    /// A handle to the metrics collected.
+    pub fn metrics(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// The wrapped filesystem.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn call<R, E, F>(&self, op: &'static str, f: F) -> Result<R, E>
+        where F: FnOnce() -> Result<R, E>,
+              E: Clone + Into<Errno>
+    {
+        let start = Instant::now();
+        let res = f();
+        let latency = start.elapsed();
+        let mut metrics = (self.metrics.0).lock().unwrap();
+        let op = metrics.ops.entry(op).or_default();
+        op.calls += 1;
+        op.latency.record(latency);
+        if let Err(ref e) = res {
+            *op.errors.entry(e.clone().into().code()).or_insert(0) += 1;
+        }
+        res
+    }
+
+    fn count<F>(&self, f: F) where F: FnOnce(&mut Metrics) {
+        f(&mut (self.metrics.0).lock().unwrap())
+    }
+}
+
+impl<T> FilesystemFL for Metered<T> where T: FilesystemFL {
+    type FileLike = T::FileLike;
+    type DirLike = T::DirLike;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.call("init", || self.inner.init(_req))
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        let _: ResultEmpty = self.call("destroy", || {
+            self.inner.destroy(_req);
+            Ok(())
+        });
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.call("lookup", || self.inner.lookup(_req, _parent, _name))
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        self.call("getattr", || self.inner.getattr(_req, _path, _fl))
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        self.call("chmod", || self.inner.chmod(_req, _path, _fl, _mode))
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        self.call("chown", || self.inner.chown(_req, _path, _fl, _uid, _gid))
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        self.call("truncate", || self.inner.truncate(_req, _path, _fl, _size))
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        self.call("utimens", || self.inner.utimens(_req, _path, _fl, _atime, _mtime))
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        self.call("utimens_macos", || {
+            self.inner.utimens_macos(_req, _path, _fl, _crtime, _chgtime, _bkuptime, _flags)
+        })
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.call("readlink", || self.inner.readlink(_req, _path))
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        self.call("mknod", || self.inner.mknod(_req, _parent, _name, _mode, _rdev))
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        self.call("mkdir", || self.inner.mkdir(_req, _parent, _name, _mode))
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("unlink", || self.inner.unlink(_req, _parent, _name))
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("rmdir", || self.inner.rmdir(_req, _parent, _name))
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        self.call("symlink", || self.inner.symlink(_req, _parent, _name, _target))
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        self.call("rename", || self.inner.rename(_req, _parent, _name, _newparent, _newname))
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.call("link", || self.inner.link(_req, _path, _newparent, _newname))
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        let res = self.call("open", || self.inner.open(_req, _path, _flags));
+        if res.is_ok() {
+            self.count(|m| m.open_files += 1);
+        }
+        res
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        let res = self.call("read", || self.inner.read(_req, _path, _fl, _offset, _size));
+        if let Ok(ref data) = res {
+            self.count(|m| m.bytes_read += data.len() as u64);
+        }
+        res
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        let res = self.call("write", || self.inner.write(_req, _path, _fl, _offset, _data, _flags));
+        if let Ok(written) = res {
+            self.count(|m| m.bytes_written += u64::from(written));
+        }
+        res
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        self.call("flush", || self.inner.flush(_req, _path, _fl, _lock_owner))
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        // The handler is gone regardless of the result.
+        self.count(|m| m.open_files = m.open_files.saturating_sub(1));
+        self.call("release",
+                  || self.inner.release(_req, _path, _fl, _flags, _lock_owner, _flush))
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        self.call("fsync", || self.inner.fsync(_req, _path, _fl, _datasync))
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        let res = self.call("opendir", || self.inner.opendir(_req, _path, _flags));
+        if res.is_ok() {
+            self.count(|m| m.open_dirs += 1);
+        }
+        res
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        self.call("readdir", || self.inner.readdir(_req, _path, _dl))
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        self.count(|m| m.open_dirs = m.open_dirs.saturating_sub(1));
+        self.call("releasedir", || self.inner.releasedir(_req, _path, _dl, _flags))
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        self.call("fsyncdir", || self.inner.fsyncdir(_req, _path, _dl, _datasync))
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.call("statfs", || self.inner.statfs(_req, _path))
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.call("setxattr",
+                  || self.inner.setxattr(_req, _path, _name, _value, _flags, _position))
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.call("getxattr", || self.inner.getxattr(_req, _path, _name, _size))
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.call("listxattr", || self.inner.listxattr(_req, _path, _size))
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("removexattr", || self.inner.removexattr(_req, _path, _name))
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.call("access", || self.inner.access(_req, _path, _mask))
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        let res = self.call("create", || self.inner.create(_req, _parent, _name, _mode, _flags));
+        if res.is_ok() {
+            self.count(|m| m.open_files += 1);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use super::super::flags::AccessMode;
+    use super::super::memfs::{InMemory, MemFs};
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    fn micros(micros: u64) -> Duration {
+        Duration::from_micros(micros)
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut hist = LatencyHistogram::default();
+        for &latency in &[0, 1, 3, 4, 1000, 1 << 40] {
+            hist.record(micros(latency));
+        }
+        let mut buckets = [0; BUCKETS];
+        for &bucket in &[0, 1, 2, 3, 10, BUCKETS - 1] {
+            buckets[bucket] = 1;
+        }
+        assert_eq!(hist.buckets(), &buckets[..]);
+        assert_eq!(LatencyHistogram::bucket_limit(2), micros(4));
+        assert_eq!(LatencyHistogram::bucket_limit(10), micros(1024));
+        assert_eq!(hist.count(), 6);
+        assert_eq!(hist.max(), micros(1 << 40));
+        assert_eq!(hist.sum(), micros(1008 + (1 << 40)));
+    }
+
+    #[test]
+    fn histogram_stats() {
+        let mut hist = LatencyHistogram::default();
+        assert_eq!((hist.mean(), hist.percentile(50.0)), (micros(0), micros(0)));
+        for _ in 0..9 {
+            hist.record(micros(10));
+        }
+        hist.record(micros(1000));
+        assert_eq!(hist.mean(), micros(109));
+        assert_eq!(hist.percentile(0.0), micros(16));
+        assert_eq!(hist.percentile(50.0), micros(16));
+        assert_eq!(hist.percentile(90.0), micros(16));
+        // The limit of the last bucket is beyond the longest latency.
+        assert_eq!(hist.percentile(95.0), micros(1000));
+        assert_eq!(hist.percentile(100.0), micros(1000));
+    }
+
+    #[test]
+    fn counts_calls_and_handlers() {
+        let fs = Metered::new(MemFs::new(InMemory::new()));
+        let metrics = fs.metrics();
+        let flags = OpenFlags::from(AccessMode::ReadWrite);
+        let created = fs.create(req(), path("/"), OsStr::new("f"), 0o644, flags).unwrap();
+        fs.write(req(), path("/f"), &created.fl, 0, b"hello".to_vec(), 0).unwrap();
+        let (fl, _) = fs.open(req(), path("/f"), flags).unwrap();
+        assert_eq!(fs.read(req(), path("/f"), &fl, 1, 10).unwrap(), b"ello");
+        assert_eq!(fs.open(req(), path("/g"), flags).err(), Some(Errno::ENOENT));
+        let (dl, _) = fs.opendir(req(), path("/"), flags).unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.open_files, snapshot.open_dirs), (2, 1));
+        assert_eq!((snapshot.bytes_read, snapshot.bytes_written), (4, 5));
+        let open = &snapshot.ops["open"];
+        assert_eq!((open.calls, open.error_count(), open.latency.count()), (2, 1, 2));
+        assert_eq!(open.errors.get(&libc::ENOENT), Some(&1));
+
+        fs.release(req(), path("/f"), created.fl, flags, 0, true).unwrap();
+        fs.releasedir(req(), path("/"), dl, flags).unwrap();
+        // Resetting keeps the numbers of open handlers.
+        let before = metrics.reset();
+        assert_eq!((before.open_files, before.open_dirs), (1, 0));
+        assert_eq!(before.ops["release"].calls, 1);
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.ops.is_empty());
+        assert_eq!((snapshot.open_files, snapshot.bytes_read), (1, 0));
+        fs.release(req(), path("/f"), fl, flags, 0, true).unwrap();
+        assert_eq!(metrics.snapshot().open_files, 0);
+    }
+}
@@ -0,0 +1,994 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A filesystem composed of several others, each mounted at a path of it.
+//! Every call is routed to the filesystem mounted at the longest prefix of its path, with the path
+//! rewritten to be relative to the mount point (so the mount point itself is "/" there). The
+//! directories leading to the mount points (if not served by another filesystem, e.g. one mounted
+//! at "/" without them) are synthesized: they list the mount points under them, and can't be
+//! modified.
+//! Mount points (and the directories leading to them) can't be removed or renamed, failing with
+//! `EBUSY`, and `rename` and `link` between different filesystems fail with `EXDEV`.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use libc;
+use time::{self, Timespec};
+
+use super::flags::{OpenFlags, OpenReplyFlags};
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite,
+            ResultReaddir, ResultStatfs, ResultXattr};
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+// The handlers of the mounted filesystems, with their types erased.
+type AnyHandle = Box<dyn Any + Send + Sync>;
+
+type BoxedFs = Box<dyn FilesystemFL<FileLike = AnyHandle, DirLike = AnyHandle> + Send + Sync>;
+
+struct Mount {
+    path: PathBuf,
+    fs: BoxedFs,
+}
+
+/// A `FilesystemFL` serving several filesystems, each mounted at a path of it, see the module docs.
+pub struct MuxFs {
+    mounts: Vec<Mount>,
+    // The attributes of the synthesized directories.
+    dir_attr: FileAttr,
+}
+
+/// The file handler of `MuxFs`: the file handler of the filesystem the file was opened in.
+#[derive(Debug)]
+pub struct MuxFile {
+    child: usize,
+    fl: AnyHandle,
+}
+
+/// The directory handler of `MuxFs`.
+#[derive(Debug)]
+pub enum MuxDir {
+    /// A directory opened in one of the mounted filesystems.
+    Child(usize, Box<dyn Any + Send + Sync>),
+    /// A synthesized directory.
+    Synthetic,
+}
+
+enum Route {
+    Child(usize, PathBuf),
+    Synthetic,
+}
+
+impl MuxFs {
+    /// A new `MuxFs`, with nothing mounted yet.
+    pub fn new() -> MuxFs {
+        let now = time::get_time();
+        MuxFs {
+            mounts: vec![],
+            dir_attr: FileAttr {
+                ino: 0,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o555,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            },
+        }
+    }
+
+    /// Mount `fs` at `path`, which must be absolute (e.g. "/" or "/data/a"). It then serves `path`
+    /// and everything under it, except for what is served by filesystems mounted under it.
+    /// Fails with `EINVAL` if `path` isn't absolute or has "." or ".." components, and with
+    /// `EBUSY` if another filesystem is already mounted at it.
+    pub fn mount<P, T>(&mut self, path: P, fs: T) -> Result<()>
+        where P: AsRef<Path>,
+              T: FilesystemFL + Send + Sync + 'static,
+              T::FileLike: Send + Sync,
+              T::DirLike: Send + Sync
+    {
+        let path = path.as_ref();
+        if !path.has_root() ||
+           path.components().any(|c| !matches!(c, Component::RootDir | Component::Normal(_))) {
+            return Err(Errno::EINVAL);
+        }
+        if self.mounts.iter().any(|m| m.path == path) {
+            return Err(Errno::EBUSY);
+        }
+        self.mounts.push(Mount { path: path.to_path_buf(), fs: Box::new(Erased(fs)) });
+        Ok(())
+    }
+
+    fn fs(&self, child: usize) -> &BoxedFs {
+        &self.mounts[child].fs
+    }
+
+    // The path in the given child of a path under its mount point.
+    fn child_path(&self, child: usize, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.mounts[child].path) {
+            Ok(rest) => Path::new("/").join(rest),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    // Whether there is a mount point at or under the given path.
+    fn is_busy(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|m| m.path.starts_with(path))
+    }
+
+    // Whether the given path leads to a mount point, without being one itself.
+    fn leads_to_mount(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|m| m.path.starts_with(path) && m.path != path)
+    }
+
+    fn route(&self, path: &Path) -> Result<Route> {
+        let child = self.mounts
+            .iter()
+            .enumerate()
+            .filter(|&(_, m)| path.starts_with(&m.path))
+            .max_by_key(|&(_, m)| m.path.components().count())
+            .map(|(i, _)| i);
+        match child {
+            Some(i) => Ok(Route::Child(i, self.child_path(i, path))),
+            None if path.parent().is_none() || self.is_busy(path) => Ok(Route::Synthetic),
+            None => Err(Errno::ENOENT),
+        }
+    }
+
+    // Route a call on an existing path to `child`, with the child and the path in it, or to
+    // `synthetic` for synthesized directories. These include the directories leading to mount
+    // points which the filesystem serving them doesn't have.
+    fn route_lookup<T, F, S>(&self, path: &Path, child: F, synthetic: S) -> Result<T>
+        where F: FnOnce(usize, &Path) -> Result<T>,
+              S: FnOnce() -> Result<T>
+    {
+        match self.route(path)? {
+            Route::Child(i, child_path) => {
+                match child(i, &child_path) {
+                    Err(ref e) if *e == Errno::ENOENT && self.leads_to_mount(path) => synthetic(),
+                    res => res,
+                }
+            }
+            Route::Synthetic => synthetic(),
+        }
+    }
+
+    // Route a call on an existing path, failing with `synthetic` for synthesized directories.
+    fn route_child(&self, path: &Path, synthetic: Errno) -> Result<(usize, PathBuf)> {
+        match self.route(path)? {
+            Route::Child(i, path) => Ok((i, path)),
+            Route::Synthetic => Err(synthetic),
+        }
+    }
+
+    // Route a call on a path or an open file.
+    fn route_attr(&self,
+                  path: &Path,
+                  fl: Option<&MuxFile>,
+                  synthetic: Errno)
+                  -> Result<(usize, PathBuf)> {
+        match fl {
+            Some(fl) => Ok((fl.child, self.child_path(fl.child, path))),
+            None => self.route_child(path, synthetic),
+        }
+    }
+
+    // Route a call creating, removing or renaming an entry, returning the child and the path of
+    // the parent in it. Fails with `busy` for mount points and the directories leading to them.
+    fn route_entry(&self,
+                   parent: &Path,
+                   name: &OsStr,
+                   busy: Errno)
+                   -> Result<(usize, PathBuf)> {
+        if self.is_busy(&parent.join(name)) {
+            return Err(busy);
+        }
+        self.route_child(parent, Errno::EACCES)
+    }
+}
+
+impl Default for MuxFs {
+    fn default() -> MuxFs {
+        MuxFs::new()
+    }
+}
+
+impl fmt::Debug for MuxFs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mounts: Vec<_> = self.mounts.iter().map(|m| &m.path).collect();
+        f.debug_struct("MuxFs").field("mounts", &mounts).finish()
+    }
+}
+
+fn dir_entry(name: &OsStr) -> DirectoryEntry {
+    DirectoryEntry { name: name.to_os_string(), kind: FileType::Directory }
+}
+
+impl FilesystemFL for MuxFs {
+    type FileLike = MuxFile;
+    type DirLike = MuxDir;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        let mut res = Ok(());
+        for m in &self.mounts {
+            res = res.and(m.fs.init(clone_req(&_req)));
+        }
+        res
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        for m in &self.mounts {
+            m.fs.destroy(clone_req(&_req));
+        }
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.route_lookup(&_parent.join(_name),
+                          |i, path| match path.parent() {
+                              Some(parent) => self.fs(i).lookup(_req, parent, _name),
+                              // The mount point itself.
+                              None => self.fs(i).getattr(_req, path, None),
+                          },
+                          || Ok((TTL, self.dir_attr)))
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        match _fl {
+            Some(fl) => {
+                let path = self.child_path(fl.child, _path);
+                self.fs(fl.child).getattr(_req, &path, Some(&fl.fl))
+            }
+            None => {
+                self.route_lookup(_path,
+                                  |i, path| self.fs(i).getattr(_req, path, None),
+                                  || Ok((TTL, self.dir_attr)))
+            }
+        }
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        let (i, path) = self.route_attr(_path, _fl, Errno::EPERM)?;
+        self.fs(i).chmod(_req, &path, _fl.map(|fl| &fl.fl), _mode)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        let (i, path) = self.route_attr(_path, _fl, Errno::EPERM)?;
+        self.fs(i).chown(_req, &path, _fl.map(|fl| &fl.fl), _uid, _gid)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        let (i, path) = self.route_attr(_path, _fl, Errno::EISDIR)?;
+        self.fs(i).truncate(_req, &path, _fl.map(|fl| &fl.fl), _size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        let (i, path) = self.route_attr(_path, _fl, Errno::EPERM)?;
+        self.fs(i).utimens(_req, &path, _fl.map(|fl| &fl.fl), _atime, _mtime)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        let (i, path) = self.route_attr(_path, _fl, Errno::EPERM)?;
+        self.fs(i).utimens_macos(_req,
+                                 &path,
+                                 _fl.map(|fl| &fl.fl),
+                                 _crtime,
+                                 _chgtime,
+                                 _bkuptime,
+                                 _flags)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        let (i, path) = self.route_child(_path, Errno::EINVAL)?;
+        self.fs(i).readlink(_req, &path)
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EEXIST)?;
+        self.fs(i).mknod(_req, &parent, _name, _mode, _rdev)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EEXIST)?;
+        self.fs(i).mkdir(_req, &parent, _name, _mode)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EBUSY)?;
+        self.fs(i).unlink(_req, &parent, _name)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EBUSY)?;
+        self.fs(i).rmdir(_req, &parent, _name)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EEXIST)?;
+        self.fs(i).symlink(_req, &parent, _name, _target)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EBUSY)?;
+        let (j, newparent) = self.route_entry(_newparent, _newname, Errno::EBUSY)?;
+        if i != j {
+            return Err(Errno::EXDEV);
+        }
+        self.fs(i).rename(_req, &parent, _name, &newparent, _newname)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        let (i, path) = self.route_child(_path, Errno::EPERM)?;
+        let (j, newparent) = self.route_entry(_newparent, _newname, Errno::EEXIST)?;
+        if i != j {
+            return Err(Errno::EXDEV);
+        }
+        self.fs(i).link(_req, &path, &newparent, _newname)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        let (i, path) = self.route_child(_path, Errno::EISDIR)?;
+        map_res_open(self.fs(i).open(_req, &path, _flags), |fl| MuxFile { child: i, fl })
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        let path = self.child_path(_fl.child, _path);
+        self.fs(_fl.child).read(_req, &path, &_fl.fl, _offset, _size)
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        let path = self.child_path(_fl.child, _path);
+        self.fs(_fl.child).write(_req, &path, &_fl.fl, _offset, _data, _flags)
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        let path = self.child_path(_fl.child, _path);
+        self.fs(_fl.child).flush(_req, &path, &_fl.fl, _lock_owner)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        let path = self.child_path(_fl.child, _path);
+        self.fs(_fl.child).release(_req, &path, _fl.fl, _flags, _lock_owner, _flush)
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        let path = self.child_path(_fl.child, _path);
+        self.fs(_fl.child).fsync(_req, &path, &_fl.fl, _datasync)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        self.route_lookup(_path,
+                          |i, path| {
+                              map_res_open(self.fs(i).opendir(_req, path, _flags),
+                                           |dl| MuxDir::Child(i, dl))
+                          },
+                          || Ok((MuxDir::Synthetic, OpenReplyFlags::empty())))
+    }
+
+    /// The entries of the directory, along with the mount points (or the directories leading to
+    /// them) directly under it.
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        let mut entries = match *_dl {
+            MuxDir::Child(i, ref dl) => {
+                self.fs(i).readdir(_req, &self.child_path(i, _path), dl)?
+            }
+            MuxDir::Synthetic => vec![dir_entry(OsStr::new(".")), dir_entry(OsStr::new(".."))],
+        };
+        let mut names: HashSet<OsString> = entries.iter().map(|e| e.name.clone()).collect();
+        for m in &self.mounts {
+            let next = m.path.strip_prefix(_path).ok().and_then(|rest| rest.components().next());
+            if let Some(Component::Normal(name)) = next {
+                if names.insert(name.to_os_string()) {
+                    entries.push(dir_entry(name));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        match _dl {
+            MuxDir::Child(i, dl) => {
+                self.fs(i).releasedir(_req, &self.child_path(i, _path), dl, _flags)
+            }
+            MuxDir::Synthetic => Ok(()),
+        }
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        match *_dl {
+            MuxDir::Child(i, ref dl) => {
+                self.fs(i).fsyncdir(_req, &self.child_path(i, _path), dl, _datasync)
+            }
+            MuxDir::Synthetic => Ok(()),
+        }
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.route_lookup(_path, |i, path| self.fs(i).statfs(_req, path), || {
+            Ok(Statfs {
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                bsize: 512,
+                namelen: 255,
+                frsize: 512,
+            })
+        })
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        let (i, path) = self.route_child(_path, Errno::EPERM)?;
+        self.fs(i).setxattr(_req, &path, _name, _value, _flags, _position)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        let (i, path) = self.route_child(_path, Errno::ENODATA)?;
+        self.fs(i).getxattr(_req, &path, _name, _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.route_lookup(_path, |i, path| self.fs(i).listxattr(_req, path, _size), || {
+            if _size == 0 { Ok(Xattr::Size(0)) } else { Ok(Xattr::Data(vec![])) }
+        })
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        let (i, path) = self.route_child(_path, Errno::EPERM)?;
+        self.fs(i).removexattr(_req, &path, _name)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.route_lookup(_path, |i, path| self.fs(i).access(_req, path, _mask), || {
+            if _mask & libc::W_OK as u32 != 0 { Err(Errno::EACCES) } else { Ok(()) }
+        })
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        let (i, parent) = self.route_entry(_parent, _name, Errno::EEXIST)?;
+        map_res_create(self.fs(i).create(_req, &parent, _name, _mode, _flags),
+                       |fl| MuxFile { child: i, fl })
+    }
+}
+
+// Adaptor erasing the types of the handlers of a mounted filesystem; handlers of another type
+// (which can't be passed by `MuxFs`) fail with `EBADF`.
+struct Erased<T>(T);
+
+fn handler<T: Any>(handle: &AnyHandle) -> Result<&T> {
+    handle.downcast_ref().ok_or(Errno::EBADF)
+}
+
+fn into_handler<T: Any>(handle: AnyHandle) -> Result<T> {
+    match handle.downcast() {
+        Ok(handler) => Ok(*handler),
+        Err(_) => Err(Errno::EBADF),
+    }
+}
+
+fn erase<T: Any + Send + Sync>(handler: T) -> AnyHandle {
+    Box::new(handler)
+}
+
+impl<T> FilesystemFL for Erased<T>
+    where T: FilesystemFL,
+          T::FileLike: Any + Send + Sync,
+          T::DirLike: Any + Send + Sync
+{
+    type FileLike = AnyHandle;
+    type DirLike = AnyHandle;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.0.init(_req)
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        self.0.destroy(_req)
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.0.lookup(_req, _parent, _name)
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.getattr(_req, _path, fl)
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.chmod(_req, _path, fl, _mode)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.chown(_req, _path, fl, _uid, _gid)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.truncate(_req, _path, fl, _size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.utimens(_req, _path, fl, _atime, _mtime)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        let fl = match _fl {
+            Some(fl) => Some(handler(fl)?),
+            None => None,
+        };
+        self.0.utimens_macos(_req, _path, fl, _crtime, _chgtime, _bkuptime, _flags)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.0.readlink(_req, _path)
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        self.0.mknod(_req, _parent, _name, _mode, _rdev)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        self.0.mkdir(_req, _parent, _name, _mode)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.0.unlink(_req, _parent, _name)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.0.rmdir(_req, _parent, _name)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        self.0.symlink(_req, _parent, _name, _target)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        self.0.rename(_req, _parent, _name, _newparent, _newname)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.0.link(_req, _path, _newparent, _newname)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        map_res_open(self.0.open(_req, _path, _flags), erase)
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        self.0.read(_req, _path, handler(_fl)?, _offset, _size)
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        self.0.write(_req, _path, handler(_fl)?, _offset, _data, _flags)
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        self.0.flush(_req, _path, handler(_fl)?, _lock_owner)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        self.0.release(_req, _path, into_handler(_fl)?, _flags, _lock_owner, _flush)
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        self.0.fsync(_req, _path, handler(_fl)?, _datasync)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        map_res_open(self.0.opendir(_req, _path, _flags), erase)
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        self.0.readdir(_req, _path, handler(_dl)?)
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        self.0.releasedir(_req, _path, into_handler(_dl)?, _flags)
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        self.0.fsyncdir(_req, _path, handler(_dl)?, _datasync)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.0.statfs(_req, _path)
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.0.setxattr(_req, _path, _name, _value, _flags, _position)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.0.getxattr(_req, _path, _name, _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.0.listxattr(_req, _path, _size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.0.removexattr(_req, _path, _name)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.0.access(_req, _path, _mask)
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        map_res_create(self.0.create(_req, _parent, _name, _mode, _flags), erase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::flags::AccessMode;
+    use super::super::memfs::{InMemory, MemFs};
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    fn mux() -> MuxFs {
+        let mut fs = MuxFs::new();
+        fs.mount("/", MemFs::new(InMemory::new())).unwrap();
+        fs.mount("/data/a", MemFs::new(InMemory::new())).unwrap();
+        fs
+    }
+
+    fn list(fs: &MuxFs, dir: &str) -> Vec<String> {
+        let dl = fs.opendir(req(), path(dir), AccessMode::ReadOnly.into()).unwrap().0;
+        let entries = fs.readdir(req(), path(dir), &dl).unwrap();
+        fs.releasedir(req(), path(dir), dl, AccessMode::ReadOnly.into()).unwrap();
+        let mut names: Vec<_> =
+            entries.into_iter().map(|e| e.name.into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn mount_points() {
+        let mut fs = mux();
+        let memfs = || MemFs::new(InMemory::new());
+        assert_eq!(fs.mount("data", memfs()), Err(Errno::EINVAL));
+        assert_eq!(fs.mount("/data/../b", memfs()), Err(Errno::EINVAL));
+        assert_eq!(fs.mount("/data/a", memfs()), Err(Errno::EBUSY));
+    }
+
+    #[test]
+    fn routing() {
+        let fs = mux();
+        let flags = AccessMode::ReadWrite.into();
+        let created = fs.create(req(), path("/data/a"), name("f"), 0o644, flags).unwrap();
+        assert_eq!(created.fl.child, 1);
+        fs.write(req(), path("/data/a/f"), &created.fl, 0, b"hello".to_vec(), 0).unwrap();
+        let fl = fs.open(req(), path("/data/a/f"), flags).unwrap().0;
+        assert_eq!(fs.read(req(), path("/data/a/f"), &fl, 0, 10), Ok(b"hello".to_vec()));
+        // The file is at the root of the child mounted at "/data/a", and not in the one at "/".
+        let child_root = fs.fs(1).lookup(req(), path("/"), name("f")).unwrap().1;
+        assert_eq!(child_root.size, 5);
+        assert_eq!(fs.fs(0).getattr(req(), path("/data/a/f"), None).err(), Some(Errno::ENOENT));
+        fs.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        assert_eq!(list(&fs, "/"), [".", "..", "d", "data"]);
+        assert_eq!(list(&fs, "/data/a"), [".", "..", "f"]);
+    }
+
+    #[test]
+    fn synthesized_dirs() {
+        let fs = mux();
+        // "/data" isn't in the filesystem mounted at "/", so it is synthesized.
+        let attr = fs.lookup(req(), path("/"), name("data")).unwrap().1;
+        assert_eq!((attr.kind, attr.perm), (FileType::Directory, 0o555));
+        assert_eq!(fs.getattr(req(), path("/data"), None).unwrap().1.perm, 0o555);
+        assert_eq!(list(&fs, "/data"), [".", "..", "a"]);
+        assert_eq!(fs.access(req(), path("/data"), libc::W_OK as u32), Err(Errno::EACCES));
+        assert_eq!(fs.rmdir(req(), path("/"), name("data")), Err(Errno::EBUSY));
+        assert_eq!(fs.rmdir(req(), path("/data"), name("a")), Err(Errno::EBUSY));
+        assert_eq!(fs.getattr(req(), path("/other"), None).err(), Some(Errno::ENOENT));
+        assert_eq!(fs.mkdir(req(), path("/"), name("data"), 0o700).err(), Some(Errno::EEXIST));
+        // When it is, it is served from there.
+        let root = MemFs::new(InMemory::new());
+        root.mkdir(req(), path("/"), name("data"), 0o700).unwrap();
+        let mut fs = MuxFs::new();
+        fs.mount("/", root).unwrap();
+        fs.mount("/data/a", MemFs::new(InMemory::new())).unwrap();
+        assert_eq!(fs.getattr(req(), path("/data"), None).unwrap().1.perm, 0o700);
+        fs.mkdir(req(), path("/data"), name("b"), 0o755).unwrap();
+        assert_eq!(list(&fs, "/data"), [".", "..", "a", "b"]);
+    }
+
+    #[test]
+    fn cross_mount_renames() {
+        let fs = mux();
+        let flags = AccessMode::ReadWrite.into();
+        fs.create(req(), path("/"), name("f"), 0o644, flags).unwrap();
+        fs.create(req(), path("/data/a"), name("g"), 0o644, flags).unwrap();
+        let rename = |parent, name_, newparent, newname| {
+            fs.rename(req(), path(parent), name(name_), path(newparent), name(newname))
+        };
+        assert_eq!(rename("/", "f", "/data/a", "f"), Err(Errno::EXDEV));
+        assert_eq!(rename("/data/a", "g", "/", "g"), Err(Errno::EXDEV));
+        assert_eq!(rename("/", "data", "/", "x"), Err(Errno::EBUSY));
+        let err = fs.link(req(), path("/f"), path("/data/a"), name("f")).err();
+        assert_eq!(err, Some(Errno::EXDEV));
+        rename("/data/a", "g", "/data/a", "h").unwrap();
+        fs.link(req(), path("/f"), path("/"), name("f2")).unwrap();
+        assert_eq!(list(&fs, "/data/a"), [".", "..", "h"]);
+    }
+}
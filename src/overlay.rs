@@ -0,0 +1,1004 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A union of two filesystems, like overlayfs: a writable upper layer over a lower one, which is
+//! never modified.
+//! Entries are looked up in the upper layer first, falling through to the lower one. Modifying an
+//! entry of the lower layer first copies it up (along with its parent directories) into the upper
+//! one. Removing an entry of the lower layer creates a whiteout for it in the upper one: an empty
+//! file named `.wh.<name>`. A directory created (or renamed) over a whiteout is made opaque,
+//! hiding the lower directory of the same name, by an empty `.wh..wh..opq` file in it. Names
+//! starting with `.wh.` are therefore reserved.
+//! NOTE: renaming a directory which exists in the lower layer (or onto one) fails with `EXDEV`,
+//! like overlayfs does without `redirect_dir`, so tools like `mv` fall back to copying. Hard links
+//! of the lower layer are broken by copying up.
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use libc;
+use time::Timespec;
+
+use super::flags::{AccessMode, OpenFlags};
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite,
+            ResultReaddir, ResultStatfs, ResultXattr};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_NAME: &str = ".wh..wh..opq";
+const COPY_CHUNK: u32 = 128 * 1024;
+
+/// A `FilesystemFL` layering `Upper` over `Lower`, see the module docs.
+#[derive(Debug)]
+pub struct OverlayFs<U, L> {
+    upper: U,
+    lower: L,
+    // Serializes copying up, so that an entry is only copied once.
+    copy_up_lock: Mutex<()>,
+}
+
+/// The file handler of `OverlayFs`: a file of the lower layer is only opened there when opened
+/// read-only, otherwise it is copied up first.
+#[derive(Debug)]
+pub enum OverlayFile<U, L> {
+    /// A file opened in the upper layer.
+    Upper(U),
+    /// A file opened (read-only) in the lower layer.
+    Lower(L),
+}
+
+/// The directory handler of `OverlayFs`, listing the directory in both layers.
+#[derive(Debug)]
+pub struct OverlayDir<U, L> {
+    upper: Option<U>,
+    lower: Option<L>,
+}
+
+fn join(parent: &Path, name: &OsStr) -> PathBuf {
+    parent.join(name)
+}
+
+fn split(path: &Path) -> (&Path, &OsStr) {
+    (path.parent().unwrap_or_else(|| Path::new("/")),
+     path.file_name().unwrap_or_else(|| OsStr::new("")))
+}
+
+fn is_whiteout_name(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    whiteout
+}
+
+fn has_whiteout_name(path: &Path) -> bool {
+    path.components().any(|c| match c {
+        Component::Normal(name) => is_whiteout_name(name),
+        _ => false,
+    })
+}
+
+fn mode_of(attr: &FileAttr) -> u32 {
+    let kind: libc::mode_t = match attr.kind {
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::Directory => libc::S_IFDIR,
+        FileType::RegularFile => libc::S_IFREG,
+        FileType::Symlink => libc::S_IFLNK,
+        FileType::Socket => libc::S_IFSOCK,
+    };
+    kind as u32 | u32::from(attr.perm)
+}
+
+// Merge the listings of both layers: upper entries hide lower ones of the same name, and
+// whiteouts hide lower ones without being listed themselves.
+fn merge(upper: Vec<DirectoryEntry>, lower: Vec<DirectoryEntry>) -> Vec<DirectoryEntry> {
+    let mut names = HashSet::new();
+    let mut entries = vec![];
+    for entry in upper {
+        if is_whiteout_name(&entry.name) {
+            names.insert(OsStr::from_bytes(&entry.name.as_bytes()[WHITEOUT_PREFIX.len()..]).to_owned());
+        } else {
+            names.insert(entry.name.clone());
+            entries.push(entry);
+        }
+    }
+    for entry in lower {
+        if !is_whiteout_name(&entry.name) && !names.contains(&entry.name) {
+            names.insert(entry.name.clone());
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+impl<U, L> OverlayFs<U, L> where U: FilesystemFL, L: FilesystemFL {
+    /// Layer `upper` over `lower`.
+    pub fn new(upper: U, lower: L) -> OverlayFs<U, L> {
+        OverlayFs {
+            upper,
+            lower,
+            copy_up_lock: Mutex::new(()),
+        }
+    }
+
+    /// Get a reference to the upper layer.
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    /// Get a reference to the lower layer.
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+
+    fn upper_attr(&self, req: &RequestInfo, path: &Path) -> Option<FileAttr> {
+        self.upper.getattr(clone_req(req), path, None).ok().map(|(_, attr)| attr)
+    }
+
+    fn is_opaque(&self, req: &RequestInfo, dir: &Path) -> bool {
+        self.upper.lookup(clone_req(req), dir, OsStr::new(OPAQUE_NAME)).is_ok()
+    }
+
+    // Whether the path of the lower layer shows through the upper one: none of its ancestors nor
+    // itself is whited-out, nor hidden by a non-directory or an opaque directory of the upper
+    // layer. The path itself existing in the upper layer is left to the caller.
+    fn lower_visible(&self, req: &RequestInfo, path: &Path) -> bool {
+        let mut dir = PathBuf::from("/");
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => continue,
+            };
+            match self.upper_attr(req, &dir) {
+                Some(ref attr) if attr.kind != FileType::Directory => return false,
+                Some(_) if self.is_opaque(req, &dir) ||
+                           self.upper.lookup(clone_req(req), &dir, &whiteout_name(name)).is_ok() => {
+                    return false;
+                }
+                _ => {}
+            }
+            dir.push(name);
+        }
+        true
+    }
+
+    fn lower_attr(&self, req: &RequestInfo, path: &Path) -> Option<(Timespec, FileAttr)> {
+        if self.lower_visible(req, path) {
+            self.lower.getattr(clone_req(req), path, None).ok()
+        } else {
+            None
+        }
+    }
+
+    fn attr(&self, req: &RequestInfo, path: &Path) -> Result<(Timespec, FileAttr)> {
+        if has_whiteout_name(path) {
+            return Err(Errno::ENOENT);
+        }
+        match self.upper.getattr(clone_req(req), path, None) {
+            Ok(res) => Ok(res),
+            Err(e) => self.lower_attr(req, path).ok_or(e),
+        }
+    }
+
+    // Create an empty file for marking a whiteout or an opaque directory.
+    fn make_marker(&self, req: &RequestInfo, dir: &Path, name: &OsStr) -> Result<()> {
+        let mode: libc::mode_t = libc::S_IFREG;
+        match self.upper.mknod(clone_req(req), dir, name, mode as u32, 0) {
+            Err(ref e) if *e == Errno::ENOSYS => {}
+            res => return res.map(|_| ()),
+        }
+        let flags = OpenFlags::from(AccessMode::WriteOnly) | OpenFlags::CREAT | OpenFlags::EXCL;
+        let created = self.upper.create(clone_req(req), dir, name, 0, flags)?;
+        let path = join(dir, name);
+        self.upper.release(clone_req(req), &path, created.fl, flags, 0, false)?;
+        Ok(())
+    }
+
+    fn copy_up(&self, req: &RequestInfo, path: &Path) -> Result<()> {
+        if path.parent().is_none() || self.upper_attr(req, path).is_some() {
+            return Ok(());
+        }
+        let (dir, name) = split(path);
+        self.copy_up(req, dir)?;
+        let _guard = self.copy_up_lock.lock().unwrap();
+        if self.upper_attr(req, path).is_some() {
+            return Ok(());
+        }
+        let (_, attr) = self.lower_attr(req, path).ok_or(Errno::ENOENT)?;
+        match attr.kind {
+            FileType::Directory => {
+                self.upper.mkdir(clone_req(req), dir, name, u32::from(attr.perm))?;
+            }
+            FileType::Symlink => {
+                let target = self.lower.readlink(clone_req(req), path)?;
+                self.upper.symlink(clone_req(req), dir, name, Path::new(OsStr::from_bytes(&target)))?;
+            }
+            FileType::RegularFile => self.copy_up_file(req, path, &attr)?,
+            _ => {
+                self.upper.mknod(clone_req(req), dir, name, mode_of(&attr), attr.rdev)?;
+            }
+        }
+        // Ownership and times are kept if the upper layer allows it.
+        let _ = self.upper.chown(clone_req(req), path, None, Some(attr.uid), Some(attr.gid));
+        if attr.kind != FileType::Symlink {
+            let _ = self.upper.utimens(clone_req(req), path, None, Some(attr.atime), Some(attr.mtime));
+        }
+        Ok(())
+    }
+
+    fn copy_up_file(&self, req: &RequestInfo, path: &Path, attr: &FileAttr) -> Result<()> {
+        let (dir, name) = split(path);
+        let read_flags = OpenFlags::from(AccessMode::ReadOnly);
+        let write_flags = OpenFlags::from(AccessMode::WriteOnly);
+        let create_flags = write_flags | OpenFlags::CREAT | OpenFlags::EXCL;
+        let (src, _) = self.lower.open(clone_req(req), path, read_flags)?;
+        let dst = match self.upper.create(clone_req(req), dir, name, mode_of(attr), create_flags) {
+            Ok(created) => Ok((created.fl, create_flags)),
+            Err(ref e) if *e == Errno::ENOSYS => {
+                self.upper.mknod(clone_req(req), dir, name, mode_of(attr), 0)?;
+                self.upper.open(clone_req(req), path, write_flags).map(|(fl, _)| (fl, write_flags))
+            }
+            Err(e) => Err(e),
+        };
+        let (dst, dst_flags) = match dst {
+            Ok(dst) => dst,
+            Err(e) => {
+                let _ = self.lower.release(clone_req(req), path, src, read_flags, 0, false);
+                return Err(e);
+            }
+        };
+        let res = self.copy_data(req, path, &src, &dst);
+        let res_dst = self.upper.release(clone_req(req), path, dst, dst_flags, 0, true);
+        let _ = self.lower.release(clone_req(req), path, src, read_flags, 0, false);
+        res.and(res_dst)
+    }
+
+    fn copy_data(&self, req: &RequestInfo, path: &Path, src: &L::FileLike, dst: &U::FileLike) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let data = self.lower.read(clone_req(req), path, src, offset, COPY_CHUNK)?;
+            if data.is_empty() {
+                return Ok(());
+            }
+            let len = data.len() as u64;
+            let written = self.upper.write(clone_req(req), path, dst, offset, data, 0)?;
+            if u64::from(written) != len {
+                return Err(Errno::EIO.with_context("short write while copying up"));
+            }
+            offset += len;
+        }
+    }
+
+    // Make room for a new entry in the upper layer, returning whether there's a whiteout in its
+    // place (i.e. it replaces a removed entry of the lower layer), to be removed by
+    // `remove_whiteout` once the entry is created.
+    fn prepare_create(&self, req: &RequestInfo, dir: &Path, name: &OsStr) -> Result<bool> {
+        if is_whiteout_name(name) || has_whiteout_name(dir) {
+            return Err(Errno::EINVAL);
+        }
+        let path = join(dir, name);
+        if self.attr(req, &path).is_ok() {
+            return Err(Errno::EEXIST);
+        }
+        self.copy_up(req, dir)?;
+        Ok(self.has_whiteout(req, dir, name))
+    }
+
+    fn has_whiteout(&self, req: &RequestInfo, dir: &Path, name: &OsStr) -> bool {
+        self.upper.lookup(clone_req(req), dir, &whiteout_name(name)).is_ok()
+    }
+
+    // Remove the whiteout of an entry which was just created in the upper layer. Failing to is
+    // harmless, as the whiteout only hides the entry of the lower layer, like the new entry does;
+    // so is a whiteout left over from such a failure when removing the entry again.
+    fn remove_whiteout(&self, req: &RequestInfo, dir: &Path, name: &OsStr) {
+        let _ = self.upper.unlink(clone_req(req), dir, &whiteout_name(name));
+    }
+
+    // Hide the entry of the lower layer, if any, after it was removed from the upper one.
+    fn hide_lower(&self, req: &RequestInfo, dir: &Path, name: &OsStr) -> Result<()> {
+        if self.lower_attr(req, &join(dir, name)).is_some() {
+            self.copy_up(req, dir)?;
+            if !self.has_whiteout(req, dir, name) {
+                self.make_marker(req, dir, &whiteout_name(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list_upper(&self, req: &RequestInfo, path: &Path) -> Result<Vec<DirectoryEntry>> {
+        let flags = OpenFlags::from(AccessMode::ReadOnly);
+        let (dl, _) = self.upper.opendir(clone_req(req), path, flags)?;
+        let res = self.upper.readdir(clone_req(req), path, &dl);
+        let _ = self.upper.releasedir(clone_req(req), path, dl, flags);
+        res
+    }
+
+    fn list_lower(&self, req: &RequestInfo, path: &Path) -> Result<Vec<DirectoryEntry>> {
+        let flags = OpenFlags::from(AccessMode::ReadOnly);
+        let (dl, _) = self.lower.opendir(clone_req(req), path, flags)?;
+        let res = self.lower.readdir(clone_req(req), path, &dl);
+        let _ = self.lower.releasedir(clone_req(req), path, dl, flags);
+        res
+    }
+
+    fn list(&self, req: &RequestInfo, path: &Path) -> Result<Vec<DirectoryEntry>> {
+        let upper = match self.upper_attr(req, path) {
+            Some(_) => self.list_upper(req, path)?,
+            None => vec![],
+        };
+        let lower = match self.lower_attr(req, path) {
+            Some(_) if !self.is_opaque(req, path) => self.list_lower(req, path)?,
+            _ => vec![],
+        };
+        Ok(merge(upper, lower))
+    }
+}
+
+impl<U, L> FilesystemFL for OverlayFs<U, L> where U: FilesystemFL, L: FilesystemFL {
+    type FileLike = OverlayFile<U::FileLike, L::FileLike>;
+    type DirLike = OverlayDir<U::DirLike, L::DirLike>;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        let res = self.upper.init(clone_req(&_req));
+        res.and(self.lower.init(_req))
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        self.upper.destroy(clone_req(&_req));
+        self.lower.destroy(_req);
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.attr(&_req, &join(_parent, _name))
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        match _fl {
+            Some(OverlayFile::Upper(fl)) => self.upper.getattr(_req, _path, Some(fl)),
+            Some(OverlayFile::Lower(fl)) => self.lower.getattr(_req, _path, Some(fl)),
+            None => self.attr(&_req, _path),
+        }
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        if let Some(OverlayFile::Upper(fl)) = _fl {
+            return self.upper.chmod(_req, _path, Some(fl), _mode);
+        }
+        self.copy_up(&_req, _path)?;
+        self.upper.chmod(_req, _path, None, _mode)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        if let Some(OverlayFile::Upper(fl)) = _fl {
+            return self.upper.chown(_req, _path, Some(fl), _uid, _gid);
+        }
+        self.copy_up(&_req, _path)?;
+        self.upper.chown(_req, _path, None, _uid, _gid)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        if let Some(OverlayFile::Upper(fl)) = _fl {
+            return self.upper.truncate(_req, _path, Some(fl), _size);
+        }
+        self.copy_up(&_req, _path)?;
+        self.upper.truncate(_req, _path, None, _size)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        if let Some(OverlayFile::Upper(fl)) = _fl {
+            return self.upper.utimens(_req, _path, Some(fl), _atime, _mtime);
+        }
+        self.copy_up(&_req, _path)?;
+        self.upper.utimens(_req, _path, None, _atime, _mtime)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        if let Some(OverlayFile::Upper(fl)) = _fl {
+            return self.upper.utimens_macos(_req, _path, Some(fl), _crtime, _chgtime, _bkuptime, _flags);
+        }
+        self.copy_up(&_req, _path)?;
+        self.upper.utimens_macos(_req, _path, None, _crtime, _chgtime, _bkuptime, _flags)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        if self.upper_attr(&_req, _path).is_some() {
+            self.upper.readlink(_req, _path)
+        } else if self.lower_attr(&_req, _path).is_some() {
+            self.lower.readlink(_req, _path)
+        } else {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        let replaces = self.prepare_create(&_req, _parent, _name)?;
+        let res = self.upper.mknod(clone_req(&_req), _parent, _name, _mode, _rdev)?;
+        if replaces {
+            self.remove_whiteout(&_req, _parent, _name);
+        }
+        Ok(res)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        let replaces = self.prepare_create(&_req, _parent, _name)?;
+        let res = self.upper.mkdir(clone_req(&_req), _parent, _name, _mode)?;
+        if replaces {
+            if let Err(e) = self.make_marker(&_req, &join(_parent, _name), OsStr::new(OPAQUE_NAME)) {
+                let _ = self.upper.rmdir(_req, _parent, _name);
+                return Err(e);
+            }
+            self.remove_whiteout(&_req, _parent, _name);
+        }
+        Ok(res)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let path = join(_parent, _name);
+        let (_, attr) = self.attr(&_req, &path)?;
+        if attr.kind == FileType::Directory {
+            return Err(Errno::EISDIR);
+        }
+        if self.upper_attr(&_req, &path).is_some() {
+            self.upper.unlink(clone_req(&_req), _parent, _name)?;
+        }
+        self.hide_lower(&_req, _parent, _name)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        let path = join(_parent, _name);
+        let (_, attr) = self.attr(&_req, &path)?;
+        if attr.kind != FileType::Directory {
+            return Err(Errno::ENOTDIR);
+        }
+        if self.list(&_req, &path)?.iter().any(|e| e.name != "." && e.name != "..") {
+            return Err(Errno::ENOTEMPTY);
+        }
+        if self.upper_attr(&_req, &path).is_some() {
+            // Only whiteouts are left in it.
+            for entry in self.list_upper(&_req, &path)? {
+                if is_whiteout_name(&entry.name) {
+                    self.upper.unlink(clone_req(&_req), &path, &entry.name)?;
+                }
+            }
+            self.upper.rmdir(clone_req(&_req), _parent, _name)?;
+        }
+        self.hide_lower(&_req, _parent, _name)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        let replaces = self.prepare_create(&_req, _parent, _name)?;
+        let res = self.upper.symlink(clone_req(&_req), _parent, _name, _target)?;
+        if replaces {
+            self.remove_whiteout(&_req, _parent, _name);
+        }
+        Ok(res)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        if is_whiteout_name(_newname) || has_whiteout_name(_newparent) {
+            return Err(Errno::EINVAL);
+        }
+        let path = join(_parent, _name);
+        let newpath = join(_newparent, _newname);
+        let (_, attr) = self.attr(&_req, &path)?;
+        let is_dir = attr.kind == FileType::Directory;
+        if let Ok((_, existing)) = self.attr(&_req, &newpath) {
+            match (is_dir, existing.kind == FileType::Directory) {
+                (true, false) => return Err(Errno::ENOTDIR),
+                (false, true) => return Err(Errno::EISDIR),
+                _ => {}
+            }
+        }
+        if is_dir && (self.lower_attr(&_req, &path).is_some() ||
+                      self.lower_attr(&_req, &newpath).is_some()) {
+            return Err(Errno::EXDEV);
+        }
+        self.copy_up(&_req, &path)?;
+        self.copy_up(&_req, _newparent)?;
+        let replaces = self.has_whiteout(&_req, _newparent, _newname);
+        // A directory replacing a removed one of the lower layer must hide it, like `mkdir` does;
+        // it is only in the upper layer, so it is marked opaque there before being moved.
+        let mark_opaque = replaces && is_dir && !self.is_opaque(&_req, &path);
+        if mark_opaque {
+            self.make_marker(&_req, &path, OsStr::new(OPAQUE_NAME))?;
+        }
+        if let Err(e) = self.upper.rename(clone_req(&_req), _parent, _name, _newparent, _newname) {
+            if mark_opaque {
+                let _ = self.upper.unlink(_req, &path, OsStr::new(OPAQUE_NAME));
+            }
+            return Err(e);
+        }
+        if replaces {
+            self.remove_whiteout(&_req, _newparent, _newname);
+        }
+        self.hide_lower(&_req, _parent, _name)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.attr(&_req, _path)?;
+        let replaces = self.prepare_create(&_req, _newparent, _newname)?;
+        self.copy_up(&_req, _path)?;
+        let res = self.upper.link(clone_req(&_req), _path, _newparent, _newname)?;
+        if replaces {
+            self.remove_whiteout(&_req, _newparent, _newname);
+        }
+        Ok(res)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        if has_whiteout_name(_path) {
+            return Err(Errno::ENOENT);
+        }
+        if self.upper_attr(&_req, _path).is_none() {
+            if self.lower_attr(&_req, _path).is_none() {
+                return Err(Errno::ENOENT);
+            }
+            if !_flags.access_mode()?.writable() && !_flags.contains(OpenFlags::TRUNC) {
+                return map_res_open(self.lower.open(_req, _path, _flags), OverlayFile::Lower);
+            }
+            self.copy_up(&_req, _path)?;
+        }
+        map_res_open(self.upper.open(_req, _path, _flags), OverlayFile::Upper)
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        match *_fl {
+            OverlayFile::Upper(ref fl) => self.upper.read(_req, _path, fl, _offset, _size),
+            OverlayFile::Lower(ref fl) => self.lower.read(_req, _path, fl, _offset, _size),
+        }
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        match *_fl {
+            OverlayFile::Upper(ref fl) => self.upper.write(_req, _path, fl, _offset, _data, _flags),
+            OverlayFile::Lower(_) => Err(Errno::EBADF),
+        }
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        match *_fl {
+            OverlayFile::Upper(ref fl) => self.upper.flush(_req, _path, fl, _lock_owner),
+            OverlayFile::Lower(ref fl) => self.lower.flush(_req, _path, fl, _lock_owner),
+        }
+    }
+
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        match _fl {
+            OverlayFile::Upper(fl) => self.upper.release(_req, _path, fl, _flags, _lock_owner, _flush),
+            OverlayFile::Lower(fl) => self.lower.release(_req, _path, fl, _flags, _lock_owner, _flush),
+        }
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        match *_fl {
+            OverlayFile::Upper(ref fl) => self.upper.fsync(_req, _path, fl, _datasync),
+            OverlayFile::Lower(ref fl) => self.lower.fsync(_req, _path, fl, _datasync),
+        }
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        if has_whiteout_name(_path) {
+            return Err(Errno::ENOENT);
+        }
+        let (upper, opaque) = match self.upper_attr(&_req, _path) {
+            Some(_) => (Some(self.upper.opendir(clone_req(&_req), _path, _flags)?), self.is_opaque(&_req, _path)),
+            None => (None, false),
+        };
+        let lower = match self.lower_attr(&_req, _path) {
+            Some((_, ref attr)) if !opaque && attr.kind == FileType::Directory => {
+                match self.lower.opendir(clone_req(&_req), _path, _flags) {
+                    Ok(lower) => Some(lower),
+                    Err(e) => {
+                        if let Some((dl, _)) = upper {
+                            let _ = self.upper.releasedir(_req, _path, dl, _flags);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            _ => None,
+        };
+        let flags = match (&upper, &lower) {
+            (&Some((_, flags)), _) | (&None, &Some((_, flags))) => flags,
+            (&None, &None) => return Err(Errno::ENOENT),
+        };
+        let dl = OverlayDir {
+            upper: upper.map(|(dl, _)| dl),
+            lower: lower.map(|(dl, _)| dl),
+        };
+        Ok((dl, flags))
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        let upper = match _dl.upper {
+            Some(ref dl) => self.upper.readdir(clone_req(&_req), _path, dl)?,
+            None => vec![],
+        };
+        let lower = match _dl.lower {
+            Some(ref dl) => self.lower.readdir(clone_req(&_req), _path, dl)?,
+            None => vec![],
+        };
+        Ok(merge(upper, lower))
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        let upper = match _dl.upper {
+            Some(dl) => self.upper.releasedir(clone_req(&_req), _path, dl, _flags),
+            None => Ok(()),
+        };
+        let lower = match _dl.lower {
+            Some(dl) => self.lower.releasedir(_req, _path, dl, _flags),
+            None => Ok(()),
+        };
+        upper.and(lower)
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        match _dl.upper {
+            Some(ref dl) => self.upper.fsyncdir(_req, _path, dl, _datasync),
+            None => Ok(()),
+        }
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.upper.statfs(_req, Path::new("/"))
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.copy_up(&_req, _path)?;
+        self.upper.setxattr(_req, _path, _name, _value, _flags, _position)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        if self.upper_attr(&_req, _path).is_some() {
+            self.upper.getxattr(_req, _path, _name, _size)
+        } else if self.lower_attr(&_req, _path).is_some() {
+            self.lower.getxattr(_req, _path, _name, _size)
+        } else {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        if self.upper_attr(&_req, _path).is_some() {
+            self.upper.listxattr(_req, _path, _size)
+        } else if self.lower_attr(&_req, _path).is_some() {
+            self.lower.listxattr(_req, _path, _size)
+        } else {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.copy_up(&_req, _path)?;
+        self.upper.removexattr(_req, _path, _name)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        if self.upper_attr(&_req, _path).is_some() {
+            self.upper.access(_req, _path, _mask)
+        } else if self.lower_attr(&_req, _path).is_some() {
+            // Writing is done in the upper layer, after copying up.
+            match self.lower.access(_req, _path, _mask) {
+                Err(ref e) if *e == Errno::EROFS => Ok(()),
+                res => res,
+            }
+        } else {
+            Err(Errno::ENOENT)
+        }
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        let replaces = self.prepare_create(&_req, _parent, _name)?;
+        let created = self.upper.create(clone_req(&_req), _parent, _name, _mode, _flags)?;
+        if replaces {
+            self.remove_whiteout(&_req, _parent, _name);
+        }
+        map_res_create(Ok(created), OverlayFile::Upper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memfs::{InMemory, MemFs};
+
+    type Overlay = OverlayFs<MemFs, MemFs>;
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    fn write_file<T: FilesystemFL>(fs: &T, file: &str, data: &[u8]) {
+        let (dir, file_name) = split(path(file));
+        let flags = OpenFlags::from(AccessMode::WriteOnly);
+        let fl = fs.create(req(), dir, file_name, 0o644, flags).unwrap().fl;
+        fs.write(req(), path(file), &fl, 0, data.to_vec(), 0).unwrap();
+        fs.release(req(), path(file), fl, flags, 0, true).unwrap();
+    }
+
+    fn read_file<T: FilesystemFL>(fs: &T, file: &str) -> Vec<u8> {
+        let flags = OpenFlags::from(AccessMode::ReadOnly);
+        let (fl, _) = fs.open(req(), path(file), flags).unwrap();
+        let data = fs.read(req(), path(file), &fl, 0, 4096).unwrap();
+        fs.release(req(), path(file), fl, flags, 0, false).unwrap();
+        data
+    }
+
+    fn list<T: FilesystemFL>(fs: &T, dir: &str) -> Vec<String> {
+        let flags = OpenFlags::from(AccessMode::ReadOnly);
+        let (dl, _) = fs.opendir(req(), path(dir), flags).unwrap();
+        let entries = fs.readdir(req(), path(dir), &dl).unwrap();
+        fs.releasedir(req(), path(dir), dl, flags).unwrap();
+        let mut names: Vec<_> =
+            entries.into_iter().map(|e| e.name.into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    fn exists<T: FilesystemFL>(fs: &T, file: &str) -> bool {
+        fs.getattr(req(), path(file), None).is_ok()
+    }
+
+    // Lower layer: /d/x, /d/y and /f.
+    fn overlay_with(upper: InMemory) -> Overlay {
+        let lower = MemFs::new(InMemory::new());
+        lower.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        write_file(&lower, "/d/x", b"x");
+        write_file(&lower, "/d/y", b"y");
+        write_file(&lower, "/f", b"lower");
+        OverlayFs::new(MemFs::new(upper), lower)
+    }
+
+    fn overlay() -> Overlay {
+        overlay_with(InMemory::new())
+    }
+
+    #[test]
+    fn falls_through_to_lower() {
+        let fs = overlay();
+        write_file(&fs, "/u", b"upper");
+        assert_eq!(fs.lookup(req(), path("/d"), name("x")).unwrap().1.size, 1);
+        assert_eq!(read_file(&fs, "/d/x"), b"x");
+        assert_eq!(read_file(&fs, "/u"), b"upper");
+        assert_eq!(fs.lookup(req(), path("/d"), name("z")).err(), Some(Errno::ENOENT));
+        // Reading doesn't copy anything up.
+        assert!(!exists(fs.upper(), "/d"));
+        assert!(exists(fs.upper(), "/u") && !exists(fs.lower(), "/u"));
+        let (fl, _) = fs.open(req(), path("/d/x"), AccessMode::ReadOnly.into()).unwrap();
+        assert!(matches!(fl, OverlayFile::Lower(_)));
+    }
+
+    #[test]
+    fn copies_up_on_write() {
+        let fs = overlay();
+        let flags = OpenFlags::from(AccessMode::ReadWrite);
+        let (fl, _) = fs.open(req(), path("/d/x"), flags).unwrap();
+        assert!(matches!(fl, OverlayFile::Upper(_)));
+        fs.write(req(), path("/d/x"), &fl, 1, b"yz".to_vec(), 0).unwrap();
+        fs.release(req(), path("/d/x"), fl, flags, 0, true).unwrap();
+        assert_eq!(read_file(&fs, "/d/x"), b"xyz");
+        assert_eq!(read_file(fs.upper(), "/d/x"), b"xyz");
+        assert_eq!(read_file(fs.lower(), "/d/x"), b"x");
+        assert_eq!(fs.upper().getattr(req(), path("/d"), None).unwrap().1.perm, 0o755);
+        // Only the file and its parents are copied up.
+        assert!(!exists(fs.upper(), "/d/y"));
+        assert_eq!(list(&fs, "/d"), [".", "..", "x", "y"]);
+    }
+
+    #[test]
+    fn whiteouts() {
+        let fs = overlay();
+        fs.unlink(req(), path("/d"), name("x")).unwrap();
+        assert!(!exists(&fs, "/d/x"));
+        assert!(exists(fs.lower(), "/d/x"));
+        assert!(exists(fs.upper(), "/d/.wh.x"));
+        assert_eq!(fs.unlink(req(), path("/d"), name("x")), Err(Errno::ENOENT));
+        assert_eq!(fs.rmdir(req(), path("/"), name("d")), Err(Errno::ENOTEMPTY));
+        fs.unlink(req(), path("/d"), name("y")).unwrap();
+        fs.rmdir(req(), path("/"), name("d")).unwrap();
+        assert!(!exists(&fs, "/d") && !exists(&fs, "/d/y"));
+        assert!(exists(fs.upper(), "/.wh.d") && !exists(fs.upper(), "/d"));
+        assert_eq!(list(&fs, "/"), [".", "..", "f"]);
+        // Whiteout names are reserved.
+        assert_eq!(fs.getattr(req(), path("/.wh.d"), None).err(), Some(Errno::ENOENT));
+        let err = fs.mkdir(req(), path("/"), name(".wh.e"), 0o755).err();
+        assert_eq!(err, Some(Errno::EINVAL));
+    }
+
+    #[test]
+    fn mkdir_over_whiteout_is_opaque() {
+        let fs = overlay();
+        fs.unlink(req(), path("/d"), name("x")).unwrap();
+        fs.unlink(req(), path("/d"), name("y")).unwrap();
+        fs.rmdir(req(), path("/"), name("d")).unwrap();
+        fs.mkdir(req(), path("/"), name("d"), 0o700).unwrap();
+        assert_eq!(list(&fs, "/d"), [".", ".."]);
+        assert!(!exists(&fs, "/d/x"));
+        assert!(!exists(fs.upper(), "/.wh.d"));
+        assert!(exists(fs.upper(), "/d/.wh..wh..opq"));
+        assert_eq!(fs.getattr(req(), path("/d"), None).unwrap().1.perm, 0o700);
+    }
+
+    #[test]
+    fn keeps_whiteouts_on_failure() {
+        // The upper layer has room for the root, a whiteout and one more inode.
+        let fs = overlay_with(InMemory::with_limits(1 << 20, 3));
+        fs.unlink(req(), path("/"), name("f")).unwrap();
+        write_file(&fs, "/g", b"");
+        let flags = OpenFlags::from(AccessMode::WriteOnly);
+        let err = fs.create(req(), path("/"), name("f"), 0o644, flags).err();
+        assert_eq!(err, Some(Errno::ENOSPC));
+        assert_eq!(fs.mkdir(req(), path("/"), name("f"), 0o755).err(), Some(Errno::ENOSPC));
+        assert!(!exists(&fs, "/f"));
+        assert!(exists(fs.upper(), "/.wh.f"));
+    }
+
+    #[test]
+    fn merges_listings() {
+        let fs = overlay();
+        write_file(&fs, "/d/n", b"new");
+        fs.unlink(req(), path("/d"), name("x")).unwrap();
+        let flags = OpenFlags::from(AccessMode::WriteOnly);
+        let (fl, _) = fs.open(req(), path("/d/y"), flags).unwrap();
+        fs.release(req(), path("/d/y"), fl, flags, 0, true).unwrap();
+        assert_eq!(list(fs.upper(), "/d"), [".", "..", ".wh.x", "n", "y"]);
+        assert_eq!(list(&fs, "/d"), [".", "..", "n", "y"]);
+        assert_eq!(list(&fs, "/"), [".", "..", "d", "f"]);
+    }
+
+    #[test]
+    fn rename_dir_over_whiteout() {
+        let fs = overlay();
+        fs.unlink(req(), path("/d"), name("x")).unwrap();
+        fs.unlink(req(), path("/d"), name("y")).unwrap();
+        fs.rmdir(req(), path("/"), name("d")).unwrap();
+        fs.mkdir(req(), path("/"), name("e"), 0o755).unwrap();
+        write_file(&fs, "/e/z", b"z");
+        fs.rename(req(), path("/"), name("e"), path("/"), name("d")).unwrap();
+        assert_eq!(fs.getattr(req(), path("/d/x"), None).err(), Some(Errno::ENOENT));
+        assert_eq!(list(&fs, "/d"), [".", "..", "z"]);
+        assert!(!exists(fs.upper(), "/.wh.d"));
+        // A directory can't replace a file, while a file of the lower layer is copied up to move.
+        let err = fs.rename(req(), path("/"), name("d"), path("/"), name("f")).err();
+        assert_eq!(err, Some(Errno::ENOTDIR));
+        fs.rename(req(), path("/"), name("f"), path("/d"), name("f")).unwrap();
+        assert_eq!(read_file(&fs, "/d/f"), b"lower");
+        assert!(!exists(&fs, "/f"));
+    }
+}
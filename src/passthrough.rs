@@ -0,0 +1,536 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A filesystem mirroring a directory of the host: every call is passed to the same path under
+//! it. This is both a starting point for filesystems which only alter some of the calls, and a
+//! reference for using the helpers of `filelike` and `dirlike`.
+//! Files are opened as `std::fs::File`, directories are listed with `dirlike::FsReadDir`, and all
+//! the rest is done through `std::fs` or libc.
+//! NOTE: `access` is not implemented, so the filesystem should be mounted with the
+//! `default_permissions` option to have the kernel check permissions.
+
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use libc;
+use time::Timespec;
+
+use super::dirlike::{FilesystemFLOpenDir, FsReadDir};
+use super::filelike::{attr_from_metadata, FilesystemFLMeta, FilesystemFLOpen, OpenFs};
+use super::flags::{OpenFlags, OpenReplyFlags};
+use super::fusefl::*;
+use super::sys::{check, cstr, to_libc_timespec};
+use fuse_mt::*;
+use super::{Errno, Result, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultStatfs,
+            ResultXattr};
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+/// A `FilesystemFL` mirroring a host directory, see the module docs.
+pub type PassthroughFs = OpenFs<Passthrough>;
+
+/// The implementation of `PassthroughFs`, to be wrapped in `OpenFs`.
+#[derive(Debug)]
+pub struct Passthrough {
+    root: PathBuf,
+}
+
+impl Passthrough {
+    /// Mirror the given directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Passthrough {
+        Passthrough { root: root.into() }
+    }
+
+    /// The mirrored directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path on the host for a path of the filesystem.
+    pub fn real_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    fn entry(&self, real: &Path) -> Result<(Timespec, FileAttr)> {
+        Ok((TTL, attr_from_metadata(&fs::symlink_metadata(real)?)))
+    }
+
+    /// When running as root, new entries are given to the user creating them, like the kernel
+    /// would (otherwise they are owned by whoever runs the filesystem).
+    fn set_owner(&self, req: &RequestInfo, real: &Path) -> Result<()> {
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(());
+        }
+        let real = cstr(real)?;
+        check(unsafe { libc::lchown(real.as_ptr(), req.uid, req.gid) })
+    }
+
+    fn new_entry(&self, req: &RequestInfo, real: &Path) -> ResultEntry {
+        self.set_owner(req, real)?;
+        self.entry(real)
+    }
+}
+
+fn open_options(flags: OpenFlags) -> Result<OpenOptions> {
+    let mode = flags.access_mode()?;
+    let mut options = OpenOptions::new();
+    // The access mode is masked out by `custom_flags`, and the creation flags are set separately.
+    options.read(mode.readable())
+        .write(mode.writable())
+        .custom_flags((flags.bits() & !(OpenFlags::CREAT | OpenFlags::EXCL).bits()) as i32);
+    Ok(options)
+}
+
+impl FilesystemFLOpen for Passthrough {
+    type FileLike = File;
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<File> {
+        let file = open_options(_flags)?.open(self.real_path(_path))?;
+        Ok((file, OpenReplyFlags::empty()))
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<File> {
+        let real = self.real_path(_parent).join(_name);
+        let file = open_options(_flags)?
+            .create(true)
+            .create_new(_flags.contains(OpenFlags::EXCL))
+            .mode(_mode)
+            .open(&real)?;
+        self.set_owner(&_req, &real)?;
+        Ok(CreatedEntryObj {
+            ttl: TTL,
+            attr: attr_from_metadata(&file.metadata()?),
+            fl: file,
+            flags: OpenReplyFlags::empty(),
+        })
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &File,
+             _datasync: bool)
+             -> ResultEmpty {
+        let res = if _datasync { _fl.sync_data() } else { _fl.sync_all() };
+        res.map_err(Errno::from)
+    }
+
+    fn fsync_metadata(&self, _req: RequestInfo, _path: &Path, _fl: &File) -> ResultEmpty {
+        _fl.sync_all().map_err(Errno::from)
+    }
+}
+
+impl FilesystemFLOpenDir for Passthrough {
+    type DirLike = FsReadDir;
+
+    fn opendir(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<FsReadDir> {
+        Ok((FsReadDir::open(self.real_path(_path))?, OpenReplyFlags::empty()))
+    }
+}
+
+impl FilesystemFLMeta for Passthrough {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        Ok(())
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.entry(&self.real_path(_parent).join(_name))
+    }
+
+    fn getattr(&self, _req: RequestInfo, _path: &Path, _fl: Option<&File>) -> ResultGetattr {
+        self.entry(&self.real_path(_path))
+    }
+
+    fn chmod(&self, _req: RequestInfo, _path: &Path, _fl: Option<&File>, _mode: u32) -> ResultEmpty {
+        let real = cstr(&self.real_path(_path))?;
+        check(unsafe { libc::chmod(real.as_ptr(), _mode as libc::mode_t) })
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&File>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        let real = cstr(&self.real_path(_path))?;
+        // -1 leaves the id unchanged.
+        check(unsafe { libc::lchown(real.as_ptr(), _uid.unwrap_or(!0), _gid.unwrap_or(!0)) })
+    }
+
+    fn truncate(&self, _req: RequestInfo, _path: &Path, _fl: Option<&File>, _size: u64) -> ResultEmpty {
+        let real = cstr(&self.real_path(_path))?;
+        check(unsafe { libc::truncate(real.as_ptr(), _size as libc::off_t) })
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&File>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        let real = cstr(&self.real_path(_path))?;
+        let times = [to_libc_timespec(_atime), to_libc_timespec(_mtime)];
+        check(unsafe {
+            libc::utimensat(libc::AT_FDCWD, real.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+        })
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        match fs::read_link(self.real_path(_path)) {
+            Ok(target) => Ok(target.as_os_str().as_bytes().to_vec()),
+            Err(e) => Err(Errno::from(e)),
+        }
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        let real = self.real_path(_parent).join(_name);
+        let path = cstr(&real)?;
+        check(unsafe { libc::mknod(path.as_ptr(), _mode as libc::mode_t, _rdev as libc::dev_t) })?;
+        self.new_entry(&_req, &real)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        let real = self.real_path(_parent).join(_name);
+        DirBuilder::new().mode(_mode).create(&real).map_err(Errno::from)?;
+        self.new_entry(&_req, &real)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        fs::remove_file(self.real_path(_parent).join(_name)).map_err(Errno::from)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        fs::remove_dir(self.real_path(_parent).join(_name)).map_err(Errno::from)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        let real = self.real_path(_parent).join(_name);
+        symlink(_target, &real).map_err(Errno::from)?;
+        self.new_entry(&_req, &real)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        fs::rename(self.real_path(_parent).join(_name),
+                   self.real_path(_newparent).join(_newname))
+            .map_err(Errno::from)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        let real = self.real_path(_newparent).join(_newname);
+        fs::hard_link(self.real_path(_path), &real).map_err(Errno::from)?;
+        self.entry(&real)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        let real = cstr(&self.real_path(_path))?;
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        check(unsafe { libc::statvfs(real.as_ptr(), &mut stat) })?;
+        Ok(Statfs {
+            blocks: stat.f_blocks as u64,
+            bfree: stat.f_bfree as u64,
+            bavail: stat.f_bavail as u64,
+            files: stat.f_files as u64,
+            ffree: stat.f_ffree as u64,
+            bsize: stat.f_bsize as u32,
+            namelen: stat.f_namemax as u32,
+            frsize: stat.f_frsize as u32,
+        })
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        if _position != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let real = cstr(&self.real_path(_path))?;
+        let name = cstr(_name)?;
+        xattr::set(&real, &name, _value, _flags as libc::c_int)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        let real = cstr(&self.real_path(_path))?;
+        let name = cstr(_name)?;
+        if _size == 0 {
+            Ok(Xattr::Size(xattr::get(&real, &name, &mut [])? as u32))
+        } else {
+            let mut buf = vec![0; _size as usize];
+            let len = xattr::get(&real, &name, &mut buf)?;
+            buf.truncate(len);
+            Ok(Xattr::Data(buf))
+        }
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        let real = cstr(&self.real_path(_path))?;
+        if _size == 0 {
+            Ok(Xattr::Size(xattr::list(&real, &mut [])? as u32))
+        } else {
+            let mut buf = vec![0; _size as usize];
+            let len = xattr::list(&real, &mut buf)?;
+            buf.truncate(len);
+            Ok(Xattr::Data(buf))
+        }
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        let real = cstr(&self.real_path(_path))?;
+        let name = cstr(_name)?;
+        xattr::remove(&real, &name)
+    }
+}
+
+// The xattr calls, not following symlinks. An empty buffer queries the needed size.
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::ffi::CStr;
+    use libc;
+
+    use super::super::sys::{check, check_size};
+    use super::super::Result;
+
+    pub fn set(path: &CStr, name: &CStr, value: &[u8], flags: libc::c_int) -> Result<()> {
+        check(unsafe {
+            libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void,
+                            value.len(), flags)
+        })
+    }
+
+    pub fn get(path: &CStr, name: &CStr, buf: &mut [u8]) -> Result<usize> {
+        check_size(unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void,
+                            buf.len())
+        })
+    }
+
+    pub fn list(path: &CStr, buf: &mut [u8]) -> Result<usize> {
+        check_size(unsafe {
+            libc::llistxattr(path.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+        })
+    }
+
+    pub fn remove(path: &CStr, name: &CStr) -> Result<()> {
+        check(unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::ffi::CStr;
+    use libc;
+
+    use super::super::{Errno, Result};
+
+    pub fn set(_path: &CStr, _name: &CStr, _value: &[u8], _flags: libc::c_int) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+
+    pub fn get(_path: &CStr, _name: &CStr, _buf: &mut [u8]) -> Result<usize> {
+        Err(Errno::ENOSYS)
+    }
+
+    pub fn list(_path: &CStr, _buf: &mut [u8]) -> Result<usize> {
+        Err(Errno::ENOSYS)
+    }
+
+    pub fn remove(_path: &CStr, _name: &CStr) -> Result<()> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::super::flags::AccessMode;
+
+    /// A directory under the system temporary directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = env::temp_dir().join(format!("fuse_fl-passthrough-{}-{}",
+                                                   process::id(),
+                                                   NEXT.fetch_add(1, Ordering::SeqCst)));
+            fs::create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn req() -> RequestInfo {
+        unsafe { RequestInfo { unique: 0, uid: libc::geteuid(), gid: libc::getegid(), pid: 0 } }
+    }
+
+    fn path(path: &str) -> &Path {
+        Path::new(path)
+    }
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    fn passthrough() -> (TempDir, PassthroughFs) {
+        let dir = TempDir::new();
+        let fs = PassthroughFs::new(Passthrough::new(&dir.0));
+        (dir, fs)
+    }
+
+    #[test]
+    fn lookup_and_getattr() {
+        let (dir, fs) = passthrough();
+        fs::create_dir(dir.0.join("d")).unwrap();
+        fs::write(dir.0.join("d/f"), b"hello").unwrap();
+        let attr = fs.lookup(req(), path("/d"), name("f")).unwrap().1;
+        assert_eq!((attr.kind, attr.size), (FileType::RegularFile, 5));
+        assert_eq!(fs.getattr(req(), path("/d"), None).unwrap().1.kind, FileType::Directory);
+        assert_eq!(fs.lookup(req(), path("/d"), name("g")).err(), Some(Errno::ENOENT));
+        assert_eq!(fs.get_ref().real_path(path("/d/f")), dir.0.join("d/f"));
+    }
+
+    #[test]
+    fn create_write_read() {
+        let (dir, fs) = passthrough();
+        let flags = OpenFlags::from(AccessMode::WriteOnly) | OpenFlags::EXCL;
+        let created = fs.create(req(), path("/"), name("f"), 0o640, flags).unwrap();
+        assert_eq!(fs.write(req(), path("/f"), &created.fl, 2, b"llo".to_vec(), 0), Ok(3));
+        fs.release(req(), path("/f"), created.fl, flags, 0, true).unwrap();
+        assert_eq!(fs::read(dir.0.join("f")).unwrap(), b"\0\0llo");
+        assert_eq!(fs.create(req(), path("/"), name("f"), 0o640, flags).err(), Some(Errno::EEXIST));
+
+        let flags = OpenFlags::from(AccessMode::ReadWrite);
+        let (fl, _) = fs.open(req(), path("/f"), flags).unwrap();
+        fs.write(req(), path("/f"), &fl, 0, b"he".to_vec(), 0).unwrap();
+        assert_eq!(fs.read(req(), path("/f"), &fl, 0, 100), Ok(b"hello".to_vec()));
+        fs.truncate(req(), path("/f"), Some(&fl), 2).unwrap();
+        assert_eq!(fs.getattr(req(), path("/f"), Some(&fl)).unwrap().1.size, 2);
+        fs.fsync(req(), path("/f"), &fl, false).unwrap();
+        fs.release(req(), path("/f"), fl, flags, 0, true).unwrap();
+        let err = fs.open(req(), path("/g"), flags).err();
+        assert_eq!(err, Some(Errno::ENOENT));
+    }
+
+    #[test]
+    fn directories() {
+        let (dir, fs) = passthrough();
+        fs.mkdir(req(), path("/"), name("d"), 0o755).unwrap();
+        fs::write(dir.0.join("d/f"), b"").unwrap();
+        fs.symlink(req(), path("/d"), name("l"), path("f")).unwrap();
+        let flags = OpenFlags::from(AccessMode::ReadOnly);
+        let (dl, _) = fs.opendir(req(), path("/d"), flags).unwrap();
+        let mut entries: Vec<_> = fs.readdir(req(), path("/d"), &dl).unwrap()
+            .into_iter()
+            .map(|e| (e.name.into_string().unwrap(), e.kind))
+            .collect();
+        fs.releasedir(req(), path("/d"), dl, flags).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries,
+                   [(".".to_string(), FileType::Directory),
+                    ("..".to_string(), FileType::Directory),
+                    ("f".to_string(), FileType::RegularFile),
+                    ("l".to_string(), FileType::Symlink)]);
+        assert_eq!(fs.rmdir(req(), path("/"), name("d")), Err(Errno::ENOTEMPTY));
+    }
+
+    #[test]
+    fn rename_and_links() {
+        let (dir, fs) = passthrough();
+        fs::create_dir(dir.0.join("d")).unwrap();
+        fs::write(dir.0.join("f"), b"data").unwrap();
+        fs.rename(req(), path("/"), name("f"), path("/d"), name("g")).unwrap();
+        assert!(!dir.0.join("f").exists());
+        assert_eq!(fs::read(dir.0.join("d/g")).unwrap(), b"data");
+        let attr = fs.link(req(), path("/d/g"), path("/"), name("h")).unwrap().1;
+        assert_eq!(attr.nlink, 2);
+
+        // Symlinks are neither followed nor resolved.
+        let attr = fs.symlink(req(), path("/"), name("l"), path("../outside")).unwrap().1;
+        assert_eq!(attr.kind, FileType::Symlink);
+        assert_eq!(fs.readlink(req(), path("/l")), Ok(b"../outside".to_vec()));
+        assert_eq!(fs.getattr(req(), path("/l"), None).unwrap().1.kind, FileType::Symlink);
+        assert_eq!(fs.readlink(req(), path("/h")).err(), Some(Errno::EINVAL));
+        fs.unlink(req(), path("/"), name("l")).unwrap();
+        assert!(fs::symlink_metadata(dir.0.join("l")).is_err());
+    }
+
+    #[test]
+    fn xattrs_and_statfs() {
+        let (dir, fs) = passthrough();
+        fs::write(dir.0.join("f"), b"").unwrap();
+        let statfs = fs.statfs(req(), path("/")).unwrap();
+        assert!(statfs.blocks > 0 && statfs.bsize > 0 && statfs.namelen > 0);
+
+        match fs.setxattr(req(), path("/f"), name("user.test"), b"value", 0, 0) {
+            // The temporary directory may not support user xattrs.
+            Err(ref e) if *e == Errno::ENOTSUP => return,
+            res => res.unwrap(),
+        }
+        match fs.getxattr(req(), path("/f"), name("user.test"), 0) {
+            Ok(Xattr::Size(5)) => {}
+            _ => panic!("wrong xattr size"),
+        }
+        match fs.getxattr(req(), path("/f"), name("user.test"), 16) {
+            Ok(Xattr::Data(ref data)) if data == b"value" => {}
+            _ => panic!("wrong xattr value"),
+        }
+        match fs.listxattr(req(), path("/f"), 256) {
+            Ok(Xattr::Data(ref data)) => assert!(data.split(|&b| b == 0).any(|n| n == b"user.test")),
+            _ => panic!("wrong xattr list"),
+        }
+        let err = fs.setxattr(req(), path("/f"), name("user.test"), b"", libc::XATTR_CREATE as u32, 0);
+        assert_eq!(err, Err(Errno::EEXIST));
+        fs.removexattr(req(), path("/f"), name("user.test")).unwrap();
+        assert_eq!(fs.getxattr(req(), path("/f"), name("user.test"), 0).err(), Some(Errno::ENODATA));
+    }
+}
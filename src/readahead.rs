@@ -0,0 +1,456 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! Readahead of file handlers, for sequential reading (e.g. streaming) over backends with a high
+//! latency per call (e.g. network storage), where the kernel's own readahead (of at most
+//! `max_readahead`, 128KiB by default) doesn't hide the latency.
+//! Like the kernel's page cache, a `Readahead` detects sequential reading, i.e. reads starting
+//! where the previous ones ended (or at the start of the file), and reads ahead the following
+//! window of the file on a background thread, doubling the window on each readahead up to a
+//! maximum. As the kernel may issue concurrent reads out of order, reads starting up to the
+//! minimal window before or after that still count as sequential. Any other read resets it,
+//! dropping the data read ahead.
+
+use std::cmp::{max, min};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::Builder;
+use time::Timespec;
+
+use super::filelike::{read_full_at, AttrFileLike, ReadFileLike, SetLenFileLike, WriteFileLike};
+use fuse_mt::FileAttr;
+use super::Result;
+
+// Data read ahead from `offset`, shorter than the window if it reached the end of file.
+#[derive(Debug)]
+struct Chunk {
+    offset: u64,
+    data: Vec<u8>,
+    eof: bool,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    fn covers(&self, pos: u64) -> bool {
+        self.offset <= pos && pos < self.end()
+    }
+}
+
+// A readahead requested from the worker, with its result once done.
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    offset: u64,
+    len: usize,
+    result: Option<Result<Vec<u8>>>,
+}
+
+impl Pending {
+    fn covers(&self, pos: u64) -> bool {
+        self.offset <= pos && pos < self.offset + self.len as u64
+    }
+}
+
+// A request to the worker, to read `len` bytes at `offset` for the pending readahead `id`.
+#[derive(Debug)]
+struct Job {
+    id: u64,
+    offset: u64,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    // Where the reads so far ended, if reading is sequential.
+    position: u64,
+    // The size of the next readahead, or 0 if it wasn't started since the last reset.
+    window: usize,
+    current: Option<Chunk>,
+    pending: Option<Pending>,
+    // The id of the last readahead requested.
+    last_id: u64,
+    // The channel to the worker, once started.
+    worker: Option<Sender<Job>>,
+}
+
+impl State {
+    fn reset(&mut self) {
+        self.window = 0;
+        self.current = None;
+        // The worker finishes a pending readahead it already started, but its data is dropped.
+        self.pending = None;
+    }
+}
+
+// The state, shared with the worker, which signals `done` when it finishes a readahead.
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+impl Shared {
+    // Reset the state, waking up the readers waiting for a pending readahead.
+    fn reset(&self, state: &mut State) {
+        state.reset();
+        self.done.notify_all();
+    }
+
+    // Wait for the readahead with the given id, if it is still pending. A readahead which failed
+    // is dropped; the data is then read again directly, which returns the error.
+    fn wait<'a>(&self, mut state: MutexGuard<'a, State>, id: u64) -> MutexGuard<'a, State> {
+        loop {
+            let done = match state.pending {
+                Some(ref pending) if pending.id == id => pending.result.is_some(),
+                _ => return state,
+            };
+            if done {
+                let pending = state.pending.take().expect("pending readahead");
+                state.current = match pending.result {
+                    Some(Ok(data)) => {
+                        Some(Chunk { offset: pending.offset, eof: data.len() < pending.len, data })
+                    }
+                    _ => None,
+                };
+                return state;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+    }
+}
+
+/// Adaptor reading ahead of sequential reads of a handler, see the module docs. Reads are served
+/// from the data read ahead where possible, and otherwise from the handler directly, so the
+/// handler should read fully (see `FillingAdaptor`). A readahead which fails is ignored, and the
+/// data is read again directly when needed, returning the error then.
+/// Writes and truncates are passed on to the handler, dropping the data read ahead.
+/// Readaheads are done one at a time by a worker thread, started on the first one, which shares
+/// the handler, so it is only dropped after any readahead in progress is done.
+#[derive(Debug)]
+pub struct Readahead<R> {
+    inner: Arc<R>,
+    min_window: usize,
+    max_window: usize,
+    shared: Arc<Shared>,
+}
+
+impl<R> Readahead<R> {
+    /// Wrap a handler, reading ahead windows of `min_window` bytes (or twice the size of the read,
+    /// if larger) growing up to `max_window` bytes.
+    pub fn new(inner: R, min_window: usize, max_window: usize) -> Readahead<R> {
+        assert!(min_window > 0 && min_window <= max_window, "invalid readahead windows");
+        let state = State {
+            position: 0,
+            window: 0,
+            current: None,
+            pending: None,
+            last_id: 0,
+            worker: None,
+        };
+        Readahead {
+            inner: Arc::new(inner),
+            min_window,
+            max_window,
+            shared: Arc::new(Shared { state: Mutex::new(state), done: Condvar::new() }),
+        }
+    }
+
+    /// The wrapped handler.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    // Drop the data read ahead, after the file was changed.
+    fn invalidate(&self) {
+        self.shared.reset(&mut self.shared.state.lock().unwrap());
+    }
+
+    // Whether a read at `offset` continues the sequential reading so far.
+    fn is_sequential(&self, state: &State, offset: u64) -> bool {
+        let distance = max(offset, state.position) - min(offset, state.position);
+        distance <= self.min_window as u64
+    }
+}
+
+impl<R> Readahead<R> where R: ReadFileLike + Send + Sync + 'static {
+    // Start the worker, reading ahead for the pending readaheads it is sent, unless they were
+    // dropped by then.
+    fn start_worker(&self) -> Option<Sender<Job>> {
+        let (sender, receiver) = channel::<Job>();
+        let inner = self.inner.clone();
+        let shared = self.shared.clone();
+        let spawned = Builder::new().name("readahead".into()).spawn(move || {
+            for job in receiver {
+                let wanted = |state: &State| {
+                    state.pending.as_ref().map_or(false, |pending| pending.id == job.id)
+                };
+                if !wanted(&shared.state.lock().unwrap()) {
+                    continue;
+                }
+                let mut data = vec![0; job.len];
+                let res = read_full_at(&*inner, &mut data, job.offset).map(|len| {
+                    data.truncate(len);
+                    data
+                });
+                let mut state = shared.state.lock().unwrap();
+                if wanted(&state) {
+                    state.pending.as_mut().expect("pending readahead").result = Some(res);
+                }
+                shared.done.notify_all();
+            }
+        });
+        // Failing to start the worker only means reading directly.
+        spawned.ok().map(|_| sender)
+    }
+
+    // Start reading the next window ahead of a sequential read of `len` bytes, unless there's one
+    // in progress already, or the end of file was reached.
+    fn read_ahead(&self, state: &mut State, len: usize) {
+        if state.pending.is_some() || state.current.as_ref().map_or(false, |chunk| chunk.eof) {
+            return;
+        }
+        let offset = match state.current {
+            Some(ref chunk) if chunk.end() > state.position => chunk.end(),
+            _ => state.position,
+        };
+        let window = if state.window == 0 {
+            min(max(self.min_window, len.saturating_mul(2)), self.max_window)
+        } else {
+            min(state.window.saturating_mul(2), self.max_window)
+        };
+        if state.worker.is_none() {
+            state.worker = self.start_worker();
+        }
+        let id = state.last_id + 1;
+        let sent = match state.worker {
+            Some(ref worker) => worker.send(Job { id, offset, len: window }).is_ok(),
+            None => false,
+        };
+        if sent {
+            state.last_id = id;
+            state.window = window;
+            state.pending = Some(Pending { id, offset, len: window, result: None });
+        }
+    }
+}
+
+impl<R> ReadFileLike for Readahead<R> where R: ReadFileLike + Send + Sync + 'static {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut done = 0;
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let sequential = self.is_sequential(&state, offset);
+            if !sequential {
+                self.shared.reset(&mut state);
+            }
+            while done < buf.len() {
+                let pos = offset + done as u64;
+                if !state.current.as_ref().map_or(false, |chunk| chunk.covers(pos)) {
+                    let id = match state.pending {
+                        Some(ref pending) if pending.covers(pos) => pending.id,
+                        _ => break,
+                    };
+                    // This releases the lock while waiting.
+                    state = self.shared.wait(state, id);
+                }
+                let chunk = match state.current {
+                    Some(ref chunk) if chunk.covers(pos) => chunk,
+                    _ => break,
+                };
+                let skip = (pos - chunk.offset) as usize;
+                let len = min(chunk.data.len() - skip, buf.len() - done);
+                buf[done..done + len].copy_from_slice(&chunk.data[skip..skip + len]);
+                done += len;
+            }
+            let end = offset + buf.len() as u64;
+            state.position = if sequential { max(state.position, end) } else { end };
+            if sequential {
+                self.read_ahead(&mut state, buf.len());
+            }
+        }
+        // Read the rest directly, concurrently with the readahead following it.
+        if done < buf.len() {
+            done += read_full_at(&*self.inner, &mut buf[done..], offset + done as u64)?;
+        }
+        Ok(done)
+    }
+}
+
+impl<R> Drop for Readahead<R> {
+    fn drop(&mut self) {
+        // Dropping the channel stops the worker, after any readahead in progress.
+        if let Ok(mut state) = self.shared.state.lock() {
+            self.shared.reset(&mut state);
+            state.worker = None;
+        }
+    }
+}
+
+impl<R> WriteFileLike for Readahead<R> where R: WriteFileLike {
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let res = self.inner.write_at(buf, offset);
+        // Even a failed write may have written some of it.
+        self.invalidate();
+        res
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_buffered(&self) -> Result<()> {
+        self.inner.write_buffered()
+    }
+}
+
+impl<R> SetLenFileLike for Readahead<R> where R: SetLenFileLike {
+    fn set_len(&self, size: u64) -> Result<()> {
+        let res = self.inner.set_len(size);
+        self.invalidate();
+        res
+    }
+}
+
+impl<R> AttrFileLike for Readahead<R> where R: AttrFileLike {
+    fn getattr(&self) -> Result<FileAttr> {
+        self.inner.getattr()
+    }
+
+    fn chmod(&self, mode: u32) -> Result<()> {
+        self.inner.chmod(mode)
+    }
+
+    fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        self.inner.chown(uid, gid)
+    }
+
+    fn utimens(&self, atime: Option<Timespec>, mtime: Option<Timespec>) -> Result<()> {
+        self.inner.utimens(atime, mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::{self, ThreadId};
+    use std::time::{Duration, Instant};
+    use super::super::filelike::MemFile;
+
+    // A handler recording the reads reaching it, with the threads doing them.
+    struct Recording {
+        file: MemFile,
+        reads: Mutex<Vec<(u64, usize, ThreadId)>>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl ReadFileLike for Recording {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.reads.lock().unwrap().push((offset, buf.len(), thread::current().id()));
+            self.file.read_at(buf, offset)
+        }
+    }
+
+    impl WriteFileLike for Recording {
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            self.file.write_at(buf, offset)
+        }
+    }
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..10000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn readahead() -> (Readahead<Recording>, Arc<AtomicBool>) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let inner = Recording {
+            file: MemFile::from_vec(data()),
+            reads: Mutex::new(vec![]),
+            dropped: dropped.clone(),
+        };
+        (Readahead::new(inner, 100, 800), dropped)
+    }
+
+    fn read(readahead: &Readahead<Recording>, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let len = readahead.read_at(&mut buf, offset).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    fn window(readahead: &Readahead<Recording>) -> usize {
+        readahead.shared.state.lock().unwrap().window
+    }
+
+    #[test]
+    fn reads_ahead_on_one_worker() {
+        let (readahead, _) = readahead();
+        let data = data();
+        let mut offset = 0;
+        while offset < data.len() {
+            let chunk = read(&readahead, offset as u64, 50);
+            assert_eq!(chunk, &data[offset..offset + 50]);
+            offset += 50;
+        }
+        assert!(read(&readahead, offset as u64, 50).is_empty());
+        let reads = readahead.inner().reads.lock().unwrap().clone();
+        assert!(reads.len() < 40, "{} reads reached the handler", reads.len());
+        assert!(reads.iter().any(|&(_, len, _)| len == 800));
+        let me = thread::current().id();
+        let workers: HashSet<_> =
+            reads.iter().map(|&(_, _, id)| id).filter(|&id| id != me).collect();
+        assert_eq!(workers.len(), 1);
+    }
+
+    #[test]
+    fn tolerates_reordering() {
+        let (readahead, _) = readahead();
+        let data = data();
+        assert_eq!(read(&readahead, 0, 50), &data[..50]);
+        assert_eq!(read(&readahead, 100, 50), &data[100..150]);
+        assert_eq!(read(&readahead, 50, 50), &data[50..100]);
+        assert_eq!(read(&readahead, 150, 50), &data[150..200]);
+        assert!(window(&readahead) > 0);
+        // A read elsewhere resets it.
+        assert_eq!(read(&readahead, 5000, 50), &data[5000..5050]);
+        assert_eq!(window(&readahead), 0);
+    }
+
+    #[test]
+    fn writes_invalidate() {
+        let (readahead, _) = readahead();
+        read(&readahead, 0, 50);
+        read(&readahead, 50, 50);
+        readahead.write_at(b"XYZ", 100).unwrap();
+        assert_eq!(window(&readahead), 0);
+        assert_eq!(&read(&readahead, 100, 50)[..3], b"XYZ");
+    }
+
+    #[test]
+    fn drop_stops_the_worker() {
+        let (readahead, dropped) = readahead();
+        read(&readahead, 0, 50);
+        drop(readahead);
+        // The worker drops the handler once it is done.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "the worker kept running");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
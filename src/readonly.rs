@@ -0,0 +1,346 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A wrapper exposing any `FilesystemFL` read-only: reading calls are forwarded to it, and every
+//! modifying one fails with `EROFS`, without reaching it.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use libc;
+use time::Timespec;
+
+use super::flags::OpenFlags;
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite, ResultReaddir,
+            ResultStatfs, ResultXattr};
+
+/// A `FilesystemFL` forwarding the reading calls to `T`, and failing the modifying ones with
+/// `EROFS`. Files can only be opened with `O_RDONLY` (and without `O_TRUNC`).
+#[derive(Debug)]
+pub struct ReadOnly<T> {
+    inner: T,
+}
+
+impl<T> ReadOnly<T> where T: FilesystemFL {
+    /// Wrap the given filesystem.
+    pub fn new(inner: T) -> ReadOnly<T> {
+        ReadOnly { inner }
+    }
+
+    /// The wrapped filesystem.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Unwrap the wrapped filesystem.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> FilesystemFL for ReadOnly<T> where T: FilesystemFL {
+    type FileLike = T::FileLike;
+    type DirLike = T::DirLike;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.inner.init(_req)
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        self.inner.destroy(_req)
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.inner.lookup(_req, _parent, _name)
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        self.inner.getattr(_req, _path, _fl)
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.inner.readlink(_req, _path)
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        Err(Errno::EROFS)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        Err(Errno::EROFS)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        Err(Errno::EROFS)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        Err(Errno::EROFS)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        if _flags.access_mode()?.writable() || _flags.contains(OpenFlags::TRUNC) {
+            return Err(Errno::EROFS);
+        }
+        self.inner.open(_req, _path, _flags)
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        self.inner.read(_req, _path, _fl, _offset, _size)
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        Err(Errno::EROFS)
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        self.inner.flush(_req, _path, _fl, _lock_owner)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        self.inner.release(_req, _path, _fl, _flags, _lock_owner, _flush)
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        self.inner.fsync(_req, _path, _fl, _datasync)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        self.inner.opendir(_req, _path, _flags)
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        self.inner.readdir(_req, _path, _dl)
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        self.inner.releasedir(_req, _path, _dl, _flags)
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        self.inner.fsyncdir(_req, _path, _dl, _datasync)
+    }
+
+    /// The statistics of the wrapped filesystem, with no free blocks or inodes (as nothing can be
+    /// written anyway). `ST_RDONLY` isn't set, as fuse-mt's `Statfs` has no flags; mount with
+    /// `-o ro` for that.
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        let statfs = self.inner.statfs(_req, _path)?;
+        Ok(Statfs { bfree: 0, bavail: 0, ffree: 0, ..statfs })
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.inner.getxattr(_req, _path, _name, _size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.inner.listxattr(_req, _path, _size)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        Err(Errno::EROFS)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        if _mask & libc::W_OK as u32 != 0 {
+            return Err(Errno::EROFS);
+        }
+        self.inner.access(_req, _path, _mask)
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        Err(Errno::EROFS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::flags::AccessMode;
+    use super::super::memfs::{InMemory, MemFs};
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 1000, pid: 0 }
+    }
+
+    #[test]
+    fn rejects_modifying_calls() {
+        let inner = MemFs::new(InMemory::new());
+        inner.create(req(), Path::new("/"), OsStr::new("f"), 0o644, AccessMode::ReadWrite.into())
+            .unwrap();
+        let fs = ReadOnly::new(inner);
+        let root = Path::new("/");
+        let file = Path::new("/f");
+        assert_eq!(fs.mkdir(req(), root, OsStr::new("d"), 0o755).err(), Some(Errno::EROFS));
+        assert_eq!(fs.unlink(req(), root, OsStr::new("f")), Err(Errno::EROFS));
+        assert_eq!(fs.truncate(req(), file, None, 0), Err(Errno::EROFS));
+        assert_eq!(fs.open(req(), file, AccessMode::WriteOnly.into()).err(), Some(Errno::EROFS));
+        let trunc = OpenFlags::from(AccessMode::ReadOnly) | OpenFlags::TRUNC;
+        assert_eq!(fs.open(req(), file, trunc).err(), Some(Errno::EROFS));
+        let fl = fs.open(req(), file, AccessMode::ReadOnly.into()).unwrap().0;
+        assert_eq!(fs.write(req(), file, &fl, 0, b"x".to_vec(), 0), Err(Errno::EROFS));
+        assert_eq!(fs.read(req(), file, &fl, 0, 10), Ok(vec![]));
+        assert_eq!(fs.access(req(), file, libc::W_OK as u32), Err(Errno::EROFS));
+        fs.access(req(), file, libc::R_OK as u32).unwrap();
+        let statfs = fs.statfs(req(), root).unwrap();
+        assert!(statfs.blocks > 0);
+        assert_eq!((statfs.bfree, statfs.bavail, statfs.ffree), (0, 0, 0));
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use libc;
+use time::Timespec;
+
+use super::{Errno, Result};
+
+/// Convert a path or name for passing to libc.
+pub fn cstr<S: AsRef<OsStr> + ?Sized>(s: &S) -> Result<CString> {
+    CString::new(s.as_ref().as_bytes()).map_err(|_| Errno::EINVAL)
+}
+
+/// Check the return value of a libc call, returning `errno` on failure.
+pub fn check(res: libc::c_int) -> Result<()> {
+    if res == -1 {
+        Err(Errno::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Check the return value of a libc call returning a size, returning `errno` on failure.
+pub fn check_size(res: libc::ssize_t) -> Result<usize> {
+    if res < 0 {
+        Err(Errno::last_os_error())
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Convert a time for `utimensat`/`futimens`, `None` leaving it unchanged.
+pub fn to_libc_timespec(t: Option<Timespec>) -> libc::timespec {
+    match t {
+        Some(t) => libc::timespec { tv_sec: t.sec as libc::time_t, tv_nsec: t.nsec as libc::c_long },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    }
+}
@@ -0,0 +1,740 @@
+// Copyright 2017 Amos Onn.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+//! A wrapper tracing every call of a `FilesystemFL`, e.g. for finding out which call fails (and
+//! how) when a program misbehaves on a mount.
+//! Each call is reported to a sink as a `TraceRecord`, which displays like a line of `strace`:
+//!
+//! ```text
+//! [pid 4242 uid 1000 gid 1000] open("/a/f", O_RDWR|O_TRUNC) = fh=3 <0.000021>
+//! [pid 4242 uid 1000 gid 1000] lookup("/a", "g") = -1 ENOENT <0.000004>
+//! ```
+//!
+//! File and directory handlers are numbered by `Traced` itself, in the order they are opened
+//! (these are not the numbers given to the kernel by `FuseFL`).
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use time::Timespec;
+
+use super::flags::{AccessMode, OpenFlags};
+use super::fusefl::*;
+use fuse_mt::*;
+use super::{Errno, ResultEmpty, ResultEntry, ResultGetattr, ResultData, ResultWrite, ResultReaddir,
+            ResultStatfs, ResultXattr};
+
+/// A traced call, as reported to the sink of `Traced`. Displays like a line of `strace`.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// The name of the call, e.g. `"open"`.
+    pub op: &'static str,
+    /// The arguments of the call, formatted.
+    pub args: String,
+    /// The uid of the calling process.
+    pub uid: u32,
+    /// The gid of the calling process.
+    pub gid: u32,
+    /// The pid of the calling process.
+    pub pid: u32,
+    /// The result of the call, formatted, or its error.
+    pub result: Result<String, Errno>,
+    /// How long the call took.
+    pub duration: Duration,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "[pid {} uid {} gid {}] {}({}) = ",
+               self.pid,
+               self.uid,
+               self.gid,
+               self.op,
+               self.args)?;
+        match self.result {
+            Ok(ref res) => f.write_str(res)?,
+            Err(ref e) => write!(f, "-1 {}", e)?,
+        }
+        write!(f, " <{}.{:06}>", self.duration.as_secs(), self.duration.subsec_micros())
+    }
+}
+
+type TraceFn = dyn Fn(&TraceRecord) + Send + Sync;
+
+struct TraceSink(Box<TraceFn>);
+
+impl fmt::Debug for TraceSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TraceSink")
+    }
+}
+
+/// The file and directory handlers of `Traced`: the handler of the wrapped filesystem, with the
+/// number it is traced by.
+#[derive(Debug)]
+pub struct TracedHandle<H> {
+    id: u64,
+    inner: H,
+}
+
+impl<H> TracedHandle<H> {
+    /// The number the handler is traced by.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The handler of the wrapped filesystem.
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+}
+
+/// A `FilesystemFL` forwarding all calls to `T`, and reporting each of them to a sink.
+#[derive(Debug)]
+pub struct Traced<T> {
+    inner: T,
+    sink: TraceSink,
+    next_id: AtomicU64,
+}
+
+impl<T> Traced<T> where T: FilesystemFL {
+    /// Wrap the given filesystem, reporting the calls to `sink`.
+    pub fn new<F>(inner: T, sink: F) -> Traced<T>
+        where F: Fn(&TraceRecord) + Send + Sync + 'static {
+        Traced { inner, sink: TraceSink(Box::new(sink)), next_id: AtomicU64::new(0) }
+    }
+
+    /// Wrap the given filesystem, printing the calls to stderr.
+    pub fn stderr(inner: T) -> Traced<T> {
+        Traced::new(inner, |record| eprintln!("{}", record))
+    }
+
+    /// The wrapped filesystem.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn wrap<H>(&self, inner: H) -> TracedHandle<H> {
+        TracedHandle { id: self.next_id.fetch_add(1, Ordering::Relaxed), inner }
+    }
+
+    fn call<R, E, F, S>(&self,
+                        op: &'static str,
+                        req: RequestInfo,
+                        args: String,
+                        f: F,
+                        show: S)
+                        -> Result<R, E>
+        where F: FnOnce(RequestInfo) -> Result<R, E>,
+              S: FnOnce(&R) -> String,
+              E: Clone + Into<Errno>
+    {
+        let (uid, gid, pid) = (req.uid, req.gid, req.pid);
+        let start = Instant::now();
+        let res = f(req);
+        let duration = start.elapsed();
+        let result = match res {
+            Ok(ref r) => Ok(show(r)),
+            Err(ref e) => Err(e.clone().into()),
+        };
+        (self.sink.0)(&TraceRecord { op, args, uid, gid, pid, result, duration });
+        res
+    }
+}
+
+fn show_empty(_: &()) -> String {
+    "0".to_string()
+}
+
+fn show_attr(attr: &FileAttr) -> String {
+    format!("{{kind={:?}, mode={:#o}, size={}, uid={}, gid={}}}",
+            attr.kind,
+            attr.perm,
+            attr.size,
+            attr.uid,
+            attr.gid)
+}
+
+fn show_entry(entry: &(Timespec, FileAttr)) -> String {
+    show_attr(&entry.1)
+}
+
+fn show_data(data: &[u8]) -> String {
+    format!("{} bytes", data.len())
+}
+
+fn show_xattr(xattr: &Xattr) -> String {
+    match *xattr {
+        Xattr::Size(size) => format!("size={}", size),
+        Xattr::Data(ref data) => show_data(data),
+    }
+}
+
+fn show_fl<H>(fl: Option<&TracedHandle<H>>) -> String {
+    match fl {
+        Some(fl) => format!(", fh={}", fl.id),
+        None => String::new(),
+    }
+}
+
+fn show_time(time: Option<Timespec>) -> String {
+    match time {
+        Some(time) => format!("{}.{:09}", time.sec, time.nsec),
+        None => "UTIME_OMIT".to_string(),
+    }
+}
+
+fn show_flags(flags: OpenFlags) -> String {
+    let mut res = match flags.access_mode() {
+        Ok(AccessMode::ReadOnly) => "O_RDONLY",
+        Ok(AccessMode::WriteOnly) => "O_WRONLY",
+        Ok(AccessMode::ReadWrite) => "O_RDWR",
+        Err(_) => "O_ACCMODE",
+    }.to_string();
+    let mut rest = flags.bits() & !OpenFlags::ACCMODE.bits();
+    // O_SYNC includes the bits of O_DSYNC, so it goes first.
+    let names = [(OpenFlags::APPEND, "O_APPEND"),
+                 (OpenFlags::TRUNC, "O_TRUNC"),
+                 (OpenFlags::CREAT, "O_CREAT"),
+                 (OpenFlags::EXCL, "O_EXCL"),
+                 (OpenFlags::NONBLOCK, "O_NONBLOCK"),
+                 (OpenFlags::SYNC, "O_SYNC"),
+                 (OpenFlags::DSYNC, "O_DSYNC"),
+                 (OpenFlags::NOFOLLOW, "O_NOFOLLOW"),
+                 (OpenFlags::DIRECTORY, "O_DIRECTORY")];
+    for &(flag, name) in &names {
+        if flag.bits() != 0 && rest & flag.bits() == flag.bits() {
+            res.push('|');
+            res.push_str(name);
+            rest &= !flag.bits();
+        }
+    }
+    if rest != 0 {
+        res.push_str(&format!("|{:#x}", rest));
+    }
+    res
+}
+
+impl<T> FilesystemFL for Traced<T> where T: FilesystemFL {
+    type FileLike = TracedHandle<T::FileLike>;
+    type DirLike = TracedHandle<T::DirLike>;
+
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        self.call("init", _req, String::new(), |req| self.inner.init(req), show_empty)
+    }
+
+    fn destroy(&self, _req: RequestInfo) {
+        let _: Result<(), Errno> = self.call("destroy",
+                                                     _req,
+                                                     String::new(),
+                                                     |req| {
+                                                         self.inner.destroy(req);
+                                                         Ok(())
+                                                     },
+                                                     |_| "?".to_string());
+    }
+
+    fn lookup(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEntry {
+        self.call("lookup",
+                  _req,
+                  format!("{:?}, {:?}", _parent, _name),
+                  |req| self.inner.lookup(req, _parent, _name),
+                  show_entry)
+    }
+
+    fn getattr(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>)
+               -> ResultGetattr {
+        self.call("getattr",
+                  _req,
+                  format!("{:?}{}", _path, show_fl(_fl)),
+                  |req| self.inner.getattr(req, _path, _fl.map(|fl| &fl.inner)),
+                  show_entry)
+    }
+
+    fn chmod(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _mode: u32)
+             -> ResultEmpty {
+        self.call("chmod",
+                  _req,
+                  format!("{:?}{}, {:#o}", _path, show_fl(_fl), _mode),
+                  |req| self.inner.chmod(req, _path, _fl.map(|fl| &fl.inner), _mode),
+                  show_empty)
+    }
+
+    fn chown(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: Option<&Self::FileLike>,
+             _uid: Option<u32>,
+             _gid: Option<u32>)
+             -> ResultEmpty {
+        self.call("chown",
+                  _req,
+                  format!("{:?}{}, {:?}, {:?}", _path, show_fl(_fl), _uid, _gid),
+                  |req| self.inner.chown(req, _path, _fl.map(|fl| &fl.inner), _uid, _gid),
+                  show_empty)
+    }
+
+    fn truncate(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _fl: Option<&Self::FileLike>,
+                _size: u64)
+                -> ResultEmpty {
+        self.call("truncate",
+                  _req,
+                  format!("{:?}{}, {}", _path, show_fl(_fl), _size),
+                  |req| self.inner.truncate(req, _path, _fl.map(|fl| &fl.inner), _size),
+                  show_empty)
+    }
+
+    fn utimens(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Option<&Self::FileLike>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>)
+               -> ResultEmpty {
+        self.call("utimens",
+                  _req,
+                  format!("{:?}{}, {}, {}",
+                          _path,
+                          show_fl(_fl),
+                          show_time(_atime),
+                          show_time(_mtime)),
+                  |req| self.inner.utimens(req, _path, _fl.map(|fl| &fl.inner), _atime, _mtime),
+                  show_empty)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn utimens_macos(&self,
+                     _req: RequestInfo,
+                     _path: &Path,
+                     _fl: Option<&Self::FileLike>,
+                     _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>,
+                     _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>)
+                     -> ResultEmpty {
+        self.call("utimens_macos",
+                  _req,
+                  format!("{:?}{}, {}, {}, {}, {:?}",
+                          _path,
+                          show_fl(_fl),
+                          show_time(_crtime),
+                          show_time(_chgtime),
+                          show_time(_bkuptime),
+                          _flags),
+                  |req| {
+                      self.inner.utimens_macos(req,
+                                               _path,
+                                               _fl.map(|fl| &fl.inner),
+                                               _crtime,
+                                               _chgtime,
+                                               _bkuptime,
+                                               _flags)
+                  },
+                  show_empty)
+    }
+
+    fn readlink(&self, _req: RequestInfo, _path: &Path) -> ResultData {
+        self.call("readlink",
+                  _req,
+                  format!("{:?}", _path),
+                  |req| self.inner.readlink(req, _path),
+                  |target| format!("{:?}", Path::new(OsStr::from_bytes(target))))
+    }
+
+    fn mknod(&self,
+             _req: RequestInfo,
+             _parent: &Path,
+             _name: &OsStr,
+             _mode: u32,
+             _rdev: u32)
+             -> ResultEntry {
+        self.call("mknod",
+                  _req,
+                  format!("{:?}, {:?}, {:#o}, {:#x}", _parent, _name, _mode, _rdev),
+                  |req| self.inner.mknod(req, _parent, _name, _mode, _rdev),
+                  show_entry)
+    }
+
+    fn mkdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr, _mode: u32) -> ResultEntry {
+        self.call("mkdir",
+                  _req,
+                  format!("{:?}, {:?}, {:#o}", _parent, _name, _mode),
+                  |req| self.inner.mkdir(req, _parent, _name, _mode),
+                  show_entry)
+    }
+
+    fn unlink(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("unlink",
+                  _req,
+                  format!("{:?}, {:?}", _parent, _name),
+                  |req| self.inner.unlink(req, _parent, _name),
+                  show_empty)
+    }
+
+    fn rmdir(&self, _req: RequestInfo, _parent: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("rmdir",
+                  _req,
+                  format!("{:?}, {:?}", _parent, _name),
+                  |req| self.inner.rmdir(req, _parent, _name),
+                  show_empty)
+    }
+
+    fn symlink(&self,
+               _req: RequestInfo,
+               _parent: &Path,
+               _name: &OsStr,
+               _target: &Path)
+               -> ResultEntry {
+        self.call("symlink",
+                  _req,
+                  format!("{:?}, {:?}, {:?}", _parent, _name, _target),
+                  |req| self.inner.symlink(req, _parent, _name, _target),
+                  show_entry)
+    }
+
+    fn rename(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _newparent: &Path,
+              _newname: &OsStr)
+              -> ResultEmpty {
+        self.call("rename",
+                  _req,
+                  format!("{:?}, {:?}, {:?}, {:?}", _parent, _name, _newparent, _newname),
+                  |req| self.inner.rename(req, _parent, _name, _newparent, _newname),
+                  show_empty)
+    }
+
+    fn link(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _newparent: &Path,
+            _newname: &OsStr)
+            -> ResultEntry {
+        self.call("link",
+                  _req,
+                  format!("{:?}, {:?}, {:?}", _path, _newparent, _newname),
+                  |req| self.inner.link(req, _path, _newparent, _newname),
+                  show_entry)
+    }
+
+    fn open(&self, _req: RequestInfo, _path: &Path, _flags: OpenFlags) -> ResultOpenObj<Self::FileLike> {
+        self.call("open",
+                  _req,
+                  format!("{:?}, {}", _path, show_flags(_flags)),
+                  |req| map_res_open(self.inner.open(req, _path, _flags), |fl| self.wrap(fl)),
+                  |(fl, _)| format!("fh={}", fl.id))
+    }
+
+    fn read(&self,
+            _req: RequestInfo,
+            _path: &Path,
+            _fl: &Self::FileLike,
+            _offset: u64,
+            _size: u32)
+            -> ResultData {
+        self.call("read",
+                  _req,
+                  format!("{:?}, fh={}, {}, {}", _path, _fl.id, _offset, _size),
+                  |req| self.inner.read(req, _path, &_fl.inner, _offset, _size),
+                  |data| show_data(data))
+    }
+
+    fn write(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _offset: u64,
+             _data: Vec<u8>,
+             _flags: u32)
+             -> ResultWrite {
+        self.call("write",
+                  _req,
+                  format!("{:?}, fh={}, {}, {} bytes, {:#x}",
+                          _path,
+                          _fl.id,
+                          _offset,
+                          _data.len(),
+                          _flags),
+                  |req| self.inner.write(req, _path, &_fl.inner, _offset, _data, _flags),
+                  |written| written.to_string())
+    }
+
+    fn flush(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _lock_owner: u64)
+             -> ResultEmpty {
+        self.call("flush",
+                  _req,
+                  format!("{:?}, fh={}, {:#x}", _path, _fl.id, _lock_owner),
+                  |req| self.inner.flush(req, _path, &_fl.inner, _lock_owner),
+                  show_empty)
+    }
+
+    #[allow(unknown_lints, clippy::too_many_arguments)]
+    fn release(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _fl: Self::FileLike,
+               _flags: OpenFlags,
+               _lock_owner: u64,
+               _flush: bool)
+               -> ResultEmpty {
+        self.call("release",
+                  _req,
+                  format!("{:?}, fh={}, {}, {:#x}, {}",
+                          _path,
+                          _fl.id,
+                          show_flags(_flags),
+                          _lock_owner,
+                          _flush),
+                  |req| self.inner.release(req, _path, _fl.inner, _flags, _lock_owner, _flush),
+                  show_empty)
+    }
+
+    fn fsync(&self,
+             _req: RequestInfo,
+             _path: &Path,
+             _fl: &Self::FileLike,
+             _datasync: bool)
+             -> ResultEmpty {
+        self.call("fsync",
+                  _req,
+                  format!("{:?}, fh={}, {}", _path, _fl.id, _datasync),
+                  |req| self.inner.fsync(req, _path, &_fl.inner, _datasync),
+                  show_empty)
+    }
+
+    fn opendir(&self,
+               _req: RequestInfo,
+               _path: &Path,
+               _flags: OpenFlags)
+               -> ResultOpenObj<Self::DirLike> {
+        self.call("opendir",
+                  _req,
+                  format!("{:?}, {}", _path, show_flags(_flags)),
+                  |req| map_res_open(self.inner.opendir(req, _path, _flags), |dl| self.wrap(dl)),
+                  |(dl, _)| format!("fh={}", dl.id))
+    }
+
+    fn readdir(&self, _req: RequestInfo, _path: &Path, _dl: &Self::DirLike) -> ResultReaddir {
+        self.call("readdir",
+                  _req,
+                  format!("{:?}, fh={}", _path, _dl.id),
+                  |req| self.inner.readdir(req, _path, &_dl.inner),
+                  |entries| format!("{} entries", entries.len()))
+    }
+
+    fn releasedir(&self,
+                  _req: RequestInfo,
+                  _path: &Path,
+                  _dl: Self::DirLike,
+                  _flags: OpenFlags)
+                  -> ResultEmpty {
+        self.call("releasedir",
+                  _req,
+                  format!("{:?}, fh={}, {}", _path, _dl.id, show_flags(_flags)),
+                  |req| self.inner.releasedir(req, _path, _dl.inner, _flags),
+                  show_empty)
+    }
+
+    fn fsyncdir(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _dl: &Self::DirLike,
+                _datasync: bool)
+                -> ResultEmpty {
+        self.call("fsyncdir",
+                  _req,
+                  format!("{:?}, fh={}, {}", _path, _dl.id, _datasync),
+                  |req| self.inner.fsyncdir(req, _path, &_dl.inner, _datasync),
+                  show_empty)
+    }
+
+    fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.call("statfs",
+                  _req,
+                  format!("{:?}", _path),
+                  |req| self.inner.statfs(req, _path),
+                  |statfs| {
+                      format!("{{bsize={}, blocks={}, bfree={}, files={}, ffree={}}}",
+                              statfs.bsize,
+                              statfs.blocks,
+                              statfs.bfree,
+                              statfs.files,
+                              statfs.ffree)
+                  })
+    }
+
+    fn setxattr(&self,
+                _req: RequestInfo,
+                _path: &Path,
+                _name: &OsStr,
+                _value: &[u8],
+                _flags: u32,
+                _position: u32)
+                -> ResultEmpty {
+        self.call("setxattr",
+                  _req,
+                  format!("{:?}, {:?}, {} bytes, {:#x}, {}",
+                          _path,
+                          _name,
+                          _value.len(),
+                          _flags,
+                          _position),
+                  |req| self.inner.setxattr(req, _path, _name, _value, _flags, _position),
+                  show_empty)
+    }
+
+    fn getxattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr, _size: u32) -> ResultXattr {
+        self.call("getxattr",
+                  _req,
+                  format!("{:?}, {:?}, {}", _path, _name, _size),
+                  |req| self.inner.getxattr(req, _path, _name, _size),
+                  show_xattr)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, _path: &Path, _size: u32) -> ResultXattr {
+        self.call("listxattr",
+                  _req,
+                  format!("{:?}, {}", _path, _size),
+                  |req| self.inner.listxattr(req, _path, _size),
+                  show_xattr)
+    }
+
+    fn removexattr(&self, _req: RequestInfo, _path: &Path, _name: &OsStr) -> ResultEmpty {
+        self.call("removexattr",
+                  _req,
+                  format!("{:?}, {:?}", _path, _name),
+                  |req| self.inner.removexattr(req, _path, _name),
+                  show_empty)
+    }
+
+    fn access(&self, _req: RequestInfo, _path: &Path, _mask: u32) -> ResultEmpty {
+        self.call("access",
+                  _req,
+                  format!("{:?}, {:#o}", _path, _mask),
+                  |req| self.inner.access(req, _path, _mask),
+                  show_empty)
+    }
+
+    fn create(&self,
+              _req: RequestInfo,
+              _parent: &Path,
+              _name: &OsStr,
+              _mode: u32,
+              _flags: OpenFlags)
+              -> ResultCreateObj<Self::FileLike> {
+        self.call("create",
+                  _req,
+                  format!("{:?}, {:?}, {:#o}, {}", _parent, _name, _mode, show_flags(_flags)),
+                  |req| {
+                      map_res_create(self.inner.create(req, _parent, _name, _mode, _flags),
+                                     |fl| self.wrap(fl))
+                  },
+                  |created| format!("fh={} {}", created.fl.id, show_attr(&created.attr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use libc;
+    use super::super::memfs::{InMemory, MemFs};
+
+    fn req() -> RequestInfo {
+        RequestInfo { unique: 0, uid: 1000, gid: 100, pid: 42 }
+    }
+
+    fn flags(bits: i32) -> OpenFlags {
+        OpenFlags::from_bits(bits as u32)
+    }
+
+    #[test]
+    fn shows_flags() {
+        assert_eq!(show_flags(flags(libc::O_RDONLY)), "O_RDONLY");
+        assert_eq!(show_flags(flags(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL)),
+                   "O_WRONLY|O_CREAT|O_EXCL");
+        // O_SYNC includes the bits of O_DSYNC, which isn't shown again.
+        assert_eq!(show_flags(flags(libc::O_WRONLY | libc::O_SYNC)), "O_WRONLY|O_SYNC");
+        assert_eq!(show_flags(flags(libc::O_RDWR | libc::O_DSYNC)), "O_RDWR|O_DSYNC");
+        assert_eq!(show_flags(flags(libc::O_ACCMODE)), "O_ACCMODE");
+        assert_eq!(show_flags(flags(libc::O_RDWR | libc::O_APPEND | libc::O_CLOEXEC)),
+                   format!("O_RDWR|O_APPEND|{:#x}", libc::O_CLOEXEC));
+    }
+
+    #[test]
+    fn displays_records() {
+        let mut record = TraceRecord {
+            op: "lookup",
+            args: "\"/a\", \"g\"".to_string(),
+            uid: 1000,
+            gid: 100,
+            pid: 42,
+            result: Err(Errno::ENOENT),
+            duration: Duration::from_micros(1_000_004),
+        };
+        let call = "[pid 42 uid 1000 gid 100] lookup(\"/a\", \"g\") = ";
+        assert_eq!(record.to_string(), format!("{}-1 ENOENT <1.000004>", call));
+        record.result = Err(Errno::EIO.with_context("backend down"));
+        assert_eq!(record.to_string(), format!("{}-1 EIO: backend down <1.000004>", call));
+        record.result = Ok("{kind=Directory}".to_string());
+        record.duration = Duration::from_micros(21);
+        assert_eq!(record.to_string(), format!("{}{{kind=Directory}} <0.000021>", call));
+    }
+
+    #[test]
+    fn reports_calls() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let fs = {
+            let records = records.clone();
+            Traced::new(MemFs::new(InMemory::new()),
+                        move |record: &TraceRecord| records.lock().unwrap().push(record.clone()))
+        };
+        let path = Path::new("/f");
+        let created = fs.create(req(), Path::new("/"), OsStr::new("f"), 0o644, flags(libc::O_WRONLY))
+            .unwrap();
+        fs.write(req(), path, &created.fl, 0, b"hello".to_vec(), 0).unwrap();
+        let (fl, _) = fs.open(req(), path, flags(libc::O_RDONLY)).unwrap();
+        assert_eq!((created.fl.id(), fl.id()), (0, 1));
+        fs.read(req(), path, &fl, 1, 10).unwrap();
+        fs.release(req(), path, fl, flags(libc::O_RDONLY), 7, true).unwrap();
+        assert_eq!(fs.open(req(), Path::new("/g"), flags(libc::O_RDONLY)).err(), Some(Errno::ENOENT));
+
+        let records = records.lock().unwrap();
+        let calls: Vec<_> = records.iter()
+            .skip(2)
+            .map(|r| (r.op, r.args.as_str(), r.result.clone()))
+            .collect();
+        assert_eq!(calls,
+                   [("open", "\"/f\", O_RDONLY", Ok("fh=1".to_string())),
+                    ("read", "\"/f\", fh=1, 1, 10", Ok("4 bytes".to_string())),
+                    ("release", "\"/f\", fh=1, O_RDONLY, 0x7, true", Ok("0".to_string())),
+                    ("open", "\"/g\", O_RDONLY", Err(Errno::ENOENT))]);
+        assert_eq!((records[0].op, records[1].op), ("create", "write"));
+        assert!(records.iter().all(|r| (r.pid, r.uid, r.gid) == (42, 1000, 100)));
+    }
+}